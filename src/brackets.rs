@@ -0,0 +1,147 @@
+use std::cmp::Reverse;
+
+use filetype::Syntax;
+use indent;
+
+fn is_bracket(c: char) -> bool {
+    indent::closer(c).is_some() || indent::is_closer(c)
+}
+
+fn starts_with(chars: &[char], s: &str) -> bool {
+    let n = s.chars().count();
+    chars.len() >= n && chars[..n].iter().cloned().eq(s.chars())
+}
+
+/// A bracket's line, char offset and character.
+pub type Bracket = (usize, usize, char);
+
+/// Every bracket that's code and not in a string or comment, in order.
+/// Strings end with their line.
+pub fn code_brackets(lines: &[&str], syntax: Option<&Syntax>) -> Vec<Bracket> {
+    let mut found = Vec::new();
+    let mut in_comment = false;
+    for (l, line) in lines.iter().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut quote = None;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let s = match syntax {
+                Some(s) => s,
+                None => {
+                    if is_bracket(c) {
+                        found.push((l, i, c));
+                    }
+                    i += 1;
+                    continue;
+                }
+            };
+            if in_comment {
+                let end = s.block_comment.map_or("", |b| b.1);
+                if starts_with(&chars[i..], end) {
+                    in_comment = false;
+                    i += end.chars().count();
+                } else {
+                    i += 1;
+                }
+                continue;
+            }
+            if let Some(q) = quote {
+                if c == q {
+                    quote = None;
+                } else if c == '\\' {
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+            if s.line_comment.map_or(false, |lc| starts_with(&chars[i..], lc)) {
+                break;
+            }
+            if let Some((start, _)) = s.block_comment {
+                if starts_with(&chars[i..], start) {
+                    in_comment = true;
+                    i += start.chars().count();
+                    continue;
+                }
+            }
+            if s.quotes.contains(c) {
+                quote = Some(c);
+            } else if is_bracket(c) {
+                found.push((l, i, c));
+            }
+            i += 1;
+        }
+    }
+    found
+}
+
+/// Where the bracket at `at` (line, char offset) is matched, or with
+/// `look_ahead` the first bracket from `at` to the end of its line, like
+/// Vim's `%`. Only brackets of the same kind count, out of those `found`
+/// by `code_brackets`.
+pub fn matching(found: &[Bracket], at: (usize, usize), look_ahead: bool) -> Option<(usize, usize)> {
+    let start = match found.iter().position(|&(l, o, _)| {
+        l == at.0 && (o == at.1 || (look_ahead && o > at.1))
+    }) {
+        Some(i) => i,
+        None => return None,
+    };
+    let c = found[start].2;
+    let (open, close, forward) = match indent::closer(c) {
+        Some(close) => (c, close, true),
+        None => {
+            match "([{".chars().find(|&o| indent::closer(o) == Some(c)) {
+                Some(open) => (open, c, false),
+                None => return None,
+            }
+        }
+    };
+    let mut depth = 0;
+    let mut check = |&&(_, _, b): &&Bracket| {
+        if b == open {
+            depth += if forward { 1 } else { -1 };
+        } else if b == close {
+            depth += if forward { -1 } else { 1 };
+        }
+        depth == 0
+    };
+    let hit = match forward {
+        true => found[start..].iter().find(|b| check(b)),
+        false => found[..start + 1].iter().rev().find(|b| check(b)),
+    };
+    hit.map(|&(l, o, _)| (l, o))
+}
+
+/// The innermost pair of brackets around a line that's on more than one
+/// line, as its first and last lines. A pair that opens on the line itself
+/// is innermost, like the block after `} else {`.
+pub fn block_at(found: &[Bracket], line: usize) -> Option<(usize, usize)> {
+    blocks(found)
+        .into_iter()
+        .filter(|&(first, last)| first <= line && line <= last)
+        .max_by_key(|&(first, last)| (first, Reverse(last)))
+}
+
+/// Every pair of brackets on more than one line, as its first and last
+/// lines.
+pub fn blocks(found: &[Bracket]) -> Vec<(usize, usize)> {
+    let mut open: Vec<(usize, char)> = Vec::new();
+    let mut pairs = Vec::new();
+    for &(l, _, c) in found {
+        if indent::closer(c).is_some() {
+            open.push((l, c));
+            continue;
+        }
+        let i = match open.iter().rposition(|&(_, o)| indent::closer(o) == Some(c)) {
+            Some(i) => i,
+            None => continue,
+        };
+        let first = open[i].0;
+        open.truncate(i);
+        if first < l {
+            pairs.push((first, l));
+        }
+    }
+    pairs
+}
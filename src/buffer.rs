@@ -1,19 +1,31 @@
+use std::cell::{Ref, RefCell};
 use std::fs;
 use std::io;
 use std::io::{Read, Write};
 use std::collections::HashMap;
-use mode::{Command, Direction};
+use mode::{Case, Command, Direction};
 use std::cmp;
+use std::iter;
+use std::mem;
 use std::cmp::Ordering;
 use std;
 use state::State;
 use settings;
+use std::ops::Deref;
 use std::sync::{Arc, Mutex};
 use unicode_width::UnicodeWidthChar;
 
 use rustbox::Color;
 use geometry;
 use errors::{CrbError, CrbResult};
+use logging;
+use marks;
+use filetype;
+use hacks;
+use indent;
+use brackets;
+use lsp;
+use ex::Sort;
 
 /// A reference to a position.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
@@ -21,6 +33,78 @@ pub struct Anchor {
     id: i64,
 }
 
+/// Owns an anchor and releases it from its buffer when dropped.
+pub struct AnchorHandle {
+    anchor: Anchor,
+    released: Arc<Mutex<Vec<i64>>>,
+}
+
+impl Deref for AnchorHandle {
+    type Target = Anchor;
+
+    fn deref(&self) -> &Anchor {
+        &self.anchor
+    }
+}
+
+impl Drop for AnchorHandle {
+    fn drop(&mut self) {
+        if let Ok(mut released) = self.released.lock() {
+            released.push(self.anchor.id);
+        }
+    }
+}
+
+/// What an anchor is for. Decides its gravity and how it's drawn.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AnchorKind {
+    Cursor,
+    #[allow(dead_code)]
+    Selection,
+    Mark,
+    Diagnostic,
+}
+
+/// A change to the text the way a language server wants to hear about it:
+/// the range replaced, with columns in UTF-16 code units, and the new text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub start: (i32, i32),
+    pub end: (i32, i32),
+    pub text: String,
+}
+
+/// A problem found by a language server, between two anchors.
+struct Marked {
+    start: AnchorHandle,
+    end: AnchorHandle,
+    severity: i32,
+    message: String,
+}
+
+/// Lines that can be folded away behind their first line, between anchors
+/// at the start of the first and last lines.
+struct Fold {
+    start: AnchorHandle,
+    end: AnchorHandle,
+    closed: bool,
+}
+
+/// A change undo can take back: the lines from `first` were `old` and
+/// are now `new`.
+struct Change {
+    first: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+/// Edits since the last checkpoint: lines `first..end` were `old` before.
+struct Pending {
+    first: usize,
+    end: usize,
+    old: Vec<String>,
+}
+
 #[derive(PartialEq, Eq)]
 pub struct Wrap {
     style: WrapStyle,
@@ -47,8 +131,17 @@ impl Wrap {
     }
 }
 
+/// Which way an anchor goes when text is inserted right where it is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Gravity {
+    /// Stay in front of the new text.
+    Left,
+    /// End up after the new text, like a cursor that typed it.
+    Right,
+}
+
 /// Private structure containing position data.
-#[derive(Clone, PartialOrd)]
+#[derive(Clone)]
 struct Position {
     line: i32,
     /// Offset from the beginning of the line.
@@ -57,6 +150,8 @@ struct Position {
     offset: i32,
     /// The offset that this will snap back to when moving to a longer line.
     wishful_offset: Option<i32>,
+    gravity: Gravity,
+    kind: AnchorKind,
 }
 impl Position {
     fn new(line: i32, offset: i32) -> Position {
@@ -64,6 +159,8 @@ impl Position {
             line: line,
             offset: offset,
             wishful_offset: None,
+            gravity: Gravity::Left,
+            kind: AnchorKind::Mark,
         }
     }
 }
@@ -81,6 +178,12 @@ impl Ord for Position {
     }
 }
 
+impl PartialOrd for Position {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 struct Line {
     text: String,
 }
@@ -96,7 +199,7 @@ pub struct Display {
 pub enum Symbol {
     Char(char),
     ColorChar(char, Color),
-    Anchor(Anchor),
+    Anchor(Anchor, AnchorKind),
     Void,
     Skip,
 }
@@ -111,27 +214,56 @@ pub struct Buffer {
 
     // Map from anchor id to position.
     anchors: HashMap<i64, Position>,
+    /// Anchor ids sorted by position, so display can walk them in order.
+    order: Vec<i64>,
+    /// Ids of dropped AnchorHandles, to be removed from `anchors`.
+    released: Arc<Mutex<Vec<i64>>>,
     next_anchor_id: i64,
+
+    /// Named marks, set with m<name>.
+    marks: HashMap<char, AnchorHandle>,
+
+    /// Changes to undo, newest last.
+    undo: Vec<Change>,
+    /// Undone changes, turned around to undo the undo, newest last.
+    redo: Vec<Change>,
+    pending: Option<Pending>,
+    /// Brackets in code, by the filetype they were found for, until the
+    /// text changes.
+    brackets: RefCell<Option<(Option<&'static str>, Vec<brackets::Bracket>)>>,
+
+    /// Edits not yet sent to a language server. None if no server follows
+    /// this buffer.
+    edits: Option<Vec<Edit>>,
+    diagnostics: Vec<Marked>,
+    folds: Vec<Fold>,
 }
 
+/// How many changes back undo can go.
+const MAX_UNDO: usize = 1000;
+
 impl Buffer {
     /** Creators **/
 
     pub fn load_from_file(path: &str, state: Arc<Mutex<State>>) -> Result<Buffer, io::Error> {
         let s = try!(read_file(path));
-        let mut contents: Vec<Line> = s.split("\n")
-            .map(|x| Line { text: x.to_string() })
-            .collect();
-        if contents.len() == 0 {
-            contents.push(Line { text: "".to_string() });
-        }
-        let mut buf = Buffer::empty(state);
-        buf.contents = contents;
+        let mut buf = Buffer::from_text(&s, state);
         buf.file_path = Some(path.to_string());
         buf.newfile = false;
+        for (name, line, offset) in marks::load(path) {
+            buf.set_mark(name, line, offset);
+        }
         Ok(buf)
     }
 
+    /// Load a file, or start a new one if it can't be read.
+    pub fn open(path: &str, state: Arc<Mutex<State>>) -> Buffer {
+        match Buffer::load_from_file(path, state.clone()) {
+            Ok(buffer) => buffer,
+            _ => Buffer::new_file(path, state),
+        }
+    }
+
     pub fn new_file(path: &str, state: Arc<Mutex<State>>) -> Buffer {
         let mut buf = Buffer::empty(state);
         buf.file_path = Some(path.to_string());
@@ -139,6 +271,15 @@ impl Buffer {
         buf
     }
 
+    /// A buffer holding text, lines split on '\n'.
+    pub fn from_text(text: &str, state: Arc<Mutex<State>>) -> Buffer {
+        let mut buf = Buffer::empty(state);
+        buf.contents = text.split("\n")
+            .map(|x| Line { text: x.to_string() })
+            .collect();
+        buf
+    }
+
     pub fn empty(state: Arc<Mutex<State>>) -> Buffer {
         Buffer {
             contents: vec![Line { text: "".to_string() }],
@@ -146,7 +287,17 @@ impl Buffer {
             unsaved: false,
             newfile: true,
             anchors: HashMap::new(),
+            order: Vec::new(),
+            released: Arc::new(Mutex::new(Vec::new())),
             next_anchor_id: 0,
+            marks: HashMap::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            pending: None,
+            brackets: RefCell::new(None),
+            edits: None,
+            diagnostics: Vec::new(),
+            folds: Vec::new(),
             state: state,
         }
     }
@@ -155,35 +306,187 @@ impl Buffer {
 
     pub fn save(&mut self) -> CrbResult<()> {
         // TODO keep track of whether changed
+        try!(self.tidy());
+        try!(self.format());
         if let Some(ref file_path) = self.file_path {
             let f = try!(fs::File::create(file_path)
                 .map_err(|e| CrbError::new(&format!("error while opening to save {}", e))));
             let mut w = io::BufWriter::new(f);
-            for line in &self.contents {
-                try!(w.write_all(line.text.as_bytes())
-                    .map_err(|e| CrbError::new(&format!("error while saving {}", e))));
-                try!(w.write_all(b"\n")
-                    .map_err(|e| CrbError::new(&format!("error while saving {}", e))));
-            }
+            // Lines were split on '\n' when loading, so a file that ended
+            // in a newline still does.
+            try!(w.write_all(self.text().as_bytes())
+                .map_err(|e| CrbError::new(&format!("error while saving {}", e))));
             self.unsaved = false;
+            if let Err(e) = self.save_marks() {
+                logging::debug(&format!("{}", e));
+            }
             Ok(())
         } else {
             return Err(CrbError::new("cannot save with no file path"));
         }
     }
 
-    pub fn new_anchor(&mut self) -> Anchor {
+    pub fn filetype(&self) -> Option<&'static str> {
+        self.file_path.as_ref().and_then(|p| filetype::detect(p))
+    }
+
+    /// Trim and retab the whole text, if the settings say to on save.
+    fn tidy(&mut self) -> CrbResult<()> {
+        let (trim, retab, spaces) = {
+            let s = self.state.lock().unwrap();
+            (s.settings.trimOnSave, s.settings.retabOnSave, s.settings.insertSpaces)
+        };
+        if !trim && !retab {
+            return Ok(());
+        }
+        self.checkpoint();
+        let last = self.count_lines() - 1;
+        if trim {
+            try!(self.trim_lines(0, last));
+        }
+        if retab {
+            try!(self.retab_lines(0, last, spaces));
+        }
+        Ok(())
+    }
+
+    /// Run the text through the filetype's formatter, if it has one. Only
+    /// the lines that it changes are touched, so anchors elsewhere stay.
+    /// If the formatter fails the text is left alone.
+    fn format(&mut self) -> CrbResult<()> {
+        let cmd = match self.filetype() {
+            Some(ft) => self.state.lock().unwrap().settings.formatters.get(ft).cloned(),
+            None => None,
+        };
+        let cmd = match cmd {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let text = self.text();
+        let formatted = try!(hacks::pipe(&cmd, &text));
+        // Formatters that work on files in place, like `cargo fmt`, print
+        // nothing, which isn't the text being empty.
+        if formatted.is_empty() && !text.is_empty() {
+            return Err(CrbError::new(&format!("{} printed nothing; formatters need to print the \
+                                               formatted text",
+                                              cmd)));
+        }
+        if formatted != text {
+            self.checkpoint();
+            try!(self.replace_text(&formatted));
+        }
+        Ok(())
+    }
+
+    pub fn new_anchor(&mut self, kind: AnchorKind) -> AnchorHandle {
+        self.new_anchor_at(kind, 0, 0)
+    }
+
+    /// Make an anchor at a position, clamped to the text.
+    /// Cursors are right-sticky: text inserted at a cursor goes before it,
+    /// whoever inserts it. Everything else stays in front of new text.
+    pub fn new_anchor_at(&mut self, kind: AnchorKind, line: i32, offset: i32) -> AnchorHandle {
+        self.purge_released();
         let a = Anchor { id: self.new_anchor_id() };
-        let p = Position::new(0, 0);
+        let (line, offset) = self.clamp(line, offset);
+        let mut p = Position::new(line, offset);
+        p.kind = kind;
+        if kind == AnchorKind::Cursor {
+            p.gravity = Gravity::Right;
+        }
         self.anchors.insert(a.id, p);
-        a
+        self.place_in_order(a.id);
+        AnchorHandle {
+            anchor: a,
+            released: self.released.clone(),
+        }
+    }
+
+    /// Forget an anchor right away. Its handle, if any, becomes dangling.
+    pub fn remove_anchor(&mut self, anchor: &Anchor) {
+        self.anchors.remove(&anchor.id);
+        self.order.retain(|&id| id != anchor.id);
+    }
+
+    /// Remove the anchors whose handles were dropped.
+    fn purge_released(&mut self) {
+        let ids: Vec<i64> = match self.released.lock() {
+            Ok(mut released) => released.drain(..).collect(),
+            Err(_) => return,
+        };
+        for id in ids {
+            self.remove_anchor(&Anchor { id: id });
+        }
+    }
+
+    /// Put an anchor's id where it belongs in `order`.
+    fn place_in_order(&mut self, id: i64) {
+        self.order.retain(|&x| x != id);
+        let pos = match self.anchors.get(&id) {
+            Some(p) => p.clone(),
+            None => return,
+        };
+        let anchors = &self.anchors;
+        let i = match self.order.binary_search_by(|x| anchors[x].cmp(&pos)) {
+            Ok(i) | Err(i) => i,
+        };
+        self.order.insert(i, id);
+    }
+
+    /// Restore the order of `order` after an edit. Edits keep anchors in
+    /// order apart from ties, so this is close to linear.
+    fn reorder(&mut self) {
+        let anchors = &self.anchors;
+        self.order.sort_by(|a, b| anchors[a].cmp(&anchors[b]));
+    }
+
+    /// Put an anchor somewhere else, clamped to the text.
+    pub fn set_anchor_pos(&mut self, anchor: &Anchor, line: i32, offset: i32) -> CrbResult<()> {
+        let (line, offset) = self.clamp(line, offset);
+        let err = CrbError::new("no such anchor");
+        {
+            let p = try!(self.anchors.get_mut(&anchor.id).ok_or(err));
+            p.line = line;
+            p.offset = offset;
+            p.wishful_offset = None;
+        }
+        self.place_in_order(anchor.id);
+        Ok(())
+    }
+
+    pub fn set_mark(&mut self, name: char, line: i32, offset: i32) {
+        match self.marks.get(&name).map(|a| **a) {
+            Some(a) => {
+                let _ = self.set_anchor_pos(&a, line, offset);
+            }
+            None => {
+                let a = self.new_anchor_at(AnchorKind::Mark, line, offset);
+                self.marks.insert(name, a);
+            }
+        }
+    }
+
+    /// Write the marks to disk so they're there next time the file is opened.
+    pub fn save_marks(&self) -> CrbResult<()> {
+        let path = match self.file_path {
+            Some(ref p) => p,
+            None => return Ok(()),
+        };
+        marks::store(path, &try!(self.marks()))
     }
 
     pub fn move_anchor(&mut self, anchor: &Anchor, m: &Command) -> Result<(), CrbError> {
         let err = CrbError::new("no such anchor");
         let pos = try!(self.anchors.get(&anchor.id).ok_or(err)).clone();
-        let pos2 = try!(self.move_pos(&pos, m));
+        let mut pos2 = try!(self.move_pos(&pos, m));
+        // Lines in closed folds don't show, so nothing stops there.
+        let line = self.visible_line(pos2.line);
+        if line != pos2.line {
+            pos2.line = line;
+            pos2.offset = cmp::min(pos2.offset, self.line(line).unwrap_or("").chars().count() as i32);
+        }
         self.anchors.insert(anchor.id, pos2);
+        self.place_in_order(anchor.id);
         Ok(())
     }
 
@@ -198,7 +501,7 @@ impl Buffer {
                 p2
             }
             Command::MoveDown(n) => {
-                p2.line = cmp::min(cmp::max(0, p2.line + n), self.count_lines());
+                p2.line = self.step_visible(p2.line, n);
                 let len = self.line(p2.line).unwrap_or("").chars().count() as i32;
                 let wish = match p2.wishful_offset {
                     None => p2.offset,
@@ -211,6 +514,16 @@ impl Buffer {
                 };
                 p2
             }
+            Command::LineStart => {
+                p2.offset = 0;
+                p2.wishful_offset = None;
+                p2
+            }
+            Command::LineEnd => {
+                p2.offset = self.line(p2.line).unwrap_or("").chars().count() as i32;
+                p2.wishful_offset = None;
+                p2
+            }
             _ => return Err(CrbError::new("unsupported move command")),
         };
         Ok(p3)
@@ -234,20 +547,8 @@ impl Buffer {
         }
         let err = CrbError::new("no such anchor");
         let pos: Position = try!(self.anchors.get(&anchor.id).ok_or(err)).clone();
-        let err = CrbError::new("insert_text_before: no line at pos");
-        let cur_line = try!(self.contents.get(pos.line as usize).ok_or(err)).text.clone();
-        let (before, after) = cur_line.split_at(pos.offset as usize);
-        if text == '\n' {
-            self.contents[pos.line as usize] = Line { text: before.to_string() };
-            self.contents.insert((pos.line + 1) as usize, Line { text: after.to_string() });
-            self.anchors.insert(anchor.id, Position::new(pos.line + 1, 0));
-            Ok(())
-        } else {
-            self.contents[pos.line as usize] =
-                Line { text: before.to_string() + &text.to_string() + &after };
-            // TODO move anchors on this line
-            self.move_anchor(anchor, &Command::MoveRight(1))
-        }
+        try!(self.insert_at(pos.line, pos.offset, &text.to_string(), Some(anchor.id)));
+        Ok(())
     }
 
     pub fn delete_at(&mut self, anchor: &Anchor, d: &Direction) -> Result<(), CrbError> {
@@ -256,199 +557,1400 @@ impl Buffer {
         }
         let err = CrbError::new("no such anchor");
         let pos = try!(self.anchors.get(&anchor.id).ok_or(err)).clone();
-        let pos: Position = match *d {
-            Direction::B => pos.clone(),
-            Direction::F => try!(self.move_pos(&pos, &Command::MoveRight(1))),
+        let len = self.line(pos.line).unwrap_or("").chars().count() as i32;
+        let last_line = self.count_lines() - 1;
+        let (start, end) = match *d {
+            Direction::B if pos.offset > 0 => ((pos.line, pos.offset - 1), (pos.line, pos.offset)),
+            Direction::B if pos.line > 0 => {
+                let prev_len = self.line(pos.line - 1).unwrap_or("").chars().count() as i32;
+                ((pos.line - 1, prev_len), (pos.line, 0))
+            }
+            Direction::F if pos.offset < len => ((pos.line, pos.offset), (pos.line, pos.offset + 1)),
+            Direction::F if pos.line < last_line => ((pos.line, pos.offset), (pos.line + 1, 0)),
+            _ => return Ok(()),
         };
-        self.delete_at_pos(&pos)
+        self.delete_range(start, end)
     }
 
-    /// Delete backwards from the position.
-    fn delete_at_pos(&mut self, pos: &Position) -> Result<(), CrbError> {
-        let err = CrbError::new("delete_at_pos: no line at pos");
-        let cur_line = try!(self.contents.get(pos.line as usize).ok_or(err)).text.clone();
-        if pos.offset == 0 && pos.line == 0 {
-            Ok(())
-        } else if pos.offset == 0 {
-            let old_text = self.contents[(pos.line - 1) as usize].text.clone();
-            self.contents[(pos.line - 1) as usize] = Line { text: old_text.clone() + &cur_line };
-
-            // move anchors the line that got merged up
-            for (_, p) in self.anchors.iter_mut() {
-                if p.line == pos.line {
-                    let p2 = Position::new(p.line - 1,
-                                           p.offset + (old_text.chars().count() as i32));
-                    *p = p2;
-                }
-            }
+    /// The brackets and quotes that close by themselves in this buffer.
+    fn auto_pairs(&self) -> Vec<(char, char)> {
+        let s = self.state.lock().unwrap();
+        let pairs = self.filetype()
+            .and_then(|ft| s.settings.autoPairs.get(ft))
+            .or(s.settings.autoPairs.get("*"));
+        let chars: Vec<char> = pairs.map_or(Vec::new(), |p| p.chars().collect());
+        chars.chunks(2).filter(|c| c.len() == 2).map(|c| (c[0], c[1])).collect()
+    }
 
-            self.contents.remove(pos.line as usize);
-            Ok(())
-        } else {
-            let (before, after) = cur_line.split_at(pos.offset as usize);
-            let before_argh: String = before.chars().take(before.len() - 1).collect();
-            self.contents[pos.line as usize] = Line { text: before_argh.to_string() + after };
-
-            // move anchors on this line
-            for (_, p) in self.anchors.iter_mut() {
-                if p.line == pos.line && p.offset >= pos.offset {
-                    let p2 = Position::new(p.line, cmp::max(0, p.offset - 1));
-                    *p = p2
+    /// Type a character before an anchor. An opener gets its closer after
+    /// the anchor, and typing a closer that's already there steps over it.
+    pub fn type_char(&mut self, anchor: &Anchor, c: char) -> CrbResult<()> {
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        let (prev, next) = {
+            let chars: Vec<char> = self.line(line).unwrap_or("").chars().collect();
+            let prev = match offset {
+                0 => None,
+                _ => chars.get(offset as usize - 1).cloned(),
+            };
+            (prev, chars.get(offset as usize).cloned())
+        };
+        let pairs = self.auto_pairs();
+        let is_closer = |c: char| pairs.iter().any(|&(_, close)| close == c);
+        if next == Some(c) && is_closer(c) {
+            return self.move_anchor(anchor, &Command::MoveRight(1));
+        }
+        try!(self.insert_text_before(anchor, c));
+        let closer = match pairs.iter().find(|&&(open, _)| open == c) {
+            Some(&(_, close)) => close,
+            None => return Ok(()),
+        };
+        // Only pair where nothing would end up stuck to the closer. Quotes
+        // after a word are apostrophes and the like.
+        let room = next.map_or(true, |n| n.is_whitespace() || is_closer(n));
+        let after_word = prev.map_or(false, |p| p.is_alphanumeric() || p == '_');
+        if !room || (closer == c && after_word) {
+            return Ok(());
+        }
+        try!(self.insert_at(line, offset + 1, &closer.to_string(), None));
+        self.set_anchor_pos(anchor, line, offset + 1)
+    }
+
+    /// Delete the character before an anchor, and the closer after it too
+    /// if that leaves an empty pair.
+    pub fn backspace(&mut self, anchor: &Anchor) -> CrbResult<()> {
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        if offset > 0 {
+            let chars: Vec<char> = self.line(line)
+                .unwrap_or("")
+                .chars()
+                .skip(offset as usize - 1)
+                .take(2)
+                .collect();
+            if chars.len() == 2 && self.auto_pairs().contains(&(chars[0], chars[1])) {
+                try!(self.delete_range((line, offset - 1), (line, offset + 1)));
+                if self.file_path.is_some() {
+                    self.unsaved = true;
                 }
+                return Ok(());
             }
+        }
+        self.delete_at(anchor, &Direction::B)
+    }
 
-            Ok(())
+    /// Delete the word before the anchor, and any whitespace between them.
+    /// At the start of a line, joins it with the previous one instead.
+    pub fn delete_word_before(&mut self, anchor: &Anchor) -> CrbResult<()> {
+        let err = CrbError::new("no such anchor");
+        let pos = try!(self.anchors.get(&anchor.id).ok_or(err)).clone();
+        let before: Vec<char> = self.line(pos.line)
+            .unwrap_or("")
+            .chars()
+            .take(pos.offset as usize)
+            .collect();
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let mut rest = before.iter().rev().peekable();
+        let mut n = 0;
+        while rest.peek().map_or(false, |c| c.is_whitespace()) {
+            rest.next();
+            n += 1;
         }
+        if let Some(&&first) = rest.peek() {
+            let word = is_word(first);
+            while rest.peek().map_or(false, |&&c| !c.is_whitespace() && is_word(c) == word) {
+                rest.next();
+                n += 1;
+            }
+        }
+        for _ in 0..cmp::max(n, 1) {
+            try!(self.delete_at(anchor, &Direction::B));
+        }
+        Ok(())
     }
 
     pub fn clear(&mut self) -> CrbResult<()> {
-        self.contents.clear();
-        self.contents.push(Line { text: "".to_string() });
-        self.anchors = self.anchors
-            .iter()
-            .map(|(&a, _)| (a, Position::new(0, 0)))
-            .collect::<HashMap<_, _>>();
+        let last = self.count_lines() - 1;
+        let len = self.line(last).unwrap_or("").chars().count() as i32;
+        try!(self.delete_range((0, 0), (last, len)));
         if self.file_path.is_some() {
             self.unsaved = true;
         }
         Ok(())
     }
 
-    /** Observers **/
-
-    pub fn line(&self, i: i32) -> Option<&str> {
-        self.contents.get(i as usize).map(|l| l.text.as_str())
+    /// Add text after the last line's end.
+    pub fn append(&mut self, text: &str) -> CrbResult<()> {
+        let last = self.count_lines() - 1;
+        let len = self.line(last).unwrap_or("").chars().count() as i32;
+        try!(self.insert_at(last, len, text, None));
+        if self.file_path.is_some() {
+            self.unsaved = true;
+        }
+        Ok(())
     }
 
-    pub fn count_lines(&self) -> i32 {
-        self.contents.len() as i32
+    /// Make the edits since the last checkpoint one change, to come back
+    /// from with undo.
+    pub fn checkpoint(&mut self) {
+        self.close_change();
+        self.redo.clear();
     }
 
-    /// Calls the closure in scan order on the rectangular area.
-    pub fn display<F>(&self, start_line: usize, size: geometry::Size, wrap: &Wrap, mut f: F)
-        where F: FnMut(&Display)
-    {
-        if *wrap != Wrap::default(wrap.width) {
-            panic!("TODO unsupported wrap");
+    fn close_change(&mut self) {
+        if let Some(p) = self.pending.take() {
+            let new = self.lines_between(p.first, p.end);
+            if new != p.old {
+                self.undo.push(Change {
+                    first: p.first,
+                    old: p.old,
+                    new: new,
+                });
+                if self.undo.len() > MAX_UNDO {
+                    self.undo.remove(0);
+                }
+            }
         }
+    }
 
-        // TODO horizontal scrolling
-        // TODO multi-width characters
+    fn lines_between(&self, first: usize, end: usize) -> Vec<String> {
+        self.contents[first..end].iter().map(|l| l.text.clone()).collect()
+    }
 
-        let mut buf_y = start_line as usize;
-        let mut buf_x = 0 as usize;
-        let mut lines = self.contents.iter().skip(buf_y);
-        let mut line_chars = to_chars(lines.next()).peekable();
-        let anchors_all = self.all_anchors();
-        let mut anchors_iter = anchors_all.iter().peekable();
+    /// Note, before an edit, that lines `first..last + 1` are about to become
+    /// `first..new_last + 1`, so undo can put them back.
+    fn track(&mut self, first: i32, last: i32, new_last: i32) {
+        *self.brackets.borrow_mut() = None;
+        let (first, end) = (first as usize, last as usize + 1);
+        let grow = new_last - last;
+        let (start, stop, old) = match self.pending.take() {
+            None => {
+                // Redoing won't fit on the text after this.
+                self.redo.clear();
+                (first, end, self.lines_between(first, end))
+            }
+            Some(p) => {
+                // Lines outside the pending ones are as they were, so they
+                // can join them as they are now.
+                let (start, stop) = (cmp::min(first, p.first), cmp::max(end, p.end));
+                let mut old = self.lines_between(start, p.first);
+                old.extend(p.old);
+                old.extend(self.lines_between(p.end, stop));
+                (start, stop, old)
+            }
+        };
+        self.pending = Some(Pending {
+            first: start,
+            end: (stop as i32 + grow) as usize,
+            old: old,
+        });
+    }
 
-        for view_y in 0..size.height {
-            let mut view_x = 0;
-            // Draw line numbers
-            if buf_y <= self.contents.len() {
-                let col_size = self.contents.len().to_string().chars().count();
-                let line_num_str = (buf_y + 1).to_string();
-                let mut line_num_chars = line_num_str.chars();
-                let offset = col_size - line_num_chars.clone().count();
-                let color = self.state.lock().unwrap().settings.lineNumColor;
-                for i in 0..col_size {
-                    if offset <= i {
-                        let d = Display {
-                            x: view_x,
-                            y: view_y,
-                            symbol: Symbol::ColorChar(line_num_chars.next().unwrap(), color),
-                        };
-                        f(&d);
-                    }
-                    view_x += 1;
+    /// Go back n changes (forward if negative). Returns the first line that
+    /// changed.
+    pub fn undo(&mut self, n: i32) -> CrbResult<i32> {
+        self.close_change();
+        let mut from = mem::replace(&mut self.undo, Vec::new());
+        let mut to = mem::replace(&mut self.redo, Vec::new());
+        if n < 0 {
+            mem::swap(&mut from, &mut to);
+        }
+        let mut first: Option<i32> = None;
+        let mut result = Ok(());
+        for _ in 0..n.abs() {
+            let change = match from.pop() {
+                Some(c) => c,
+                None => break,
+            };
+            let line = {
+                let old: Vec<&str> = change.old.iter().map(|l| &l[..]).collect();
+                self.replace_changed(change.first, change.new.len(), &old)
+            };
+            match line {
+                Ok(line) => first = Some(first.map_or(line, |f| cmp::min(f, line))),
+                Err(e) => {
+                    result = Err(e);
+                    break;
                 }
             }
+            to.push(Change {
+                first: change.first,
+                old: change.new,
+                new: change.old,
+            });
+        }
+        // Undoing isn't an edit of its own.
+        self.pending = None;
+        if n < 0 {
+            mem::swap(&mut from, &mut to);
+        }
+        self.undo = from;
+        self.redo = to;
+        try!(result);
+        first.ok_or(CrbError::new(match n > 0 {
+            true => "nothing to undo",
+            false => "nothing to redo",
+        }))
+    }
 
-            let mut skip_for = 0;
-            let mut skip_symbol = Symbol::Skip;
-            for view_x in (view_x + 1)..size.width {
-                if skip_for > 0 {
-                    let d = Display {
-                        x: view_x,
-                        y: view_y,
-                        symbol: skip_symbol.clone(),
-                    };
-                    f(&d);
-                    skip_for -= 1;
-                    continue;
-                }
+    /// Change the whole text, only touching the lines that differ so that
+    /// anchors elsewhere stay put. Returns the first line that changed.
+    pub fn replace_text(&mut self, text: &str) -> CrbResult<i32> {
+        let new: Vec<&str> = text.split('\n').collect();
+        let count = self.contents.len();
+        self.replace_changed(0, count, &new)
+    }
 
-                let mut did_anchor = false;
-                if let Some(tpl) = anchors_iter.peek() {
-                    let tpl: &(&i64, &Position) = tpl;
-                    let anchor_id: &i64 = tpl.0;
-                    let pos: &Position = tpl.1;
-                    if (pos.line as usize == buf_y) && (pos.offset as usize == buf_x) {
-                        let a = Anchor { id: anchor_id + 0 };
-                        let d = Display {
-                            x: view_x,
-                            y: view_y,
-                            symbol: Symbol::Anchor(a),
-                        };
-                        f(&d);
-                        did_anchor = true;
-                    }
-                }
-                if did_anchor {
-                    anchors_iter.next();
-                }
+    /// Put lines in place of `count` lines from `first`, only touching the
+    /// ones that differ. Returns the first line that changed.
+    fn replace_changed(&mut self, first: usize, count: usize, new: &[&str]) -> CrbResult<i32> {
+        if first + count > self.contents.len() {
+            return Err(CrbError::new("replace_changed: lines out of range"));
+        }
+        let mut start = 0;
+        while start < count && start < new.len() && self.contents[first + start].text == new[start] {
+            start += 1;
+        }
+        let (mut old_end, mut new_end) = (count, new.len());
+        while old_end > start && new_end > start &&
+              self.contents[first + old_end - 1].text == new[new_end - 1] {
+            old_end -= 1;
+            new_end -= 1;
+        }
+        try!(self.replace_lines((first + start) as i32,
+                                (old_end - start) as i32,
+                                &new[start..new_end]));
+        Ok((first + start) as i32)
+    }
 
-                let s = match line_chars.next() {
-                    Some('\t') => {
-                        let cwidth = self.state.lock().unwrap().settings.tabWidth;
-                        skip_for = cwidth - 1;
-                        skip_symbol = Symbol::Void;
-                        Symbol::Void
-                    }
-                    Some(c) => {
-                        let cwidth = UnicodeWidthChar::width(c).unwrap_or(1);
-                        if cwidth > 1 {
-                            skip_for = cwidth - 1;
-                        }
-                        skip_symbol = Symbol::Skip;
-                        Symbol::Char(c)
-                    }
-                    None => Symbol::Void,
-                };
-                let d = Display {
-                    x: view_x,
-                    y: view_y,
-                    symbol: s,
-                };
-                f(&d);
-                buf_x += 1;
+    /// Replace `count` lines starting at `first` with other lines.
+    pub fn replace_lines(&mut self, first: i32, count: i32, lines: &[&str]) -> CrbResult<()> {
+        let last = self.count_lines() - 1;
+        if first < 0 || count < 0 || first + count - 1 > last {
+            return Err(CrbError::new("replace_lines: lines out of range"));
+        }
+        let end_of = |buf: &Buffer, l: i32| buf.line(l).unwrap_or("").chars().count() as i32;
+        let text = lines.join("\n");
+        match (count, lines.is_empty()) {
+            (0, true) => {}
+            (0, false) if first > last => {
+                let len = end_of(self, last);
+                try!(self.insert_at(last, len, &format!("\n{}", text), None));
             }
-            if line_chars.peek().is_none() {
-                // Next buf line
-                buf_y += 1;
-                buf_x = 0;
-                line_chars = to_chars(lines.next()).peekable();
+            (0, false) => {
+                try!(self.insert_at(first, 0, &format!("{}\n", text), None));
+            }
+            (_, true) if first + count - 1 < last => {
+                try!(self.delete_range((first, 0), (first + count, 0)));
+            }
+            (_, true) if first > 0 => {
+                let from = end_of(self, first - 1);
+                let to = end_of(self, last);
+                try!(self.delete_range((first - 1, from), (last, to)));
+            }
+            _ => {
+                let to = end_of(self, first + count - 1);
+                try!(self.delete_range((first, 0), (first + count - 1, to)));
+                try!(self.insert_at(first, 0, &text, None));
             }
         }
+        if self.file_path.is_some() {
+            self.unsaved = true;
+        }
+        Ok(())
     }
 
-    fn all_anchors(&self) -> Vec<(&i64, &Position)> {
-        let mut ans: Vec<(&i64, &Position)> = self.anchors.iter().collect();
-        ans.sort_by_key(|x| x.1);
-        ans
+    fn indent_style(&self) -> indent::Style {
+        indent::Style::new(&self.state.lock().unwrap().settings)
     }
 
-    /// Get the line of an anchor.
-    /// The [0] value is the data line.
-    /// The [1] value is the wrap line offset from that line.
-    pub fn get_anchor_line(&self, a: &Anchor, _: &Wrap) -> CrbResult<(i32, i32)> {
-        let err = CrbError::new("no such anchor");
-        let p = try!(self.anchors.get(&a.id).ok_or(err));
-        // TODO handle wrap
-        Ok((p.line, 0))
+    /// Change the whitespace at the start of a line. Anchors in the rest of
+    /// the line stay with their text.
+    pub fn set_indent(&mut self, line: i32, new: &str) -> CrbResult<()> {
+        let old = indent::leading(self.line(line).unwrap_or("")).to_string();
+        if old == new {
+            return Ok(());
+        }
+        try!(self.delete_range((line, 0), (line, old.chars().count() as i32)));
+        try!(self.insert_at(line, 0, new, None));
+        if self.file_path.is_some() {
+            self.unsaved = true;
+        }
+        Ok(())
     }
-}
-
+
+    /// Break the line at an anchor, indenting the new line like the one
+    /// it came from, or one level more after an opening bracket. Between
+    /// a pair of brackets the closing one gets a line of its own.
+    pub fn new_line(&mut self, anchor: &Anchor) -> CrbResult<()> {
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        let text = self.line(line).unwrap_or("").to_string();
+        let (before, after) = split_chars(&text, offset);
+        let style = self.indent_style();
+        let openers = filetype::indent_openers(self.filetype());
+        let base = indent::leading(&before).to_string();
+        if !text.is_empty() && text.trim().is_empty() {
+            // Don't leave whitespace behind on a line with nothing else.
+            try!(self.set_indent(line, ""));
+            try!(self.set_anchor_pos(anchor, line, 0));
+        }
+        let opens = indent::opens_block(&before, openers);
+        let inner = match opens {
+            true => style.shift(&base, 1),
+            false => base.clone(),
+        };
+        let closes = after.trim_left().chars().next().map_or(false, indent::is_closer);
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        if opens && closes {
+            let rest = indent::leading(&after).chars().count() as i32;
+            try!(self.delete_range((line, offset), (line, offset + rest)));
+            try!(self.insert_at(line, offset, &format!("\n{}\n{}", inner, base), None));
+            try!(self.set_anchor_pos(anchor, line + 1, inner.chars().count() as i32));
+        } else {
+            try!(self.insert_at(line, offset, &format!("\n{}", inner), Some(anchor.id)));
+        }
+        if self.file_path.is_some() {
+            self.unsaved = true;
+        }
+        Ok(())
+    }
+
+    /// After typing a closing bracket at the start of a line, line it up
+    /// with the line of the bracket it closes.
+    pub fn align_closer(&mut self, anchor: &Anchor) -> CrbResult<()> {
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        let text = self.line(line).unwrap_or("").to_string();
+        let typed = text.chars().nth(offset as usize - 1);
+        let openers = filetype::indent_openers(self.filetype());
+        let lone = text.chars().take(offset as usize - 1).all(|c| c == ' ' || c == '\t');
+        match typed {
+            Some(c) if lone && openers.chars().any(|o| indent::closer(o) == Some(c)) => {}
+            _ => return Ok(()),
+        }
+        let opener = {
+            let lines: Vec<&str> = self.contents.iter().map(|l| &l.text[..]).collect();
+            indent::opener_line(&lines, (line as usize, offset as usize - 1))
+        };
+        if let Some(o) = opener {
+            let new = indent::leading(self.line(o as i32).unwrap_or("")).to_string();
+            try!(self.set_indent(line, &new));
+        }
+        Ok(())
+    }
+
+    /// Move lines n levels of indentation in (out if negative). Blank
+    /// lines stay blank.
+    pub fn shift_lines(&mut self, first: i32, last: i32, n: i32) -> CrbResult<()> {
+        let style = self.indent_style();
+        for l in first..last + 1 {
+            let text = self.line(l).unwrap_or("").to_string();
+            if !text.trim().is_empty() {
+                try!(self.set_indent(l, &style.shift(&text, n)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Where an anchor would end up after a move, without moving it.
+    pub fn moved_pos(&mut self, anchor: &Anchor, m: &Command) -> CrbResult<(i32, i32)> {
+        let err = CrbError::new("no such anchor");
+        let pos = try!(self.anchors.get(&anchor.id).ok_or(err)).clone();
+        let pos = try!(self.move_pos(&pos, m));
+        Ok((pos.line, pos.offset))
+    }
+
+    pub fn change_case(&mut self, start: (i32, i32), end: (i32, i32), case: Case) -> CrbResult<()> {
+        let text: String = self.text_between(start, end)
+            .chars()
+            .flat_map(|c| {
+                let upper = match case {
+                    Case::Upper => true,
+                    Case::Lower => false,
+                    Case::Toggle => c.is_lowercase(),
+                };
+                match upper {
+                    true => c.to_uppercase().collect::<Vec<char>>(),
+                    false => c.to_lowercase().collect::<Vec<char>>(),
+                }
+            })
+            .collect();
+        self.replace_between(start, end, &text)
+    }
+
+    /// Put n of a character in place of the ones from an anchor on, which
+    /// ends up on the last of them.
+    pub fn replace_chars(&mut self, anchor: &Anchor, n: i32, c: char) -> CrbResult<()> {
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        let len = self.line(line).unwrap_or("").chars().count() as i32;
+        if n < 1 || offset + n > len {
+            return Err(CrbError::new("not that many characters to replace"));
+        }
+        let text: String = iter::repeat(c).take(n as usize).collect();
+        try!(self.replace_between((line, offset), (line, offset + n), &text));
+        self.set_anchor_pos(anchor, line, offset + n - 1)
+    }
+
+    /// Type a character over the one at an anchor, as in Replace mode.
+    pub fn overwrite(&mut self, anchor: &Anchor, c: char) -> CrbResult<()> {
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        if offset < self.line(line).unwrap_or("").chars().count() as i32 {
+            try!(self.delete_range((line, offset), (line, offset + 1)));
+        }
+        self.insert_text_before(anchor, c)
+    }
+
+    /// Join the lines after a line onto it, n times, with a space where
+    /// their indentation was. Returns where the last join happened.
+    pub fn join_lines(&mut self, line: i32, n: i32) -> CrbResult<i32> {
+        let mut at = None;
+        for _ in 0..n {
+            if line + 1 >= self.count_lines() {
+                break;
+            }
+            let (keep, lead, space) = {
+                let text = self.line(line).unwrap_or("");
+                let next = self.line(line + 1).unwrap_or("");
+                let keep = text.trim_right().chars().count() as i32;
+                let rest = next.trim_left();
+                (keep, (next.chars().count() - rest.chars().count()) as i32,
+                 keep > 0 && !rest.is_empty() && !rest.starts_with(')'))
+            };
+            try!(self.replace_between((line, keep), (line + 1, lead), if space { " " } else { "" }));
+            at = Some(keep);
+        }
+        at.ok_or(CrbError::new("no line to join"))
+    }
+
+    /// Add n to the number at or after an anchor on its line, which ends
+    /// up on its last digit.
+    pub fn increment(&mut self, anchor: &Anchor, n: i32) -> CrbResult<()> {
+        let (line, offset) = try!(self.anchor_pos(anchor));
+        let chars: Vec<char> = self.line(line).unwrap_or("").chars().collect();
+        let (start, end) = try!(number_at(&chars, offset as usize)
+            .ok_or(CrbError::new("no number under or after the cursor")));
+        let digits: String = chars[start..end].iter().cloned().collect();
+        let value = try!(digits.parse::<i64>()
+            .ok()
+            .and_then(|v| v.checked_add(n as i64))
+            .ok_or(CrbError::new("number out of range")));
+        let text = value.to_string();
+        try!(self.replace_between((line, start as i32), (line, end as i32), &text));
+        self.set_anchor_pos(anchor, line, (start + text.chars().count()) as i32 - 1)
+    }
+
+    /// Make a line above an anchor's with the same indentation, and put the
+    /// anchor at its end.
+    pub fn open_line_above(&mut self, anchor: &Anchor) -> CrbResult<()> {
+        let (line, _) = try!(self.anchor_pos(anchor));
+        let lead = indent::leading(self.line(line).unwrap_or("")).to_string();
+        try!(self.insert_at(line, 0, &format!("{}\n", lead), None));
+        if self.file_path.is_some() {
+            self.unsaved = true;
+        }
+        self.set_anchor_pos(anchor, line, lead.chars().count() as i32)
+    }
+
+    /// Put lines in a new order, with line `order[i]` as line `first + i`.
+    /// Lines left out are dropped. Anchors go along with their lines, and
+    /// those on dropped lines go to the first line with the same text.
+    fn rearrange_lines(&mut self, first: i32, last: i32, order: &[i32]) -> CrbResult<()> {
+        let texts: Vec<String> = order.iter().map(|&l| self.line(l).unwrap_or("").to_string()).collect();
+        let moved: Vec<(i64, i32, i32)> = self.anchors
+            .iter()
+            .filter(|&(_, p)| first <= p.line && p.line <= last)
+            .map(|(&id, p)| {
+                let text = self.line(p.line).unwrap_or("");
+                let to = order.iter()
+                    .position(|&l| l == p.line)
+                    .or(texts.iter().position(|t| t == text))
+                    .unwrap_or(0);
+                (id, first + to as i32, p.offset)
+            })
+            .collect();
+        {
+            let lines: Vec<&str> = texts.iter().map(|t| &t[..]).collect();
+            try!(self.replace_lines(first, last - first + 1, &lines));
+        }
+        for (id, line, offset) in moved {
+            let (line, offset) = self.clamp(line, offset);
+            if let Some(p) = self.anchors.get_mut(&id) {
+                p.line = line;
+                p.offset = offset;
+                p.wishful_offset = None;
+            }
+        }
+        self.reorder();
+        Ok(())
+    }
+
+    pub fn sort_lines(&mut self, first: i32, last: i32, how: &Sort) -> CrbResult<()> {
+        let mut order: Vec<i32> = (first..last + 1).collect();
+        {
+            let number = |l: i32| {
+                let chars: Vec<char> = self.line(l).unwrap_or("").chars().collect();
+                number_at(&chars, 0).and_then(|(s, e)| {
+                    chars[s..e].iter().cloned().collect::<String>().parse::<i64>().ok()
+                })
+            };
+            let text = |l: i32| self.line(l).unwrap_or("");
+            order.sort_by(|&a, &b| {
+                if how.numeric {
+                    number(a).cmp(&number(b))
+                } else if how.ignore_case {
+                    text(a).to_lowercase().cmp(&text(b).to_lowercase())
+                } else {
+                    text(a).cmp(text(b))
+                }
+            });
+        }
+        if how.reverse {
+            order.reverse();
+        }
+        self.rearrange_lines(first, last, &order)
+    }
+
+    /// Drop lines that are the same as one before them.
+    pub fn uniq_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        let mut seen: Vec<&str> = Vec::new();
+        let mut order = Vec::new();
+        for l in first..last + 1 {
+            let text = self.line(l).unwrap_or("");
+            if !seen.contains(&text) {
+                seen.push(text);
+                order.push(l);
+            }
+        }
+        self.rearrange_lines(first, last, &order)
+    }
+
+    pub fn reverse_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        let order: Vec<i32> = (first..last + 1).rev().collect();
+        self.rearrange_lines(first, last, &order)
+    }
+
+    /// Line up each delimiter in the lines, the first ones, then the second
+    /// ones and so on, with spaces before them.
+    pub fn align_lines(&mut self, first: i32, last: i32, delimiter: &str) -> CrbResult<()> {
+        for n in 0.. {
+            let found: Vec<(i32, i32)> = (first..last + 1)
+                .filter_map(|l| {
+                    let text = self.line(l).unwrap_or("");
+                    text.match_indices(delimiter)
+                        .nth(n)
+                        .map(|(i, _)| (l, text[..i].chars().count() as i32))
+                })
+                .collect();
+            let column = match found.iter().map(|f| f.1).max() {
+                Some(c) => c,
+                None => break,
+            };
+            for &(l, offset) in found.iter().filter(|f| f.1 < column) {
+                let pad: String = iter::repeat(' ').take((column - offset) as usize).collect();
+                try!(self.insert_at(l, offset, &pad, None));
+                if self.file_path.is_some() {
+                    self.unsaved = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Strip whitespace from the end of lines.
+    pub fn trim_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        for l in first..last + 1 {
+            let (keep, len) = {
+                let text = self.line(l).unwrap_or("");
+                (text.trim_right().chars().count() as i32, text.chars().count() as i32)
+            };
+            if keep < len {
+                try!(self.delete_range((l, keep), (l, len)));
+                if self.file_path.is_some() {
+                    self.unsaved = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the indentation of lines in spaces or tabs, `tabWidth` columns
+    /// to a tab.
+    pub fn retab_lines(&mut self, first: i32, last: i32, spaces: bool) -> CrbResult<()> {
+        let mut style = self.indent_style();
+        style.spaces = spaces;
+        for l in first..last + 1 {
+            let new = style.render(style.columns(indent::leading(self.line(l).unwrap_or(""))));
+            try!(self.set_indent(l, &new));
+        }
+        Ok(())
+    }
+
+    /// Comment lines out with the filetype's comment syntax, or back in if
+    /// they all are. Without line comments each line gets a block comment.
+    /// Blank lines are left alone.
+    pub fn toggle_comment(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        let syntax = filetype::syntax(self.filetype());
+        let (open, close) = match syntax.map(|s| (s.line_comment, s.block_comment)) {
+            Some((Some(lc), _)) => (lc, ""),
+            Some((None, Some(block))) => block,
+            _ => return Err(CrbError::new("no comment syntax for this file")),
+        };
+        let lines: Vec<(i32, String)> = (first..last + 1)
+            .map(|l| (l, self.line(l).unwrap_or("").to_string()))
+            .filter(|&(_, ref text)| !text.trim().is_empty())
+            .collect();
+        let commented = !lines.is_empty() &&
+                        lines.iter().all(|&(_, ref text)| {
+            let text = text.trim();
+            text.len() >= open.len() + close.len() && text.starts_with(open) && text.ends_with(close)
+        });
+        let column = lines.iter()
+            .map(|&(_, ref text)| indent::leading(text).chars().count())
+            .min()
+            .unwrap_or(0) as i32;
+        let char_at = |text: &str, i: i32| text.chars().nth(i as usize);
+        for &(l, ref text) in lines.iter() {
+            let lead = indent::leading(text).chars().count() as i32;
+            if !commented {
+                if !close.is_empty() {
+                    let end = text.trim_right().chars().count() as i32;
+                    try!(self.insert_at(l, end, &format!(" {}", close), None));
+                }
+                try!(self.insert_at(l, column, &format!("{} ", open), None));
+                continue;
+            }
+            // The end first, so the start's offsets stay right.
+            if !close.is_empty() {
+                let end = text.trim_right().chars().count() as i32;
+                let mut from = end - close.chars().count() as i32;
+                if from > lead + open.chars().count() as i32 && char_at(text, from - 1) == Some(' ') {
+                    from -= 1;
+                }
+                try!(self.delete_range((l, from), (l, end)));
+            }
+            let mut to = lead + open.chars().count() as i32;
+            if char_at(text, to) == Some(' ') {
+                to += 1;
+            }
+            try!(self.delete_range((l, lead), (l, to)));
+        }
+        if self.file_path.is_some() && !lines.is_empty() {
+            self.unsaved = true;
+        }
+        Ok(())
+    }
+
+    /// Indent lines by their brackets, starting from the nearest line
+    /// above them that isn't blank.
+    pub fn reindent_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        let style = self.indent_style();
+        let openers = filetype::indent_openers(self.filetype());
+        let new = {
+            let above = (0..first).rev().filter_map(|l| self.line(l)).find(|l| !l.trim().is_empty());
+            let lines: Vec<&str> = (first..last + 1).filter_map(|l| self.line(l)).collect();
+            indent::reindent(above, &lines, openers, &style)
+        };
+        for (i, text) in new.iter().enumerate() {
+            try!(self.set_indent(first + i as i32, indent::leading(text)));
+        }
+        Ok(())
+    }
+
+    /// Start keeping the edits for a language server.
+    pub fn track_edits(&mut self) {
+        self.edits = Some(Vec::new());
+    }
+
+    pub fn tracking_edits(&self) -> bool {
+        self.edits.is_some()
+    }
+
+    /// The edits since last time, oldest first.
+    pub fn take_edits(&mut self) -> Vec<Edit> {
+        match self.edits {
+            Some(ref mut edits) => edits.split_off(0),
+            None => Vec::new(),
+        }
+    }
+
+    /// The first and last lines of a fold, if edits left it more than one.
+    fn fold_lines(&self, fold: &Fold) -> Option<(i32, i32)> {
+        match (self.anchor_pos(&fold.start), self.anchor_pos(&fold.end)) {
+            (Ok((first, _)), Ok((last, _))) if first < last => Some((first, last)),
+            _ => None,
+        }
+    }
+
+    /// The outermost closed fold with the line in it, which is what shows.
+    pub fn closed_fold_at(&self, line: i32) -> Option<(i32, i32)> {
+        self.folds
+            .iter()
+            .filter(|f| f.closed)
+            .filter_map(|f| self.fold_lines(f))
+            .filter(|&(first, last)| first <= line && line <= last)
+            .fold(None, |outer: Option<(i32, i32)>, f| {
+                match outer {
+                    Some(o) if o.0 <= f.0 && f.1 <= o.1 => Some(o),
+                    _ => Some(f),
+                }
+            })
+    }
+
+    /// The line that shows for a line: the first of a closed fold it's in.
+    pub fn visible_line(&self, line: i32) -> i32 {
+        self.closed_fold_at(line).map_or(line, |f| f.0)
+    }
+
+    /// The line n lines down (up if negative) as they show, with each
+    /// closed fold as one line.
+    pub fn step_visible(&self, line: i32, n: i32) -> i32 {
+        let mut line = self.visible_line(line);
+        for _ in 0..n.abs() {
+            let next = match n > 0 {
+                true => self.closed_fold_at(line).map_or(line, |f| f.1) + 1,
+                false => line - 1,
+            };
+            if next < 0 || next >= self.count_lines() {
+                break;
+            }
+            line = self.visible_line(next);
+        }
+        line
+    }
+
+    /// How many lines show from one line down to another.
+    pub fn visible_rows(&self, from: i32, to: i32) -> i32 {
+        let (top, bottom, sign) = match from <= to {
+            true => (self.visible_line(from), self.visible_line(to), 1),
+            false => (self.visible_line(to), self.visible_line(from), -1),
+        };
+        let mut rows = 0;
+        let mut line = top;
+        while line < bottom {
+            line = self.closed_fold_at(line).map_or(line, |f| f.1) + 1;
+            rows += 1;
+        }
+        sign * rows
+    }
+
+    /// Fold lines away, closed.
+    pub fn create_fold(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        if first >= last {
+            return Err(CrbError::new("a fold needs more than one line"));
+        }
+        let fold = Fold {
+            start: self.new_anchor_at(AnchorKind::Mark, first, 0),
+            end: self.new_anchor_at(AnchorKind::Mark, last, 0),
+            closed: true,
+        };
+        self.folds.push(fold);
+        Ok(())
+    }
+
+    fn fold_method(&self) -> Option<String> {
+        let s = self.state.lock().unwrap();
+        self.filetype()
+            .and_then(|ft| s.settings.foldMethods.get(ft))
+            .or(s.settings.foldMethods.get("*"))
+            .cloned()
+    }
+
+    /// Where a fold would go around a line by the filetype's fold method.
+    fn fold_region(&self, line: i32) -> Option<(i32, i32)> {
+        let method = self.fold_method();
+        let region = match method.as_ref().map(|m| &m[..]) {
+            Some("indent") => {
+                let lines: Vec<&str> = self.contents.iter().map(|l| &l.text[..]).collect();
+                indent::block_at(&lines, line as usize, &self.indent_style())
+            }
+            Some("syntax") => brackets::block_at(&self.code_brackets(), line as usize),
+            _ => None,
+        };
+        region.map(|(first, last)| (first as i32, last as i32))
+    }
+
+    /// Everywhere the filetype's fold method would put a fold.
+    fn fold_regions(&self) -> Vec<(i32, i32)> {
+        let method = self.fold_method();
+        let regions = match method.as_ref().map(|m| &m[..]) {
+            Some("indent") => {
+                let lines: Vec<&str> = self.contents.iter().map(|l| &l.text[..]).collect();
+                indent::blocks(&lines, &self.indent_style())
+            }
+            Some("syntax") => brackets::blocks(&self.code_brackets()),
+            _ => Vec::new(),
+        };
+        regions.into_iter().map(|(first, last)| (first as i32, last as i32)).collect()
+    }
+
+    /// Close the innermost open fold around a line. Without one, fold the
+    /// lines the fold method finds.
+    pub fn close_fold(&mut self, line: i32) -> CrbResult<()> {
+        let inner = self.folds
+            .iter()
+            .enumerate()
+            .filter(|&(_, f)| !f.closed)
+            .filter_map(|(i, f)| self.fold_lines(f).map(|l| (i, l)))
+            .filter(|&(_, (first, last))| first <= line && line <= last)
+            .min_by_key(|&(_, (first, last))| last - first)
+            .map(|(i, _)| i);
+        if let Some(i) = inner {
+            self.folds[i].closed = true;
+            return Ok(());
+        }
+        let shown = self.closed_fold_at(line);
+        let region = match shown {
+            // Fold what the closed fold is in.
+            Some((first, last)) => {
+                self.fold_regions()
+                    .into_iter()
+                    .filter(|&(f, l)| f < first && last <= l)
+                    .min_by_key(|&(f, l)| l - f)
+            }
+            None => self.fold_region(line),
+        };
+        let (first, last) = try!(region.ok_or(CrbError::new("nothing to fold")));
+        self.create_fold(first, last)
+    }
+
+    /// Open the fold that shows for a line.
+    pub fn open_fold(&mut self, line: i32) -> CrbResult<()> {
+        let shown = try!(self.closed_fold_at(line).ok_or(CrbError::new("no closed fold here")));
+        for i in 0..self.folds.len() {
+            if self.folds[i].closed && self.fold_lines(&self.folds[i]) == Some(shown) {
+                self.folds[i].closed = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Open every fold a line is hidden in.
+    pub fn reveal(&mut self, line: i32) {
+        while self.visible_line(line) != line {
+            let _ = self.open_fold(line);
+        }
+    }
+
+    /// Replace the diagnostics with a language server's.
+    pub fn set_diagnostics(&mut self, diags: &[lsp::Diagnostic]) {
+        self.diagnostics.clear();
+        for d in diags {
+            let start = self.char_pos(d.start);
+            let end = self.char_pos(d.end);
+            let marked = Marked {
+                start: self.new_anchor_at(AnchorKind::Diagnostic, start.0, start.1),
+                end: self.new_anchor_at(AnchorKind::Diagnostic, end.0, end.1),
+                severity: d.severity,
+                message: d.message.clone(),
+            };
+            self.diagnostics.push(marked);
+        }
+    }
+
+    /// A position with a UTF-16 column as one with a character offset.
+    fn char_pos(&self, pos: (i32, i32)) -> (i32, i32) {
+        (pos.0, lsp::char_col(self.line(pos.0).unwrap_or(""), pos.1))
+    }
+
+    /// Insert text at a position and move every anchor after it along.
+    /// Anchors right at the position move if they're right-sticky, or if
+    /// they're the `pusher` that's doing the typing.
+    /// Returns the position after the inserted text.
+    fn insert_at(&mut self,
+                 line: i32,
+                 offset: i32,
+                 text: &str,
+                 pusher: Option<i64>)
+                 -> CrbResult<(i32, i32)> {
+        self.purge_released();
+        let err = CrbError::new("insert_at: no line at pos");
+        let cur_line = try!(self.contents.get(line as usize).ok_or(err)).text.clone();
+        if offset < 0 || offset > cur_line.chars().count() as i32 {
+            return Err(CrbError::new("insert_at: offset out of range"));
+        }
+        if let Some(ref mut edits) = self.edits {
+            let col = lsp::utf16_col(&cur_line, offset);
+            edits.push(Edit {
+                start: (line, col),
+                end: (line, col),
+                text: text.to_string(),
+            });
+        }
+        let (before, after) = split_chars(&cur_line, offset);
+        let mut pieces: Vec<String> = text.split('\n').map(|p| p.to_string()).collect();
+        let added = pieces.len() as i32 - 1;
+        let end_line = line + added;
+        let end_offset = match added {
+            0 => offset + pieces[0].chars().count() as i32,
+            _ => pieces[added as usize].chars().count() as i32,
+        };
+        pieces[0] = before + &pieces[0];
+        pieces[added as usize].push_str(&after);
+        self.track(line, line, end_line);
+        self.contents[line as usize] = Line { text: pieces.remove(0) };
+        for (i, piece) in pieces.into_iter().enumerate() {
+            self.contents.insert(line as usize + 1 + i, Line { text: piece });
+        }
+
+        for (id, p) in self.anchors.iter_mut() {
+            let pushed = p.offset == offset && (p.gravity == Gravity::Right || Some(*id) == pusher);
+            if p.line > line {
+                p.line += added;
+            } else if p.line == line && (p.offset > offset || pushed) {
+                p.line = end_line;
+                p.offset = p.offset - offset + end_offset;
+                p.wishful_offset = None;
+            }
+        }
+        self.reorder();
+        Ok((end_line, end_offset))
+    }
+
+    /// Delete the text between two positions. Anchors after the range move
+    /// back along with the text, and anchors inside it end up at the start.
+    fn delete_range(&mut self, start: (i32, i32), end: (i32, i32)) -> CrbResult<()> {
+        if end <= start {
+            return Ok(());
+        }
+        self.purge_released();
+        let err = CrbError::new("delete_range: no line at pos");
+        let first = try!(self.contents.get(start.0 as usize).ok_or(err)).text.clone();
+        let err = CrbError::new("delete_range: no line at pos");
+        let last = try!(self.contents.get(end.0 as usize).ok_or(err)).text.clone();
+        if let Some(ref mut edits) = self.edits {
+            edits.push(Edit {
+                start: (start.0, lsp::utf16_col(&first, start.1)),
+                end: (end.0, lsp::utf16_col(&last, end.1)),
+                text: String::new(),
+            });
+        }
+        let (before, _) = split_chars(&first, start.1);
+        let (_, after) = split_chars(&last, end.1);
+        self.track(start.0, end.0, start.0);
+        self.contents[start.0 as usize] = Line { text: before + &after };
+        for _ in start.0..end.0 {
+            self.contents.remove(start.0 as usize + 1);
+        }
+
+        let removed = end.0 - start.0;
+        for (_, p) in self.anchors.iter_mut() {
+            let here = (p.line, p.offset);
+            if here <= start {
+                continue;
+            }
+            if here >= end {
+                if p.line == end.0 {
+                    p.offset = start.1 + p.offset - end.1;
+                }
+                p.line -= removed;
+            } else {
+                p.line = start.0;
+                p.offset = start.1;
+            }
+            p.wishful_offset = None;
+        }
+        // Nothing passes anything else here, so `order` is still sorted.
+        Ok(())
+    }
+
+    /// The text between two positions.
+    fn text_between(&self, start: (i32, i32), end: (i32, i32)) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for l in start.0..end.0 + 1 {
+            let text = self.line(l).unwrap_or("");
+            let from = if l == start.0 { start.1 } else { 0 };
+            let to = if l == end.0 { end.1 } else { text.chars().count() as i32 };
+            lines.push(text.chars().skip(from as usize).take(cmp::max(0, to - from) as usize).collect());
+        }
+        lines.join("\n")
+    }
+
+    /// Put text in place of what's between two positions, unless it's the
+    /// same. Anchors inside end up at the start.
+    fn replace_between(&mut self, start: (i32, i32), end: (i32, i32), text: &str) -> CrbResult<()> {
+        if self.text_between(start, end) == text {
+            return Ok(());
+        }
+        try!(self.delete_range(start, end));
+        try!(self.insert_at(start.0, start.1, text, None));
+        if self.file_path.is_some() {
+            self.unsaved = true;
+        }
+        Ok(())
+    }
+
+    /** Observers **/
+
+    pub fn line(&self, i: i32) -> Option<&str> {
+        self.contents.get(i as usize).map(|l| l.text.as_str())
+    }
+
+    pub fn count_lines(&self) -> i32 {
+        self.contents.len() as i32
+    }
+
+    /// Calls the closure in scan order on the rectangular area, with closed
+    /// folds as one line each, language server problems and whitespace in
+    /// their colors, and the bracket at `matched` highlighted.
+    pub fn display<F>(&self,
+                      start_line: usize,
+                      size: geometry::Size,
+                      wrap: &Wrap,
+                      matched: Option<(i32, i32)>,
+                      mut f: F)
+        where F: FnMut(&Display)
+    {
+        if *wrap != Wrap::default(wrap.width) {
+            panic!("TODO unsupported wrap");
+        }
+
+        // TODO horizontal scrolling
+        // TODO multi-width characters
+
+        let mut buf_y = start_line as usize;
+        let mut buf_x = 0 as usize;
+        let mut lines = self.contents.iter().skip(buf_y);
+        let mut line_chars = to_chars(lines.next()).peekable();
+        let released = self.released.lock().map(|r| r.clone()).unwrap_or(Vec::new());
+        let mut anchors_iter = self.order
+            .iter()
+            .filter(|id| !released.contains(id))
+            .filter_map(|id| self.anchors.get(id).map(|p| (id, p)))
+            .peekable();
+        let (error_color, warning_color, match_color, fold_color);
+        let (show_whitespace, highlight_trailing, whitespace_color, trailing_color);
+        let (tab_glyph, trail_glyph, nbsp_glyph, tab_width);
+        {
+            let s = self.state.lock().unwrap();
+            let s = &s.settings;
+            error_color = s.errorColor;
+            warning_color = s.warningColor;
+            match_color = s.matchColor;
+            fold_color = s.foldColor;
+            show_whitespace = s.showWhitespace;
+            // Terminals, build output and popups aren't files to tidy.
+            highlight_trailing = s.highlightTrailing && self.file_path.is_some();
+            whitespace_color = s.whitespaceColor;
+            trailing_color = s.trailingColor;
+            tab_glyph = s.tabGlyph;
+            trail_glyph = s.trailGlyph;
+            nbsp_glyph = s.nbspGlyph;
+            tab_width = s.tabWidth;
+        }
+        // Every diagnostic colors at least one character.
+        let marked: Vec<((i32, i32), (i32, i32), Color)> = self.diagnostics
+            .iter()
+            .filter_map(|d| {
+                match (self.anchor_pos(&d.start), self.anchor_pos(&d.end)) {
+                    (Ok(s), Ok(e)) => {
+                        let color = match d.severity {
+                            1 => error_color,
+                            _ => warning_color,
+                        };
+                        Some((s, cmp::max(e, (s.0, s.1 + 1)), color))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+
+        for view_y in 0..size.height {
+            let mut view_x = 0;
+            // Draw line numbers
+            if buf_y <= self.contents.len() {
+                let col_size = self.contents.len().to_string().chars().count();
+                let line_num_str = (buf_y + 1).to_string();
+                let mut line_num_chars = line_num_str.chars();
+                let offset = col_size - line_num_chars.clone().count();
+                let color = self.state.lock().unwrap().settings.lineNumColor;
+                for i in 0..col_size {
+                    if offset <= i {
+                        let d = Display {
+                            x: view_x,
+                            y: view_y,
+                            symbol: Symbol::ColorChar(line_num_chars.next().unwrap(), color),
+                        };
+                        f(&d);
+                    }
+                    view_x += 1;
+                }
+            }
+
+            if let (0, Some((first, last))) = (buf_x, self.closed_fold_at(buf_y as i32)) {
+                // A closed fold is one line saying what's in it, with its
+                // anchors at the start.
+                while let Some(&(&anchor_id, pos)) = anchors_iter.peek() {
+                    if pos.line > last {
+                        break;
+                    }
+                    if pos.line == first {
+                        let d = Display {
+                            x: view_x + 1,
+                            y: view_y,
+                            symbol: Symbol::Anchor(Anchor { id: anchor_id }, pos.kind),
+                        };
+                        f(&d);
+                    }
+                    anchors_iter.next();
+                }
+                let summary = format!("+-- {} lines: {}",
+                                      last - first + 1,
+                                      self.contents[first as usize].text.trim());
+                let mut summary = summary.chars();
+                for view_x in (view_x + 1)..size.width {
+                    let d = Display {
+                        x: view_x,
+                        y: view_y,
+                        symbol: summary.next().map_or(Symbol::Void, |c| Symbol::ColorChar(c, fold_color)),
+                    };
+                    f(&d);
+                }
+                buf_y = last as usize + 1;
+                lines = self.contents.iter().skip(buf_y);
+                line_chars = to_chars(lines.next()).peekable();
+                continue;
+            }
+
+            // Where the whitespace at the end of the line starts.
+            let trail_from = self.line(buf_y as i32).map_or(0, |l| l.trim_right().chars().count());
+            let mut skip_for = 0;
+            let mut skip_symbol = Symbol::Skip;
+            for view_x in (view_x + 1)..size.width {
+                if skip_for > 0 {
+                    let d = Display {
+                        x: view_x,
+                        y: view_y,
+                        symbol: skip_symbol.clone(),
+                    };
+                    f(&d);
+                    skip_for -= 1;
+                    continue;
+                }
+
+                while let Some(&(&anchor_id, pos)) = anchors_iter.peek() {
+                    let here = (buf_y as i32, buf_x as i32);
+                    if (pos.line, pos.offset) > here {
+                        break;
+                    }
+                    // Anchors above the view are skipped, not drawn.
+                    if (pos.line, pos.offset) == here {
+                        let d = Display {
+                            x: view_x,
+                            y: view_y,
+                            symbol: Symbol::Anchor(Anchor { id: anchor_id }, pos.kind),
+                        };
+                        f(&d);
+                    }
+                    anchors_iter.next();
+                }
+
+                let trailing = highlight_trailing && buf_x >= trail_from;
+                let color = if trailing { trailing_color } else { whitespace_color };
+                let s = match line_chars.next() {
+                    Some('\t') => {
+                        skip_for = tab_width - 1;
+                        skip_symbol = Symbol::Void;
+                        match show_whitespace || trailing {
+                            true => Symbol::ColorChar(tab_glyph, color),
+                            false => Symbol::Void,
+                        }
+                    }
+                    Some(' ') if trailing => {
+                        skip_symbol = Symbol::Skip;
+                        Symbol::ColorChar(trail_glyph, color)
+                    }
+                    Some('\u{a0}') if show_whitespace || trailing => {
+                        skip_symbol = Symbol::Skip;
+                        Symbol::ColorChar(nbsp_glyph, color)
+                    }
+                    Some(c) => {
+                        let cwidth = UnicodeWidthChar::width(c).unwrap_or(1);
+                        if cwidth > 1 {
+                            skip_for = cwidth - 1;
+                        }
+                        skip_symbol = Symbol::Skip;
+                        let here = (buf_y as i32, buf_x as i32);
+                        match marked.iter().find(|m| m.0 <= here && here < m.1) {
+                            _ if matched == Some(here) => Symbol::ColorChar(c, match_color),
+                            Some(m) => Symbol::ColorChar(c, m.2),
+                            None => Symbol::Char(c),
+                        }
+                    }
+                    None => Symbol::Void,
+                };
+                let d = Display {
+                    x: view_x,
+                    y: view_y,
+                    symbol: s,
+                };
+                f(&d);
+                buf_x += 1;
+            }
+            if line_chars.peek().is_none() {
+                // Next buf line
+                buf_y += 1;
+                buf_x = 0;
+                line_chars = to_chars(lines.next()).peekable();
+            }
+        }
+    }
+
+    fn clamp(&self, line: i32, offset: i32) -> (i32, i32) {
+        let line = cmp::min(cmp::max(0, line), self.count_lines() - 1);
+        let len = self.line(line).unwrap_or("").chars().count() as i32;
+        (line, cmp::min(cmp::max(0, offset), len))
+    }
+
+    /// All named marks and where they are.
+    pub fn marks(&self) -> CrbResult<Vec<marks::Saved>> {
+        let mut saved = Vec::new();
+        for (&name, a) in self.marks.iter() {
+            let (line, offset) = try!(self.anchor_pos(a));
+            saved.push((name, line, offset));
+        }
+        Ok(saved)
+    }
+
+    /// The whole text, lines joined with '\n'.
+    pub fn text(&self) -> String {
+        self.contents.iter().map(|l| &l.text[..]).collect::<Vec<_>>().join("\n")
+    }
+
+    pub fn mark_pos(&self, name: char) -> Option<(i32, i32)> {
+        self.marks.get(&name).and_then(|a| self.anchor_pos(a).ok())
+    }
+
+    /// The message of a diagnostic on a line, errors first.
+    pub fn diagnostic_on(&self, line: i32) -> Option<&str> {
+        let mut found: Vec<&Marked> = self.diagnostics
+            .iter()
+            .filter(|d| {
+                match (self.anchor_pos(&d.start), self.anchor_pos(&d.end)) {
+                    (Ok(s), Ok(e)) => s.0 <= line && line <= e.0,
+                    _ => false,
+                }
+            })
+            .collect();
+        found.sort_by_key(|d| d.severity);
+        found.first().map(|d| &d.message[..])
+    }
+
+    /// Where the bracket at a position is matched. With `look_ahead`, the
+    /// first bracket after it on its line counts too.
+    pub fn matching_bracket(&self, line: i32, offset: i32, look_ahead: bool) -> Option<(i32, i32)> {
+        let any = self.line(line).map_or(false, |l| {
+            let mut rest = l.chars().skip(offset as usize);
+            match look_ahead {
+                true => rest.any(|c| "()[]{}".contains(c)),
+                false => rest.next().map_or(false, |c| "()[]{}".contains(c)),
+            }
+        });
+        if !any {
+            return None;
+        }
+        brackets::matching(&self.code_brackets(), (line as usize, offset as usize), look_ahead)
+            .map(|(l, o)| (l as i32, o as i32))
+    }
+
+    fn code_brackets<'a>(&'a self) -> Ref<'a, Vec<brackets::Bracket>> {
+        let ft = self.filetype();
+        let stale = self.brackets.borrow().as_ref().map_or(true, |&(f, _)| f != ft);
+        if stale {
+            let lines: Vec<&str> = self.contents.iter().map(|l| &l.text[..]).collect();
+            let found = brackets::code_brackets(&lines, filetype::syntax(ft).as_ref());
+            *self.brackets.borrow_mut() = Some((ft, found));
+        }
+        Ref::map(self.brackets.borrow(), |b| &b.as_ref().unwrap().1)
+    }
+
+    /// Offset of the first non-whitespace character of a line.
+    pub fn first_non_blank(&self, line: i32) -> i32 {
+        self.line(line).unwrap_or("").chars().take_while(|c| c.is_whitespace()).count() as i32
+    }
+
+    /// The line and offset of an anchor.
+    pub fn anchor_pos(&self, a: &Anchor) -> CrbResult<(i32, i32)> {
+        let err = CrbError::new("no such anchor");
+        let p = try!(self.anchors.get(&a.id).ok_or(err));
+        Ok((p.line, p.offset))
+    }
+
+    /// The word touching an anchor, and the offset where it starts.
+    pub fn word_at(&self, a: &Anchor) -> Option<(String, i32)> {
+        let (line, offset) = match self.anchor_pos(a) {
+            Ok(p) => p,
+            Err(_) => return None,
+        };
+        let chars: Vec<char> = self.line(line).unwrap_or("").chars().collect();
+        let is_word = |i: usize| chars.get(i).map_or(false, |&c| c.is_alphanumeric() || c == '_');
+        let mut start = offset as usize;
+        if !is_word(start) && start > 0 && is_word(start - 1) {
+            start -= 1;
+        }
+        if !is_word(start) {
+            return None;
+        }
+        while start > 0 && is_word(start - 1) {
+            start -= 1;
+        }
+        let mut end = start;
+        while is_word(end) {
+            end += 1;
+        }
+        Some((chars[start..end].iter().cloned().collect(), start as i32))
+    }
+
+    /// Positions of every occurrence of the text, in order.
+    pub fn find(&self, pattern: &str) -> Vec<(i32, i32)> {
+        let mut found = Vec::new();
+        if pattern.is_empty() {
+            return found;
+        }
+        for (i, line) in self.contents.iter().enumerate() {
+            for (byte, _) in line.text.match_indices(pattern) {
+                found.push((i as i32, line.text[..byte].chars().count() as i32));
+            }
+        }
+        found
+    }
+
+    /// Get the line of an anchor.
+    /// The [0] value is the data line.
+    /// The [1] value is the wrap line offset from that line.
+    pub fn get_anchor_line(&self, a: &Anchor, _: &Wrap) -> CrbResult<(i32, i32)> {
+        let err = CrbError::new("no such anchor");
+        let p = try!(self.anchors.get(&a.id).ok_or(err));
+        // TODO handle wrap
+        Ok((p.line, 0))
+    }
+}
+
+
+/// Where the number with a character in it, or the first one after it,
+/// starts and ends, with its minus sign.
+fn number_at(chars: &[char], offset: usize) -> Option<(usize, usize)> {
+    let digit = |i: usize| chars.get(i).map_or(false, |c| c.is_digit(10));
+    let mut start = match (offset..chars.len()).find(|&i| digit(i)) {
+        Some(i) => i,
+        None => return None,
+    };
+    while start > 0 && digit(start - 1) {
+        start -= 1;
+    }
+    let mut end = start;
+    while digit(end) {
+        end += 1;
+    }
+    if start > 0 && chars[start - 1] == '-' {
+        start -= 1;
+    }
+    Some((start, end))
+}
+
+/// Split a string at a character offset.
+fn split_chars(s: &str, offset: i32) -> (String, String) {
+    let offset = cmp::max(0, offset) as usize;
+    let before: String = s.chars().take(offset).collect();
+    let after: String = s.chars().skip(offset).collect();
+    (before, after)
+}
 
 fn to_chars(oli: Option<&Line>) -> std::str::Chars {
     match oli {
@@ -471,3 +1973,446 @@ fn canonicalize_move(mov: &Command) -> Command {
         m @ _ => m,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mode::Direction;
+    use state::State;
+    use std::sync::{Arc, Mutex};
+
+    /// Small xorshift generator so runs are repeatable without extra crates.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % (n as u64)) as usize
+        }
+    }
+
+    /// The same text and anchors as a flat list of chars and indexes into it.
+    struct Model {
+        text: Vec<char>,
+        anchors: Vec<(Anchor, usize, Gravity)>,
+    }
+
+    impl Model {
+        fn index(&self, a: &Anchor) -> usize {
+            self.anchors.iter().find(|x| x.0 == *a).unwrap().1
+        }
+
+        fn insert(&mut self, by: &Anchor, c: char) {
+            let at = self.index(by);
+            self.text.insert(at, c);
+            for x in self.anchors.iter_mut() {
+                if x.1 > at || (x.1 == at && (x.2 == Gravity::Right || x.0 == *by)) {
+                    x.1 += 1;
+                }
+            }
+        }
+
+        fn delete(&mut self, start: usize, end: usize) {
+            if end > self.text.len() || start >= end {
+                return;
+            }
+            self.text.drain(start..end);
+            for x in self.anchors.iter_mut() {
+                if x.1 >= end {
+                    x.1 -= end - start;
+                } else if x.1 > start {
+                    x.1 = start;
+                }
+            }
+        }
+    }
+
+    fn flat_index(buf: &Buffer, a: &Anchor) -> usize {
+        let (line, offset) = buf.anchor_pos(a).unwrap();
+        let mut i = 0;
+        for l in 0..line {
+            i += buf.line(l).unwrap().chars().count() + 1;
+        }
+        i + offset as usize
+    }
+
+    fn text(buf: &Buffer) -> String {
+        (0..buf.count_lines()).map(|l| buf.line(l).unwrap()).collect::<Vec<_>>().join("\n")
+    }
+
+    fn check(buf: &Buffer, model: &Model) {
+        assert_eq!(text(buf), model.text.iter().cloned().collect::<String>());
+        for &(ref a, i, _) in model.anchors.iter() {
+            assert_eq!(flat_index(buf, a), i);
+        }
+    }
+
+    #[test]
+    fn anchors_follow_random_edits() {
+        let chars = ['a', 'b', ' ', '\n', 'é', '\t'];
+        for seed in 1..50 {
+            let mut rng = Rng(seed * 7919);
+            let state = Arc::new(Mutex::new(State::new()));
+            let mut buf = Buffer::empty(state);
+            let mut model = Model {
+                text: Vec::new(),
+                anchors: Vec::new(),
+            };
+            let mut handles = Vec::new();
+            for i in 0..6 {
+                let (kind, g) = match i % 2 {
+                    0 => (AnchorKind::Mark, Gravity::Left),
+                    _ => (AnchorKind::Cursor, Gravity::Right),
+                };
+                let a = buf.new_anchor(kind);
+                model.anchors.push((*a, 0, g));
+                handles.push(a);
+            }
+
+            for _ in 0..300 {
+                let by = model.anchors[rng.below(model.anchors.len())].0;
+                let at = model.index(&by);
+                match rng.below(4) {
+                    0 | 1 => {
+                        let c = chars[rng.below(chars.len())];
+                        buf.insert_text_before(&by, c).unwrap();
+                        model.insert(&by, c);
+                    }
+                    2 => {
+                        buf.delete_at(&by, &Direction::B).unwrap();
+                        if at > 0 {
+                            model.delete(at - 1, at);
+                        }
+                    }
+                    _ => {
+                        buf.delete_at(&by, &Direction::F).unwrap();
+                        model.delete(at, at + 1);
+                    }
+                }
+                check(&buf, &model);
+            }
+        }
+    }
+
+    #[test]
+    fn clear_collapses_anchors() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::empty(state);
+        let a = buf.new_anchor(AnchorKind::Cursor);
+        let b = buf.new_anchor(AnchorKind::Mark);
+        for c in "one\ntwo".chars() {
+            buf.insert_text_before(&a, c).unwrap();
+        }
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (1, 3));
+        assert_eq!(buf.anchor_pos(&b).unwrap(), (0, 0));
+        buf.clear().unwrap();
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (0, 0));
+        assert_eq!(buf.count_lines(), 1);
+    }
+
+    #[test]
+    fn dropped_handles_release_anchors() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::empty(state);
+        let keep = buf.new_anchor(AnchorKind::Cursor);
+        let id = {
+            let gone = buf.new_anchor_at(AnchorKind::Mark, 0, 0);
+            gone.id
+        };
+        buf.insert_text_before(&keep, 'x').unwrap();
+        assert!(!buf.anchors.contains_key(&id));
+        assert_eq!(buf.order, vec![keep.id]);
+        buf.remove_anchor(&keep);
+        assert!(buf.anchor_pos(&keep).is_err());
+    }
+
+    #[test]
+    fn order_follows_positions() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::empty(state);
+        let cursor = buf.new_anchor(AnchorKind::Cursor);
+        let mark = buf.new_anchor(AnchorKind::Mark);
+        for c in "ab\ncd".chars() {
+            buf.insert_text_before(&cursor, c).unwrap();
+        }
+        buf.set_anchor_pos(&mark, 1, 1).unwrap();
+        assert_eq!(buf.order, vec![mark.id, cursor.id]);
+        buf.set_anchor_pos(&mark, 0, 1).unwrap();
+        assert_eq!(buf.order, vec![mark.id, cursor.id]);
+        buf.insert_text_before(&mark, 'z').unwrap();
+        let positions: Vec<_> = buf.order.iter().map(|id| buf.anchors[id].clone()).collect();
+        assert!(positions.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn undo_and_redo_whole_changes() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::from_text("one\ntwo\nthree", state);
+        let below = buf.new_anchor_at(AnchorKind::Mark, 2, 3);
+        buf.checkpoint();
+        buf.replace_lines(0, 2, &["b", "a", "c"]).unwrap();
+        assert_eq!(buf.text(), "b\na\nc\nthree");
+        assert_eq!(buf.anchor_pos(&below).unwrap(), (3, 3));
+        buf.checkpoint();
+        buf.replace_lines(1, 1, &[]).unwrap();
+        assert_eq!(buf.text(), "b\nc\nthree");
+        assert_eq!(buf.undo(2).unwrap(), 0);
+        assert_eq!(buf.text(), "one\ntwo\nthree");
+        assert_eq!(buf.anchor_pos(&below).unwrap(), (2, 3));
+        buf.undo(-1).unwrap();
+        assert_eq!(buf.text(), "b\na\nc\nthree");
+        assert!(buf.undo(-2).is_ok());
+        assert!(buf.undo(-1).is_err());
+        buf.replace_lines(2, 1, &[]).unwrap();
+        assert_eq!(buf.text(), "b\nc");
+    }
+
+    /// Apply edits the way a language server would, to check they add up
+    /// to the buffer's text.
+    fn apply_edits(text: &str, edits: &[Edit]) -> String {
+        let mut text = text.to_string();
+        for e in edits {
+            let lines: Vec<&str> = text.split('\n').collect();
+            let at = |(line, col): (i32, i32)| {
+                let before: usize = lines[..line as usize].iter().map(|l| l.len() + 1).sum();
+                let chars = lsp::char_col(lines[line as usize], col) as usize;
+                before + lines[line as usize].chars().take(chars).map(|c| c.len_utf8()).sum::<usize>()
+            };
+            let (start, end) = (at(e.start), at(e.end));
+            text = format!("{}{}{}", &text[..start], e.text, &text[end..]);
+        }
+        text
+    }
+
+    #[test]
+    fn edits_for_language_servers() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let before = "a😀b\nline two";
+        let mut buf = Buffer::from_text(before, state);
+        buf.track_edits();
+        let a = buf.new_anchor_at(AnchorKind::Cursor, 0, 2);
+        buf.insert_text_before(&a, 'x').unwrap();
+        buf.insert_text_before(&a, '\n').unwrap();
+        buf.delete_at(&a, &Direction::B).unwrap();
+        buf.delete_at(&a, &Direction::B).unwrap();
+        buf.replace_lines(1, 1, &["two", "three"]).unwrap();
+        let edits = buf.take_edits();
+        assert_eq!(edits[0].start, (0, 3));
+        assert_eq!(apply_edits(before, &edits), buf.text());
+        assert!(buf.take_edits().is_empty());
+    }
+
+    #[test]
+    fn indents_by_brackets() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::from_text("fn main() {}", state);
+        buf.file_path = Some("main.rs".to_string());
+        let a = buf.new_anchor_at(AnchorKind::Cursor, 0, 11);
+        buf.new_line(&a).unwrap();
+        assert_eq!(buf.text(), "fn main() {\n\t\n}");
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (1, 1));
+        for c in "if x {".chars() {
+            buf.insert_text_before(&a, c).unwrap();
+        }
+        buf.new_line(&a).unwrap();
+        buf.new_line(&a).unwrap();
+        buf.insert_text_before(&a, '}').unwrap();
+        buf.align_closer(&a).unwrap();
+        assert_eq!(buf.text(), "fn main() {\n\tif x {\n\n\t}\n}");
+        buf.shift_lines(0, 4, 1).unwrap();
+        assert_eq!(buf.text(), "\tfn main() {\n\t\tif x {\n\n\t\t}\n\t}");
+        buf.replace_lines(2, 1, &["y();"]).unwrap();
+        buf.reindent_lines(0, 4).unwrap();
+        assert_eq!(buf.text(), "fn main() {\n\tif x {\n\t\ty();\n\t}\n}");
+    }
+
+    #[test]
+    fn pairs_brackets_and_quotes() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::from_text("", state);
+        buf.file_path = Some("main.rs".to_string());
+        let a = buf.new_anchor_at(AnchorKind::Cursor, 0, 0);
+        for c in "f(x, \"a".chars() {
+            buf.type_char(&a, c).unwrap();
+        }
+        assert_eq!(buf.text(), "f(x, \"a\")");
+        buf.type_char(&a, '"').unwrap();
+        buf.type_char(&a, ')').unwrap();
+        assert_eq!(buf.text(), "f(x, \"a\")");
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (0, 9));
+        // No quote pairs in Rust, and nothing gets stuck to a word.
+        buf.type_char(&a, '\'').unwrap();
+        buf.set_anchor_pos(&a, 0, 0).unwrap();
+        buf.type_char(&a, '[').unwrap();
+        assert_eq!(buf.text(), "[f(x, \"a\")'");
+        buf.set_anchor_pos(&a, 0, 13).unwrap();
+        buf.type_char(&a, '{').unwrap();
+        buf.backspace(&a).unwrap();
+        buf.backspace(&a).unwrap();
+        assert_eq!(buf.text(), "[f(x, \"a\")");
+    }
+
+    #[test]
+    fn matches_brackets() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::from_text("f(a, \")\", [b]) {\n\t// }\n\tg(/* ( */);\n}", state);
+        assert_eq!(buf.matching_bracket(0, 0, false), None);
+        // Without a filetype, strings and comments aren't known.
+        assert_eq!(buf.matching_bracket(0, 1, false), Some((0, 6)));
+        assert_eq!(buf.matching_bracket(0, 15, false), Some((1, 4)));
+        buf.file_path = Some("main.rs".to_string());
+        assert_eq!(buf.matching_bracket(0, 1, false), Some((0, 13)));
+        assert_eq!(buf.matching_bracket(0, 0, true), Some((0, 13)));
+        assert_eq!(buf.matching_bracket(0, 15, false), Some((3, 0)));
+        assert_eq!(buf.matching_bracket(3, 0, false), Some((0, 15)));
+        assert_eq!(buf.matching_bracket(2, 2, false), Some((2, 10)));
+        buf.delete_range((3, 0), (3, 1)).unwrap();
+        assert_eq!(buf.matching_bracket(0, 15, false), None);
+    }
+
+    #[test]
+    fn folds_lines() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let text = "fn f() {\n\tif x {\n\t\ty();\n\t}\n}\nend";
+        let mut buf = Buffer::from_text(text, state.clone());
+        buf.file_path = Some("f.rs".to_string());
+        let a = buf.new_anchor_at(AnchorKind::Cursor, 2, 2);
+        buf.close_fold(2).unwrap();
+        assert_eq!(buf.closed_fold_at(2), Some((1, 3)));
+        buf.close_fold(1).unwrap();
+        assert_eq!(buf.closed_fold_at(2), Some((0, 4)));
+        assert_eq!(buf.step_visible(0, 1), 5);
+        assert_eq!(buf.visible_rows(0, 5), 1);
+        buf.move_anchor(&a, &Command::MoveRight(0)).unwrap();
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (0, 2));
+        let mut rows: Vec<String> = vec![String::new(); 3];
+        buf.display(0, geometry::Size::new(40, 3), &Wrap::default(40), None, |d| {
+            match d.symbol {
+                Symbol::Char(c) | Symbol::ColorChar(c, _) if d.x > 1 => rows[d.y as usize].push(c),
+                _ => {}
+            }
+        });
+        assert_eq!(rows, vec!["+-- 5 lines: fn f() {", "end", ""]);
+        buf.open_fold(0).unwrap();
+        assert_eq!(buf.step_visible(0, 2), 4);
+        buf.reveal(2);
+        assert_eq!(buf.visible_line(2), 2);
+
+        let mut py = Buffer::from_text("def f():\n    x = 1\n\n    y = 2\nz = 3", state);
+        py.file_path = Some("f.py".to_string());
+        py.close_fold(2).unwrap();
+        assert_eq!(py.closed_fold_at(2), Some((0, 3)));
+    }
+
+    #[test]
+    fn toggles_comments() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::from_text("fn f() {\n\tx();\n\n\t\ty();\n}", state.clone());
+        assert!(buf.toggle_comment(0, 0).is_err());
+        buf.file_path = Some("f.rs".to_string());
+        let a = buf.new_anchor_at(AnchorKind::Cursor, 3, 3);
+        buf.toggle_comment(1, 3).unwrap();
+        assert_eq!(buf.text(), "fn f() {\n\t// x();\n\n\t// \ty();\n}");
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (3, 6));
+        buf.toggle_comment(0, 1).unwrap();
+        assert_eq!(buf.text(), "// fn f() {\n// \t// x();\n\n\t// \ty();\n}");
+        buf.toggle_comment(0, 3).unwrap();
+        assert_eq!(buf.text(), "fn f() {\n\t// x();\n\n\t\ty();\n}");
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (3, 3));
+
+        let mut md = Buffer::from_text("# Title\n  text ", state);
+        md.file_path = Some("README.md".to_string());
+        md.toggle_comment(0, 1).unwrap();
+        assert_eq!(md.text(), "<!-- # Title -->\n<!--   text --> ");
+        md.toggle_comment(0, 1).unwrap();
+        assert_eq!(md.text(), "# Title\n  text ");
+    }
+
+    #[test]
+    fn transforms_text() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::from_text("let Straße = 9;\n    (x)\nfoo(\n    )", state);
+        let a = buf.new_anchor_at(AnchorKind::Cursor, 0, 4);
+        buf.change_case((0, 4), (0, 10), Case::Upper).unwrap();
+        buf.change_case((0, 0), (0, 3), Case::Toggle).unwrap();
+        assert_eq!(buf.line(0), Some("LET STRASSE = 9;"));
+        buf.set_anchor_pos(&a, 0, 0).unwrap();
+        buf.increment(&a, 3).unwrap();
+        assert_eq!(buf.line(0), Some("LET STRASSE = 12;"));
+        assert_eq!(buf.anchor_pos(&a).unwrap(), (0, 15));
+        buf.increment(&a, -20).unwrap();
+        assert_eq!(buf.line(0), Some("LET STRASSE = -8;"));
+        buf.replace_chars(&a, 2, 'x').unwrap();
+        assert_eq!(buf.line(0), Some("LET STRASSE = -xx"));
+        assert!(buf.replace_chars(&a, 2, 'x').is_err());
+        buf.overwrite(&a, 'y').unwrap();
+        buf.overwrite(&a, 'z').unwrap();
+        assert_eq!(buf.line(0), Some("LET STRASSE = -xyz"));
+        assert_eq!(buf.join_lines(0, 1).unwrap(), 18);
+        assert_eq!(buf.line(0), Some("LET STRASSE = -xyz (x)"));
+        assert_eq!(buf.join_lines(1, 2).unwrap(), 4);
+        assert_eq!(buf.line(1), Some("foo()"));
+        assert!(buf.join_lines(1, 1).is_err());
+        buf.open_line_above(&a).unwrap();
+        assert_eq!(buf.text(), "\nLET STRASSE = -xyz (x)\nfoo()");
+    }
+
+    #[test]
+    fn rearranges_lines() {
+        let state = Arc::new(Mutex::new(State::new()));
+        let mut buf = Buffer::from_text("top\nb 10\nA 9\nb 10\nc -1\nend", state);
+        let on_a = buf.new_anchor_at(AnchorKind::Mark, 2, 1);
+        let on_dup = buf.new_anchor_at(AnchorKind::Mark, 3, 2);
+        let end = buf.new_anchor_at(AnchorKind::Mark, 5, 1);
+        let mut how = Sort {
+            numeric: false,
+            ignore_case: false,
+            reverse: false,
+        };
+        buf.sort_lines(1, 4, &how).unwrap();
+        assert_eq!(buf.text(), "top\nA 9\nb 10\nb 10\nc -1\nend");
+        assert_eq!(buf.anchor_pos(&on_a).unwrap(), (1, 1));
+        assert_eq!(buf.anchor_pos(&on_dup).unwrap(), (3, 2));
+        how.numeric = true;
+        how.reverse = true;
+        buf.sort_lines(1, 4, &how).unwrap();
+        assert_eq!(buf.text(), "top\nb 10\nb 10\nA 9\nc -1\nend");
+        buf.uniq_lines(1, 4).unwrap();
+        assert_eq!(buf.text(), "top\nb 10\nA 9\nc -1\nend");
+        assert_eq!(buf.anchor_pos(&on_dup).unwrap(), (1, 2));
+        assert_eq!(buf.anchor_pos(&end).unwrap(), (4, 1));
+        buf.reverse_lines(0, 4).unwrap();
+        assert_eq!(buf.text(), "end\nc -1\nA 9\nb 10\ntop");
+        buf.replace_lines(0, 5, &["x,yy,z", "xxx,y,z", "w"]).unwrap();
+        buf.align_lines(0, 2, ",").unwrap();
+        assert_eq!(buf.text(), "x  ,yy,z\nxxx,y ,z\nw");
+    }
+
+    #[test]
+    fn keeps_text_a_formatter_drops() {
+        let state = Arc::new(Mutex::new(State::new()));
+        state.lock().unwrap().settings.formatters.insert("rust".to_string(), "true".to_string());
+        let mut buf = Buffer::from_text("fn main() {}", state.clone());
+        buf.file_path = Some("main.rs".to_string());
+        assert!(buf.format().is_err());
+        assert_eq!(buf.text(), "fn main() {}");
+        state.lock().unwrap().settings.formatters.insert("rust".to_string(), "tr a-z A-Z".to_string());
+        buf.format().unwrap();
+        assert_eq!(buf.text(), "FN MAIN() {}");
+    }
+
+    #[test]
+    fn tidies_whitespace() {
+        let state = Arc::new(Mutex::new(State::new()));
+        state.lock().unwrap().settings.tabWidth = 4;
+        let mut buf = Buffer::from_text("a  \n\tb \t\n      c\n \t", state);
+        buf.trim_lines(0, 3).unwrap();
+        assert_eq!(buf.text(), "a\n\tb\n      c\n");
+        buf.retab_lines(0, 3, false).unwrap();
+        assert_eq!(buf.text(), "a\n\tb\n\t  c\n");
+        buf.retab_lines(1, 2, true).unwrap();
+        assert_eq!(buf.text(), "a\n    b\n      c\n");
+    }
+}
@@ -0,0 +1,177 @@
+use errors::CrbResult;
+use jobs::{JobId, Jobs};
+
+/// A place in the source that the build complained about.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub file: String,
+    /// 1-based, like the compiler prints them.
+    pub line: i32,
+    pub col: i32,
+    pub message: String,
+    /// Which line of the build output it came from.
+    pub output_line: i32,
+}
+
+/// The build job, and the quickfix list made from its output.
+pub struct Build {
+    job: Option<JobId>,
+    /// Exec the new crb when this build succeeds.
+    pub restart: bool,
+    pub errors: Vec<Diagnostic>,
+    current: Option<usize>,
+    output_lines: i32,
+    /// A rustc message whose `-->` location hasn't come yet.
+    pending_message: Option<String>,
+}
+
+impl Build {
+    pub fn new() -> Build {
+        Build {
+            job: None,
+            restart: false,
+            errors: Vec::new(),
+            current: None,
+            output_lines: 0,
+            pending_message: None,
+        }
+    }
+
+    /// Whether a job is this build.
+    pub fn owns(&self, id: JobId) -> bool {
+        self.job == Some(id)
+    }
+
+    /// Run a shell command, killing the build in progress if there is one.
+    pub fn start(&mut self, jobs: &mut Jobs, cmd: &str, restart: bool) -> CrbResult<()> {
+        if let Some(id) = self.job.take() {
+            jobs.cancel(id);
+        }
+        self.list();
+        self.job = Some(try!(jobs.spawn(cmd)));
+        self.restart = restart;
+        Ok(())
+    }
+
+    /// Whether a build is going on, whose list this is.
+    pub fn running(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// Start an empty list. Lists that aren't from a build, like
+    /// references, are fed in with `output` too, when no build is running.
+    pub fn list(&mut self) {
+        self.restart = false;
+        self.errors.clear();
+        self.current = None;
+        self.output_lines = 0;
+        self.pending_message = None;
+    }
+
+    /// The build's job is over, one way or another.
+    pub fn finish(&mut self) {
+        self.job = None;
+    }
+
+    /// Take in a line of output, picking out a diagnostic if there is one.
+    pub fn output(&mut self, line: &str) {
+        self.scan(line);
+        self.output_lines += 1;
+    }
+
+    fn scan(&mut self, line: &str) {
+        let trimmed = line.trim_left();
+        if trimmed.starts_with("error") || trimmed.starts_with("warning") {
+            self.pending_message = Some(trimmed.to_string());
+        }
+        let (located, rustc) = match trimmed.starts_with("--> ") {
+            true => (&trimmed[4..], true),
+            false => (line, false),
+        };
+        if let Some((file, l, c, rest)) = parse_location(located) {
+            let message = match (rustc, self.pending_message.take()) {
+                (true, Some(m)) => m,
+                _ => rest.trim().to_string(),
+            };
+            self.errors.push(Diagnostic {
+                file: file,
+                line: l,
+                col: c,
+                message: message,
+                output_line: self.output_lines,
+            });
+        }
+    }
+
+    /// Move n entries through the quickfix list (back if negative).
+    pub fn next_error(&mut self, n: i32) -> Option<&Diagnostic> {
+        if self.errors.is_empty() {
+            return None;
+        }
+        let last = self.errors.len() as i32 - 1;
+        let i = match self.current {
+            Some(i) => i as i32 + n,
+            None if n > 0 => n - 1,
+            None => last + n + 1,
+        };
+        let i = if i < 0 { 0 } else if i > last { last } else { i } as usize;
+        self.current = Some(i);
+        self.errors.get(i)
+    }
+}
+
+/// Parse `file:line:col: rest` or `file:line: rest`. The file has to look
+/// like a path, so that times and the like don't count.
+fn parse_location(s: &str) -> Option<(String, i32, i32, &str)> {
+    let mut parts = s.splitn(3, ':');
+    let file = match parts.next() {
+        Some(f) if !f.contains(' ') && (f.contains('.') || f.contains('/')) => f,
+        _ => return None,
+    };
+    let line: i32 = match parts.next().and_then(|l| l.parse().ok()) {
+        Some(l) => l,
+        None => return None,
+    };
+    let rest = parts.next().unwrap_or("");
+    let mut more = rest.splitn(2, ':');
+    match more.next().and_then(|c| c.parse().ok()) {
+        Some(col) => Some((file.to_string(), line, col, more.next().unwrap_or(""))),
+        None => Some((file.to_string(), line, 1, rest)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_locations() {
+        assert_eq!(parse_location("src/main.rs:12:5: error: oops"),
+                   Some(("src/main.rs".to_string(), 12, 5, " error: oops")));
+        assert_eq!(parse_location("main.c:3: warning: unused"),
+                   Some(("main.c".to_string(), 3, 1, " warning: unused")));
+        assert_eq!(parse_location("Finished at 12:30:01"), None);
+        assert_eq!(parse_location("Makefile:x: nope"), None);
+    }
+
+    #[test]
+    fn lists_errors() {
+        let mut build = Build::new();
+        for line in &["   Compiling crb v0.1.0",
+                      "error[E0308]: mismatched types",
+                      "  --> src/main.rs:879:36",
+                      "   |",
+                      "gcc.c:4:2: warning: implicit declaration"] {
+            build.output(line);
+        }
+        assert_eq!(build.errors.len(), 2);
+        assert_eq!(build.errors[0].message, "error[E0308]: mismatched types");
+        assert_eq!((build.errors[0].line, build.errors[0].col, build.errors[0].output_line),
+                   (879, 36, 2));
+        assert_eq!(build.errors[1].file, "gcc.c");
+        assert_eq!(build.errors[1].message, "warning: implicit declaration");
+        assert_eq!(build.next_error(1).unwrap().line, 879);
+        assert_eq!(build.next_error(5).unwrap().line, 4);
+        assert_eq!(build.next_error(-1).unwrap().line, 879);
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::Path;
+
+/// Completion in progress in Insert mode. Going through the candidates
+/// puts each in place of what was typed, and going past the last one
+/// brings back what was typed.
+#[derive(Debug)]
+pub struct Completion {
+    /// What was typed.
+    pub prefix: String,
+    pub candidates: Vec<String>,
+    pub selected: Option<usize>,
+}
+
+impl Completion {
+    pub fn new(prefix: &str, candidates: Vec<String>) -> Completion {
+        Completion {
+            prefix: prefix.to_string(),
+            candidates: candidates,
+            selected: None,
+        }
+    }
+
+    /// The text in place of the prefix.
+    pub fn current(&self) -> &str {
+        match self.selected {
+            Some(i) => &self.candidates[i],
+            None => &self.prefix,
+        }
+    }
+
+    /// Move n candidates down the list (up if negative).
+    pub fn select(&mut self, n: i32) {
+        // One past the last candidate is the prefix.
+        let len = self.candidates.len() as i32 + 1;
+        let now = self.selected.map_or(len - 1, |i| i as i32);
+        let next = ((now + n) % len + len) % len;
+        self.selected = match next == len - 1 {
+            true => None,
+            false => Some(next as usize),
+        };
+    }
+
+    /// Add candidates that came later, like from a language server.
+    pub fn add(&mut self, more: Vec<String>) {
+        for c in more {
+            if c.starts_with(&self.prefix) && c != self.prefix && !self.candidates.contains(&c) {
+                self.candidates.push(c);
+            }
+        }
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Characters that end a path when looking back from the cursor.
+fn ends_path(c: char) -> bool {
+    c.is_whitespace() || "\"'`()[]{}<>,;=".contains(c)
+}
+
+/// What's before the cursor to complete, and whether it's a path.
+pub fn prefix_at(line: &str, offset: i32) -> (String, bool) {
+    let before: Vec<char> = line.chars().take(offset as usize).collect();
+    let path_start = before.iter().rposition(|&c| ends_path(c)).map_or(0, |i| i + 1);
+    let path: String = before[path_start..].iter().cloned().collect();
+    if path.contains('/') {
+        return (path, true);
+    }
+    let word_start = before.iter().rposition(|&c| !is_word(c)).map_or(0, |i| i + 1);
+    (before[word_start..].iter().cloned().collect(), false)
+}
+
+/// Words in the texts that start with the prefix, without duplicates, in
+/// the order they come.
+pub fn words(texts: &[String], prefix: &str) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+    for text in texts {
+        for word in text.split(|c: char| !is_word(c)) {
+            if word.len() > prefix.len() && word.starts_with(prefix) &&
+               !found.iter().any(|w| w == word) {
+                found.push(word.to_string());
+            }
+        }
+    }
+    found
+}
+
+/// Files and directories whose path starts with the prefix. Directories
+/// end in `/`.
+pub fn paths(prefix: &str) -> Vec<String> {
+    let (dir, name) = match prefix.rfind('/') {
+        Some(i) => (&prefix[..i + 1], &prefix[i + 1..]),
+        None => ("", prefix),
+    };
+    let entries = match fs::read_dir(Path::new(if dir.is_empty() { "." } else { dir })) {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    let mut found: Vec<String> = entries.filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let file = e.file_name().to_string_lossy().into_owned();
+            // Hidden files only if asked for.
+            if !file.starts_with(name) || (file.starts_with('.') && !name.starts_with('.')) {
+                return None;
+            }
+            let slash = match e.file_type().map(|t| t.is_dir()) {
+                Ok(true) => "/",
+                _ => "",
+            };
+            Some(format!("{}{}{}", dir, file, slash))
+        })
+        .collect();
+    found.sort();
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn selects_around_to_the_prefix() {
+        let mut c = Completion::new("fo", strings(&["foo", "food"]));
+        assert_eq!(c.current(), "fo");
+        c.select(1);
+        assert_eq!(c.current(), "foo");
+        c.select(1);
+        assert_eq!(c.current(), "food");
+        c.select(1);
+        assert_eq!(c.current(), "fo");
+        c.select(-1);
+        assert_eq!(c.current(), "food");
+        c.select(5);
+        assert_eq!(c.current(), "foo");
+    }
+
+    #[test]
+    fn adds_only_new_matches() {
+        let mut c = Completion::new("fo", strings(&["foo"]));
+        c.add(strings(&["foo", "fo", "bar", "fold", "fold"]));
+        assert_eq!(c.candidates, strings(&["foo", "fold"]));
+        assert_eq!(words(&strings(&["fo foo, foo_bar(foo)"]), "fo"),
+                   strings(&["foo", "foo_bar"]));
+    }
+
+    #[test]
+    fn finds_paths_and_words() {
+        assert_eq!(prefix_at("let x = self.na", 15), ("na".to_string(), false));
+        assert_eq!(prefix_at("open(\"src/ma", 12), ("src/ma".to_string(), true));
+        assert_eq!(prefix_at("cat /tmp/a.b more", 12), ("/tmp/a.b".to_string(), true));
+        assert_eq!(prefix_at("", 0), (String::new(), false));
+        assert_eq!(paths("src/complet"), strings(&["src/complete.rs"]));
+    }
+}
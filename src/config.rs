@@ -0,0 +1,182 @@
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::time::Duration;
+
+use errors::{CrbError, CrbResult};
+use keymap;
+use keymap::Binding;
+use state::State;
+
+/// Location of the config file: $CRBRC, or ~/.crbrc.
+pub fn path() -> Option<String> {
+    if let Ok(p) = env::var("CRBRC") {
+        return Some(p);
+    }
+    env::var("HOME").ok().map(|home| home + "/.crbrc")
+}
+
+/// Project settings, like the build command, go in .crbrc in the
+/// directory crb is started from.
+const PROJECT: &'static str = ".crbrc";
+
+/// Load the config file, then the project's, into the state. Missing files
+/// are not an error. The project's file can only set commands to run if the
+/// config file trusts its directory, so that starting crb in a cloned repo
+/// doesn't run whatever it says.
+pub fn load(state: &mut State) -> CrbResult<()> {
+    let global = path();
+    if let Some(ref p) = global {
+        try!(load_file(state, p, true));
+    }
+    let same = match global {
+        Some(ref p) => fs::canonicalize(p).ok() == fs::canonicalize(PROJECT).ok(),
+        None => false,
+    };
+    if same {
+        return Ok(());
+    }
+    let cwd = env::current_dir().and_then(|d| fs::canonicalize(d)).ok();
+    let trusted = cwd.is_some() &&
+                  state.settings.trustedDirs.iter().any(|d| fs::canonicalize(d).ok() == cwd);
+    load_file(state, PROJECT, trusted)
+}
+
+fn load_file(state: &mut State, path: &str, trusted: bool) -> CrbResult<()> {
+    let mut contents = String::new();
+    match fs::File::open(path) {
+        Ok(mut f) => {
+            try!(f.read_to_string(&mut contents)
+                .map_err(|e| CrbError::new(&format!("error reading {}: {}", path, e))));
+        }
+        Err(_) => return Ok(()),
+    }
+    apply(state, &contents, trusted).map_err(|e| CrbError::new(&format!("{}: {}", path, e)))
+}
+
+/// Apply config text line by line. Stops at the first bad line. Lines that
+/// set commands to run are bad unless the text is `trusted`.
+pub fn apply(state: &mut State, contents: &str, trusted: bool) -> CrbResult<()> {
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        try!(directive(state, &words, trusted)
+            .map_err(|e| CrbError::new(&format!("config line {}: {}", i + 1, e))));
+    }
+    Ok(())
+}
+
+/// Directives:
+///   trust <directory>
+///   leader <key>
+///   timeout <milliseconds>
+///   map <mode> <keys> <command> [args...]
+///   remap <mode> <keys> <keys>
+///   unmap <mode> <keys>
+///   build <shell command...>
+///   format <filetype> <shell command...>
+///   lsp <filetype> <shell command...>
+///   pairs <filetype> [pairs]
+///   fold <filetype> <manual|indent|syntax>
+///   whitespace <show|trailing|trim-on-save|retab-on-save> <on|off>
+///   whitespace glyphs <tab><trail><nbsp>
+fn directive(state: &mut State, words: &[&str], trusted: bool) -> Result<(), String> {
+    let leader = state.keymap.leader;
+    let argc = words.len() - 1;
+    match words[0] {
+        "trust" | "build" | "format" | "lsp" if !trusted => {
+            return Err(format!("{} is only allowed in {} or a trusted project; add `trust {}` there",
+                               words[0],
+                               path().unwrap_or("~/.crbrc".to_string()),
+                               env::current_dir().map(|d| d.display().to_string()).unwrap_or_default()));
+        }
+        _ => {}
+    }
+    match (words[0], argc) {
+        ("trust", 1) => {
+            let dir = match (words[1].starts_with("~/"), env::var("HOME")) {
+                (true, Ok(home)) => home + &words[1][1..],
+                _ => words[1].to_string(),
+            };
+            state.settings.trustedDirs.push(dir);
+        }
+        ("leader", 1) => {
+            let keys = try!(keymap::parse_keys(words[1], leader));
+            if keys.len() != 1 {
+                return Err("leader must be a single key".to_string());
+            }
+            state.keymap.leader = keys[0];
+        }
+        ("timeout", 1) => {
+            let ms: u64 = try!(words[1].parse().map_err(|_| format!("bad timeout {}", words[1])));
+            state.keymap.timeout = Duration::from_millis(ms);
+        }
+        ("map", n) if n >= 3 => {
+            let mode = try!(keymap::parse_mode(words[1]));
+            let keys = try!(keymap::parse_keys(words[2], leader));
+            let cmd = try!(keymap::parse_command(words[3], &words[4..]));
+            state.keymap.bind(mode, keys, Binding::Cmd(cmd));
+        }
+        ("remap", 3) => {
+            let mode = try!(keymap::parse_mode(words[1]));
+            let keys = try!(keymap::parse_keys(words[2], leader));
+            let target = try!(keymap::parse_keys(words[3], leader));
+            state.keymap.bind(mode, keys, Binding::Keys(target));
+        }
+        ("unmap", 2) => {
+            let mode = try!(keymap::parse_mode(words[1]));
+            let keys = try!(keymap::parse_keys(words[2], leader));
+            state.keymap.unbind(mode, &keys);
+        }
+        ("build", n) if n >= 1 => {
+            state.settings.buildCommand = words[1..].join(" ");
+        }
+        ("format", n) if n >= 2 => {
+            state.settings.formatters.insert(words[1].to_string(), words[2..].join(" "));
+        }
+        ("pairs", 1) | ("pairs", 2) => {
+            let pairs = words.get(2).unwrap_or(&"");
+            if pairs.chars().count() % 2 != 0 {
+                return Err(format!("pairs need an opener and a closer: {}", pairs));
+            }
+            state.settings.autoPairs.insert(words[1].to_string(), pairs.to_string());
+        }
+        ("fold", 2) => {
+            match words[2] {
+                "manual" | "indent" | "syntax" => {}
+                m => return Err(format!("unknown fold method: {}", m)),
+            }
+            state.settings.foldMethods.insert(words[1].to_string(), words[2].to_string());
+        }
+        ("whitespace", 2) => {
+            let glyphs: Vec<char> = words[2].chars().collect();
+            let on = match words[2] {
+                "on" => true,
+                "off" => false,
+                _ if words[1] == "glyphs" && glyphs.len() == 3 => false,
+                w => return Err(format!("expected on or off: {}", w)),
+            };
+            let settings = &mut state.settings;
+            match words[1] {
+                "show" => settings.showWhitespace = on,
+                "trailing" => settings.highlightTrailing = on,
+                "trim-on-save" => settings.trimOnSave = on,
+                "retab-on-save" => settings.retabOnSave = on,
+                "glyphs" if glyphs.len() == 3 => {
+                    settings.tabGlyph = glyphs[0];
+                    settings.trailGlyph = glyphs[1];
+                    settings.nbspGlyph = glyphs[2];
+                }
+                w => return Err(format!("unknown whitespace setting: {}", w)),
+            }
+        }
+        ("lsp", n) if n >= 2 => {
+            state.settings.languageServers.insert(words[1].to_string(), words[2..].join(" "));
+        }
+        _ => return Err(format!("bad directive: {}", words.join(" "))),
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustbox;
+use rustbox::{Key, RustBox};
+
+use jobs;
+use jobs::JobEvent;
+use lsp::Reply;
+use state;
+use state::State;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timer {
+    /// Stop waiting for the rest of a key sequence or a paste.
+    Keys,
+}
+
+/// Everything the main loop waits for.
+#[derive(Debug)]
+pub enum Event {
+    /// A key from the terminal.
+    Key(Key),
+    /// A key queued up to be handled again, like a mapping's keys.
+    Typeahead(Key),
+    Timer(Timer),
+    Jobs(Vec<JobEvent>),
+    /// What language servers said.
+    Lsp(Vec<Reply>),
+    /// Time to look at the terminal windows.
+    Tick,
+}
+
+/// Waits on the terminal, background jobs, language servers and timers all
+/// at once. termbox can't wait on anything but the terminal, so while jobs
+/// or servers run it looks at them every `jobs::poll_interval()`.
+pub struct EventLoop {
+    timers: Vec<(Instant, Timer)>,
+}
+
+impl EventLoop {
+    pub fn new() -> EventLoop {
+        EventLoop { timers: Vec::new() }
+    }
+
+    /// Fire a timer after a while, replacing it if it was already set.
+    pub fn set_timer(&mut self, timer: Timer, after: Duration) {
+        self.cancel_timer(timer);
+        self.timers.push((Instant::now() + after, timer));
+    }
+
+    pub fn cancel_timer(&mut self, timer: Timer) {
+        self.timers.retain(|&(_, t)| t != timer);
+    }
+
+    /// A timer that's gone off, if any.
+    fn expired(&mut self) -> Option<Timer> {
+        let now = Instant::now();
+        let i = match self.timers.iter().position(|&(when, _)| when <= now) {
+            Some(i) => i,
+            None => return None,
+        };
+        Some(self.timers.remove(i).1)
+    }
+
+    /// How long until the next timer goes off.
+    fn next_timer(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.timers
+            .iter()
+            .map(|&(when, _)| if when > now { when - now } else { Duration::new(0, 0) })
+            .min()
+    }
+
+    /// Wait for the next thing to happen. Typeahead goes first, then timers
+    /// and jobs, then the terminal. With `ticking`, nothing happening for a
+    /// while is a `Tick`.
+    pub fn next(&mut self,
+                rustbox: &RustBox,
+                state: &Arc<Mutex<State>>,
+                ticking: bool)
+                -> Result<Event, rustbox::EventError> {
+        loop {
+            if let Some(key) = state::do_safe(&**state, |s| s.typeahead.pop_front()) {
+                return Ok(Event::Typeahead(key));
+            }
+            if let Some(timer) = self.expired() {
+                return Ok(Event::Timer(timer));
+            }
+            let events = state::do_safe(&**state, |s| s.jobs.poll());
+            if !events.is_empty() {
+                return Ok(Event::Jobs(events));
+            }
+            let replies = state::do_safe(&**state, |s| s.lsp.poll());
+            if !replies.is_empty() {
+                return Ok(Event::Lsp(replies));
+            }
+            let running = state::do_safe(&**state, |s| s.jobs.running() || s.lsp.running());
+            let wait = match (self.next_timer(), running || ticking) {
+                (Some(t), true) => Some(cmp::min(t, jobs::poll_interval())),
+                (None, true) => Some(jobs::poll_interval()),
+                (t, false) => t,
+            };
+            let event = match wait {
+                Some(t) => try!(rustbox.peek_event(t, false)),
+                None => try!(rustbox.poll_event(false)),
+            };
+            match event {
+                rustbox::Event::KeyEvent(key) => return Ok(Event::Key(key)),
+                rustbox::Event::NoEvent if ticking => return Ok(Event::Tick),
+                _ => {}
+            }
+        }
+    }
+}
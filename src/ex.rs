@@ -0,0 +1,291 @@
+/// A line in a range, before it's looked up in a buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    /// `.`, the line of the cursor.
+    Current,
+    /// `$`
+    Last,
+    /// A line number, 1-based as typed.
+    Line(i32),
+    /// `'x`, the line of a mark.
+    Mark(char),
+}
+
+/// Lines to work on: an address plus an offset at each end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Range {
+    pub start: (Address, i32),
+    pub end: (Address, i32),
+}
+
+/// How `:sort` orders lines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+    /// By the first number in each line. Lines without one go first.
+    pub numeric: bool,
+    pub ignore_case: bool,
+    pub reverse: bool,
+}
+
+/// A command typed after `:`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ex {
+    /// `:!cmd` runs a command and shows what it prints.
+    Shell(String),
+    /// `:r !cmd` inserts what a command prints at the cursor.
+    ReadShell(String),
+    /// `:{range}!cmd` pipes lines through a command and puts its output
+    /// in their place.
+    Filter(Range, String),
+    /// `:{range}>` and `:{range}<` indent lines a level more or less, once
+    /// for each `>` or `<`.
+    Shift(Range, i32),
+    /// `:{range}=` indents lines by their brackets.
+    Reindent(Range),
+    /// `:{range}sort[!] [n] [i]` sorts lines, backwards with `!`, by number
+    /// with `n` and ignoring case with `i`. The range is the whole file if
+    /// it's left out, and for the commands below too.
+    Sort(Range, Sort),
+    /// `:{range}uniq` drops lines that came before, keeping the first.
+    Uniq(Range),
+    /// `:{range}reverse` turns lines upside down.
+    Reverse(Range),
+    /// `:{range}align <delimiter>` lines up the delimiters in lines by
+    /// padding the text before them.
+    Align(Range, String),
+    /// `:{range}trim` strips whitespace from the end of lines.
+    Trim(Range),
+    /// `:{range}retab [tabs|spaces]` redoes indentation in tabs or spaces,
+    /// by `insertSpaces` if neither is given.
+    Retab(Range, Option<bool>),
+    /// `:{range}comment` comments lines out, or back in.
+    Comment(Range),
+    /// `:{range}fo[ld]` folds lines away.
+    Fold(Range),
+}
+
+pub fn parse(line: &str) -> Result<Ex, String> {
+    let line = line.trim_left();
+    let (range, rest) = try!(parse_range(line));
+    let rest = rest.trim_left();
+    if rest.starts_with('!') {
+        let cmd = rest[1..].trim().to_string();
+        if cmd.is_empty() {
+            return Err("no command".to_string());
+        }
+        return Ok(match range {
+            Some(r) => Ex::Filter(r, cmd),
+            None => Ex::Shell(cmd),
+        });
+    }
+    let range_or_line = || {
+        range.clone().unwrap_or(Range {
+            start: (Address::Current, 0),
+            end: (Address::Current, 0),
+        })
+    };
+    if rest.starts_with('>') || rest.starts_with('<') {
+        let right = rest.chars().take_while(|&c| c == '>').count() as i32;
+        let left = rest.chars().take_while(|&c| c == '<').count() as i32;
+        if rest[(right + left) as usize..].trim().is_empty() {
+            return Ok(Ex::Shift(range_or_line(), right - left));
+        }
+    }
+    if rest.trim() == "=" {
+        return Ok(Ex::Reindent(range_or_line()));
+    }
+    let range_or_all = || {
+        range.clone().unwrap_or(Range {
+            start: (Address::Line(1), 0),
+            end: (Address::Last, 0),
+        })
+    };
+    let (name, arg) = match rest.find(|c: char| c.is_whitespace() || c == '!') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    };
+    match name {
+        "sort" => {
+            let reverse = arg.starts_with('!');
+            let flags = arg.trim_left_matches('!');
+            if let Some(c) = flags.chars().find(|&c| !c.is_whitespace() && c != 'n' && c != 'i') {
+                return Err(format!("unknown sort option: {}", c));
+            }
+            let sort = Sort {
+                numeric: flags.contains('n'),
+                ignore_case: flags.contains('i'),
+                reverse: reverse,
+            };
+            return Ok(Ex::Sort(range_or_all(), sort));
+        }
+        "uniq" if arg.trim().is_empty() => return Ok(Ex::Uniq(range_or_all())),
+        "reverse" if arg.trim().is_empty() => return Ok(Ex::Reverse(range_or_all())),
+        "trim" if arg.trim().is_empty() => return Ok(Ex::Trim(range_or_all())),
+        "retab" => {
+            let spaces = match arg.trim() {
+                "" => None,
+                "spaces" => Some(true),
+                "tabs" => Some(false),
+                a => return Err(format!("expected tabs or spaces: {}", a)),
+            };
+            return Ok(Ex::Retab(range_or_all(), spaces));
+        }
+        "align" => {
+            let delimiter = arg.trim();
+            if delimiter.is_empty() {
+                return Err("align needs a delimiter".to_string());
+            }
+            return Ok(Ex::Align(range_or_all(), delimiter.to_string()));
+        }
+        _ => {}
+    }
+    if rest.trim() == "comment" {
+        return Ok(Ex::Comment(range_or_line()));
+    }
+    if let Some(ref r) = range {
+        if rest.trim() == "fold" || rest.trim() == "fo" {
+            return Ok(Ex::Fold(r.clone()));
+        }
+    }
+    for name in ["read", "r"].iter() {
+        if range.is_none() && rest.starts_with(name) {
+            let arg = rest[name.len()..].trim_left();
+            if arg.starts_with('!') && arg.len() > 1 {
+                return Ok(Ex::ReadShell(arg[1..].trim().to_string()));
+            }
+            return Err("only :r !cmd is supported".to_string());
+        }
+    }
+    Err(format!("not a command: {}", line))
+}
+
+/// `%`, `addr` or `addr,addr` at the start of the line, if there is one.
+fn parse_range(s: &str) -> Result<(Option<Range>, &str), String> {
+    if s.starts_with('%') {
+        let all = Range {
+            start: (Address::Line(1), 0),
+            end: (Address::Last, 0),
+        };
+        return Ok((Some(all), &s[1..]));
+    }
+    let (start, rest) = try!(parse_address(s));
+    let start = match start {
+        Some(a) => a,
+        None => return Ok((None, s)),
+    };
+    if !rest.starts_with(',') {
+        let range = Range {
+            start: start.clone(),
+            end: start,
+        };
+        return Ok((Some(range), rest));
+    }
+    match try!(parse_address(&rest[1..])) {
+        (Some(end), rest) => {
+            Ok((Some(Range {
+                start: start,
+                end: end,
+            }),
+                rest))
+        }
+        (None, _) => Err("range needs an end".to_string()),
+    }
+}
+
+/// An address and its `+n`/`-n` offsets.
+fn parse_address(s: &str) -> Result<(Option<(Address, i32)>, &str), String> {
+    let mut chars = s.char_indices();
+    let (addr, mut rest) = match chars.next() {
+        Some((_, '.')) => (Some(Address::Current), &s[1..]),
+        Some((_, '$')) => (Some(Address::Last), &s[1..]),
+        Some((_, '\'')) => {
+            match chars.next() {
+                Some((i, c)) => (Some(Address::Mark(c)), &s[i + c.len_utf8()..]),
+                None => return Err("mark needs a name".to_string()),
+            }
+        }
+        Some((_, c)) if c.is_digit(10) => {
+            let (n, rest) = number(s);
+            (Some(Address::Line(n)), rest)
+        }
+        // A bare offset counts from the cursor.
+        Some((_, '+')) | Some((_, '-')) => (Some(Address::Current), s),
+        _ => (None, s),
+    };
+    let addr = match addr {
+        Some(a) => a,
+        None => return Ok((None, s)),
+    };
+    let mut offset = 0;
+    loop {
+        let sign = match rest.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => break,
+        };
+        let (n, after) = number(&rest[1..]);
+        // `+` on its own means one line.
+        offset += sign * if after.len() == rest.len() - 1 { 1 } else { n };
+        rest = after;
+    }
+    Ok((Some((addr, offset)), rest))
+}
+
+/// The digits at the start of a string, and what's after them.
+fn number(s: &str) -> (i32, &str) {
+    let end = s.find(|c: char| !c.is_digit(10)).unwrap_or(s.len());
+    (s[..end].parse().unwrap_or(0), &s[end..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(start: (Address, i32), end: (Address, i32)) -> Range {
+        Range {
+            start: start,
+            end: end,
+        }
+    }
+
+    #[test]
+    fn parses_ranges_and_filters() {
+        assert_eq!(parse("!make"), Ok(Ex::Shell("make".to_string())));
+        assert_eq!(parse("r !date"), Ok(Ex::ReadShell("date".to_string())));
+        assert_eq!(parse(".,+5!rustfmt"),
+                   Ok(Ex::Filter(range((Address::Current, 0), (Address::Current, 5)),
+                                 "rustfmt".to_string())));
+        assert_eq!(parse("'a,$-1!sort"),
+                   Ok(Ex::Filter(range((Address::Mark('a'), 0), (Address::Last, -1)),
+                                 "sort".to_string())));
+        assert_eq!(parse("%!sort"),
+                   Ok(Ex::Filter(range((Address::Line(1), 0), (Address::Last, 0)),
+                                 "sort".to_string())));
+        assert_eq!(parse("3>>"),
+                   Ok(Ex::Shift(range((Address::Line(3), 0), (Address::Line(3), 0)), 2)));
+        assert!(parse("1,!ls").is_err());
+        assert!(parse("!").is_err());
+        assert!(parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn parses_line_commands() {
+        let all = range((Address::Line(1), 0), (Address::Last, 0));
+        let sort = Sort {
+            numeric: true,
+            ignore_case: false,
+            reverse: true,
+        };
+        assert_eq!(parse("sort! n"), Ok(Ex::Sort(all.clone(), sort)));
+        assert!(parse("sort x").is_err());
+        assert_eq!(parse("2,4uniq"),
+                   Ok(Ex::Uniq(range((Address::Line(2), 0), (Address::Line(4), 0)))));
+        assert_eq!(parse("align ="), Ok(Ex::Align(all.clone(), "=".to_string())));
+        assert!(parse("align").is_err());
+        assert_eq!(parse("trim"), Ok(Ex::Trim(all.clone())));
+        assert_eq!(parse("retab"), Ok(Ex::Retab(all.clone(), None)));
+        assert_eq!(parse("retab tabs"), Ok(Ex::Retab(all.clone(), Some(false))));
+        assert!(parse("retab both").is_err());
+        assert!(parse("fold").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+
+/// The filetype of a file, from its extension or name. Config settings that
+/// depend on the language, like formatters, are keyed by these names.
+pub fn detect(path: &str) -> Option<&'static str> {
+    let p = Path::new(path);
+    if let Some("Makefile") = p.file_name().and_then(|n| n.to_str()) {
+        return Some("make");
+    }
+    let ft = match p.extension().and_then(|e| e.to_str()) {
+        Some("rs") => "rust",
+        Some("c") | Some("h") => "c",
+        Some("cc") | Some("cpp") | Some("hpp") => "cpp",
+        Some("go") => "go",
+        Some("py") => "python",
+        Some("js") => "javascript",
+        Some("sh") => "sh",
+        Some("toml") => "toml",
+        Some("lua") => "lua",
+        Some("sql") => "sql",
+        Some("hs") => "haskell",
+        Some("md") => "markdown",
+        _ => return None,
+    };
+    Some(ft)
+}
+
+/// Characters that indent the next line when a line leaves them open.
+/// Brackets close again, and other openers, like Python's `:`, only count
+/// at the end of a line.
+pub fn indent_openers(filetype: Option<&str>) -> &'static str {
+    match filetype {
+        Some("python") => "([{:",
+        Some("markdown") | None => "",
+        _ => "([{",
+    }
+}
+
+/// Enough of a language's syntax to tell code from strings and comments.
+pub struct Syntax {
+    pub line_comment: Option<&'static str>,
+    /// Start and end.
+    pub block_comment: Option<(&'static str, &'static str)>,
+    /// Characters that start and end strings.
+    pub quotes: &'static str,
+}
+
+pub fn syntax(filetype: Option<&str>) -> Option<Syntax> {
+    let (line_comment, block_comment, quotes) = match filetype {
+        // Quotes in Rust are mostly lifetimes.
+        Some("rust") => (Some("//"), Some(("/*", "*/")), "\""),
+        Some("c") | Some("cpp") | Some("javascript") => (Some("//"), Some(("/*", "*/")), "\"'"),
+        Some("go") => (Some("//"), Some(("/*", "*/")), "\"'`"),
+        Some("python") | Some("sh") | Some("toml") => (Some("#"), None, "\"'"),
+        Some("make") => (Some("#"), None, ""),
+        Some("lua") => (Some("--"), Some(("--[[", "]]")), "\"'"),
+        Some("sql") => (Some("--"), Some(("/*", "*/")), "'"),
+        // Quotes in Haskell are mostly primes, like x'.
+        Some("haskell") => (Some("--"), Some(("{-", "-}")), "\""),
+        Some("markdown") => (None, Some(("<!--", "-->")), ""),
+        _ => return None,
+    };
+    Some(Syntax {
+        line_comment: line_comment,
+        block_comment: block_comment,
+        quotes: quotes,
+    })
+}
@@ -2,7 +2,29 @@ use rustbox;
 use rustbox::{RustBox, Color};
 
 use window::Window;
-use buffer::Symbol;
+use buffer::{AnchorKind, Symbol};
+
+/// Show what's being typed after `:` on the bottom row.
+pub fn render_cmdline(rb: &RustBox, text: &str) {
+    let y = rb.height() - 1;
+    let line = format!(":{}", text);
+    let width = line.chars().count();
+    rb.print(0, y, rustbox::RB_NORMAL, Color::White, Color::Black, &line);
+    rb.print_char(width, y, rustbox::RB_NORMAL, Color::Black, Color::White, ' ');
+    for x in width + 1..rb.width() {
+        rb.print_char(x, y, rustbox::RB_NORMAL, Color::White, Color::Black, ' ');
+    }
+}
+
+/// Show a message, like an error, on the bottom row.
+pub fn render_message(rb: &RustBox, text: &str) {
+    let y = rb.height() - 1;
+    let text: String = text.lines().next().unwrap_or("").chars().take(rb.width()).collect();
+    rb.print(0, y, rustbox::RB_NORMAL, Color::Red, Color::Black, &text);
+    for x in text.chars().count()..rb.width() {
+        rb.print_char(x, y, rustbox::RB_NORMAL, Color::White, Color::Black, ' ');
+    }
+}
 
 pub fn render(rb: &RustBox, window: &Window) {
     // Write file name to top bar
@@ -24,7 +46,7 @@ pub fn render(rb: &RustBox, window: &Window) {
 
     }
     // Write buffer contents
-    let mut cursor_is_next = false;
+    let mut cursor_is_next = None;
     window.display(|cell| {
         let one_for_the_bar = 1;
         let y = (cell.y + window.topleft.y + one_for_the_bar) as usize;
@@ -33,26 +55,31 @@ pub fn render(rb: &RustBox, window: &Window) {
         let white = Color::White;
         let black = Color::Black;
         let (fg, bg) = match cursor_is_next {
-            false => (white, black),
-            true => (black, white),
+            None => (white, black),
+            Some(color) => (black, color),
         };
         match cell.symbol {
             Symbol::Void => {
                 rb.print_char(x, y, sty, fg, bg, ' ');
-                cursor_is_next = false;
+                cursor_is_next = None;
             }
             Symbol::Skip => {}
             Symbol::Char(c) => {
                 rb.print_char(x, y, sty, fg, bg, c);
-                cursor_is_next = false;
+                cursor_is_next = None;
             }
-            Symbol::Anchor(_) => {
-                // TODO check if the anchor is a cursor.
-                cursor_is_next = true;
+            Symbol::Anchor(a, AnchorKind::Cursor) => {
+                // Cursors of other windows aren't drawn.
+                if window.is_primary_cursor(&a) {
+                    cursor_is_next = Some(white);
+                } else if window.is_cursor(&a) {
+                    cursor_is_next = Some(Color::Cyan);
+                }
             }
+            Symbol::Anchor(_, _) => {}
             Symbol::ColorChar(c, color) => {
                 rb.print_char(x, y, sty, color, bg, c);
-                cursor_is_next = false;
+                cursor_is_next = None;
             }
         }
     });
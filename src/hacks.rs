@@ -1,23 +1,57 @@
-use std::process;
-use std::process::Command;
+use std::io;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
 use std::os::unix::process::CommandExt;
 
 use errors::{CrbError, CrbResult};
 
-pub fn recompile() -> CrbResult<process::Output> {
-    // duct::sh("make")
-    //     .capture_stdout()
-    //     .capture_stderr()
-    //     .unchecked()
-    //     .run()
-    //     .map_err(|e| CrbError::new(&format!("Error recompiling: {:?}", e)))
-    Command::new("make")
-        .output()
-        .map_err(|e| CrbError::new(&format!("Error recompiling: {:?}", e)))
+/// Run a shell command with text on its stdin and return what it prints.
+/// If it fails, the error has what it printed on stderr.
+pub fn pipe(cmd: &str, input: &str) -> CrbResult<String> {
+    let mut child = try!(Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| CrbError::new(&format!("error running {}: {}", cmd, e))));
+    // Write from another thread, or a command that prints before it has
+    // read everything could fill its pipe and wait for us forever.
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = input.to_string();
+        thread::spawn(move || {
+            let _ = stdin.write_all(input.as_bytes());
+        })
+    });
+    let output = try!(child.wait_with_output()
+        .map_err(|e| CrbError::new(&format!("error running {}: {}", cmd, e))));
+    if let Some(w) = writer {
+        let _ = w.join();
+    }
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(CrbError::new(&format!("{} failed ({}): {}", cmd, output.status, err.trim())));
+    }
+    String::from_utf8(output.stdout)
+        .map_err(|e| CrbError::new(&format!("{} printed bad utf-8: {}", cmd, e)))
 }
 
-/// Exec the new version.
-pub fn restart() -> CrbResult<()> {
-    let e = Command::new("cargo").arg("run").arg("src/main.rs").exec();
+/// Exec the new version, passing it these arguments.
+pub fn restart(args: &[&str]) -> CrbResult<()> {
+    let e = Command::new("cargo").arg("run").arg("--").args(args).exec();
     Err(CrbError::new(&format!("Error restarting: {:?}", e)))
 }
+
+/// Ask the terminal to wrap pasted text in markers (see paste.rs).
+/// termbox doesn't know about this, so write the escape code ourselves.
+pub fn bracketed_paste(on: bool) {
+    let code = match on {
+        true => "\x1b[?2004h",
+        false => "\x1b[?2004l",
+    };
+    let mut out = io::stdout();
+    let _ = out.write_all(code.as_bytes());
+    let _ = out.flush();
+}
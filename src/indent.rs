@@ -0,0 +1,227 @@
+use std::iter;
+
+use settings::Settings;
+
+/// How indentation is written.
+#[derive(Debug, Clone, Copy)]
+pub struct Style {
+    pub spaces: bool,
+    /// Columns in one level of indentation.
+    pub width: usize,
+    pub tab_width: usize,
+}
+
+impl Style {
+    pub fn new(settings: &Settings) -> Style {
+        Style {
+            spaces: settings.insertSpaces,
+            width: match settings.insertSpaces {
+                true => settings.numSpacesPerTab,
+                false => settings.tabWidth,
+            },
+            tab_width: settings.tabWidth,
+        }
+    }
+
+    /// Indentation that's this many columns wide.
+    pub fn render(&self, cols: usize) -> String {
+        let (tabs, spaces) = match self.spaces {
+            true => (0, cols),
+            false => (cols / self.tab_width, cols % self.tab_width),
+        };
+        iter::repeat('\t').take(tabs).chain(iter::repeat(' ').take(spaces)).collect()
+    }
+
+    /// How many columns some indentation takes up.
+    pub fn columns(&self, indent: &str) -> usize {
+        indent.chars().fold(0, |cols, c| {
+            match c {
+                '\t' => (cols / self.tab_width + 1) * self.tab_width,
+                _ => cols + 1,
+            }
+        })
+    }
+
+    /// A line's indentation moved n levels (left if negative).
+    pub fn shift(&self, line: &str, n: i32) -> String {
+        let cols = self.columns(leading(line)) as i32 + n * self.width as i32;
+        self.render(if cols < 0 { 0 } else { cols as usize })
+    }
+}
+
+/// The whitespace at the start of a line.
+pub fn leading(line: &str) -> &str {
+    let end = line.find(|c: char| c != ' ' && c != '\t').unwrap_or(line.len());
+    &line[..end]
+}
+
+pub fn closer(opener: char) -> Option<char> {
+    match opener {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        _ => None,
+    }
+}
+
+pub fn is_closer(c: char) -> bool {
+    c == ')' || c == ']' || c == '}'
+}
+
+/// Whether a character closes one of the openers.
+fn closes(c: char, openers: &str) -> bool {
+    is_closer(c) && openers.chars().any(|o| closer(o) == Some(c))
+}
+
+/// Brackets opened minus brackets closed.
+fn net_brackets(text: &str, openers: &str) -> i32 {
+    text.chars().fold(0, |n, c| {
+        if openers.contains(c) && closer(c).is_some() {
+            n + 1
+        } else if closes(c, openers) {
+            n - 1
+        } else {
+            n
+        }
+    })
+}
+
+/// Whether the next line goes one level in: a bracket was left open, or
+/// the text ends with an opener like Python's `:`. Closers at the start,
+/// like in `} else {`, belong to the line before.
+pub fn opens_block(text: &str, openers: &str) -> bool {
+    match text.trim_right().chars().last() {
+        Some(c) if openers.contains(c) && closer(c).is_none() => true,
+        _ => net_brackets(text.trim_left().trim_left_matches(|c| closes(c, openers)), openers) > 0,
+    }
+}
+
+fn starts_with_closer(line: &str, openers: &str) -> bool {
+    line.trim_left().chars().next().map_or(false, |c| closes(c, openers))
+}
+
+/// Lines indented after the line above them, one level in after a block
+/// opens and out again where it closes. Blank lines are left empty.
+pub fn reindent(above: Option<&str>, lines: &[&str], openers: &str, style: &Style) -> Vec<String> {
+    let mut prev = above.map(|l| l.to_string());
+    let mut out = Vec::new();
+    for line in lines {
+        let text = line.trim_left();
+        if text.is_empty() {
+            out.push(String::new());
+            continue;
+        }
+        let mut level = 0;
+        let mut cols = 0;
+        if let Some(ref p) = prev {
+            cols = style.columns(leading(p)) as i32;
+            if opens_block(p, openers) {
+                level += 1;
+            } else if net_brackets(p, openers) < 0 && !starts_with_closer(p, openers) {
+                // The end of something that went on over several lines.
+                level -= 1;
+            }
+        }
+        if starts_with_closer(text, openers) {
+            level -= 1;
+        }
+        let cols = cols + level * style.width as i32;
+        let new = style.render(if cols < 0 { 0 } else { cols as usize }) + text;
+        out.push(new.clone());
+        prev = Some(new);
+    }
+    out
+}
+
+/// The lines of the block a line is in by indentation: the closest line
+/// before it that's indented less, through the last line after that's
+/// indented more. A line followed by deeper ones starts its own block.
+/// Blank lines go with the line after them.
+pub fn block_at(lines: &[&str], line: usize, style: &Style) -> Option<(usize, usize)> {
+    let depth = |l: usize| {
+        match lines[l].trim().is_empty() {
+            true => None,
+            false => Some(style.columns(leading(lines[l]))),
+        }
+    };
+    let next_depth = |l: usize| (l..lines.len()).filter_map(|n| depth(n)).next();
+    let here = match next_depth(line) {
+        Some(d) => d,
+        None => return None,
+    };
+    let header = match next_depth(line + 1) {
+        Some(d) if d > here && depth(line).is_some() => line,
+        _ => {
+            match (0..line).rev().find(|&l| depth(l).map_or(false, |d| d < here)) {
+                Some(l) => l,
+                None => return None,
+            }
+        }
+    };
+    let top = depth(header).unwrap_or(0);
+    let mut end = header;
+    for l in header + 1..lines.len() {
+        match depth(l) {
+            Some(d) if d <= top => break,
+            Some(_) => end = l,
+            None => {}
+        }
+    }
+    match end > header {
+        true => Some((header, end)),
+        false => None,
+    }
+}
+
+/// Every block by indentation, as its header line and last line, the way
+/// `block_at` finds them.
+pub fn blocks(lines: &[&str], style: &Style) -> Vec<(usize, usize)> {
+    let depths: Vec<Option<usize>> = lines.iter()
+        .map(|l| {
+            match l.trim().is_empty() {
+                true => None,
+                false => Some(style.columns(leading(l))),
+            }
+        })
+        .collect();
+    let mut found = Vec::new();
+    for header in 0..lines.len() {
+        let top = match depths[header] {
+            Some(d) => d,
+            None => continue,
+        };
+        let mut end = header;
+        for l in header + 1..lines.len() {
+            match depths[l] {
+                Some(d) if d <= top => break,
+                Some(_) => end = l,
+                None => {}
+            }
+        }
+        if end > header {
+            found.push((header, end));
+        }
+    }
+    found
+}
+
+/// Where the bracket that `at` (line, char offset) closes was opened:
+/// its line. Brackets of all kinds count.
+pub fn opener_line(lines: &[&str], at: (usize, usize)) -> Option<usize> {
+    let mut depth = 0;
+    for l in (0..at.0 + 1).rev() {
+        let chars: Vec<char> = lines[l].chars().collect();
+        let end = if l == at.0 { at.1 } else { chars.len() };
+        for &c in chars[..end].iter().rev() {
+            if is_closer(c) {
+                depth += 1;
+            } else if closer(c).is_some() {
+                if depth == 0 {
+                    return Some(l);
+                }
+                depth -= 1;
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::process::{Child, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+use errors::{CrbError, CrbResult};
+
+pub type JobId = u32;
+
+/// Something a background job did.
+#[derive(Debug)]
+pub enum JobEvent {
+    /// A line the job printed, on stdout or stderr.
+    Output(JobId, String),
+    /// The job exited: whether it succeeded.
+    Done(JobId, bool),
+}
+
+/// What the reader threads send back.
+enum Msg {
+    Line(JobId, String),
+    Closed(JobId),
+}
+
+struct Job {
+    child: Child,
+    /// Pipes still being read.
+    open: u32,
+}
+
+/// Shell commands running in the background. Their output is read on
+/// threads of its own and picked up by the main loop with `poll`.
+pub struct Jobs {
+    running: HashMap<JobId, Job>,
+    next_id: JobId,
+    tx: Sender<Msg>,
+    rx: Receiver<Msg>,
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        let (tx, rx) = channel();
+        Jobs {
+            running: HashMap::new(),
+            next_id: 1,
+            tx: tx,
+            rx: rx,
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        !self.running.is_empty()
+    }
+
+    pub fn spawn(&mut self, cmd: &str) -> CrbResult<JobId> {
+        let mut child = try!(unsafe {
+                process::Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .pre_exec(|| {
+                        // A process group of its own, so that cancelling
+                        // gets whatever the shell started too.
+                        libc::setpgid(0, 0);
+                        Ok(())
+                    })
+                    .spawn()
+            }
+            .map_err(|e| CrbError::new(&format!("error running {}: {}", cmd, e))));
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut open = 0;
+        if let Some(out) = child.stdout.take() {
+            forward(id, out, self.tx.clone());
+            open += 1;
+        }
+        if let Some(err) = child.stderr.take() {
+            forward(id, err, self.tx.clone());
+            open += 1;
+        }
+        self.running.insert(id,
+                            Job {
+                                child: child,
+                                open: open,
+                            });
+        Ok(id)
+    }
+
+    /// Kill a job. Output it already printed is dropped.
+    pub fn cancel(&mut self, id: JobId) -> bool {
+        match self.running.remove(&id) {
+            Some(mut job) => {
+                unsafe {
+                    libc::killpg(job.child.id() as libc::pid_t, libc::SIGKILL);
+                }
+                let _ = job.child.wait();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kill every job, returning which ones there were.
+    pub fn cancel_all(&mut self) -> Vec<JobId> {
+        let ids: Vec<JobId> = self.running.keys().cloned().collect();
+        for &id in ids.iter() {
+            self.cancel(id);
+        }
+        ids
+    }
+
+    /// Everything the jobs did since the last poll. Doesn't block: a job
+    /// whose pipes closed before it exited is done on a later poll.
+    pub fn poll(&mut self) -> Vec<JobEvent> {
+        let mut events = Vec::new();
+        while let Ok(msg) = self.rx.try_recv() {
+            match msg {
+                Msg::Line(id, line) => {
+                    if self.running.contains_key(&id) {
+                        events.push(JobEvent::Output(id, line));
+                    }
+                }
+                Msg::Closed(id) => {
+                    if let Some(job) = self.running.get_mut(&id) {
+                        job.open -= 1;
+                    }
+                }
+            }
+        }
+        let mut exited = Vec::new();
+        for (&id, job) in self.running.iter_mut() {
+            if job.open > 0 {
+                continue;
+            }
+            match job.child.try_wait() {
+                Ok(Some(status)) => exited.push((id, status.success())),
+                Ok(None) => {}
+                Err(_) => exited.push((id, false)),
+            }
+        }
+        exited.sort();
+        for (id, success) in exited {
+            self.running.remove(&id);
+            events.push(JobEvent::Done(id, success));
+        }
+        events
+    }
+}
+
+/// How often the main loop looks for output while jobs run.
+pub fn poll_interval() -> Duration {
+    Duration::from_millis(50)
+}
+
+/// Send each line of a pipe down a channel, from a thread of its own.
+fn forward<R: Read + Send + 'static>(id: JobId, pipe: R, tx: Sender<Msg>) {
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            match line {
+                Ok(l) => {
+                    if tx.send(Msg::Line(id, l)).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = tx.send(Msg::Closed(id));
+    });
+}
@@ -0,0 +1,334 @@
+use std::fmt;
+
+/// Just enough JSON to talk to language servers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Fields in the order they came in.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// A field of an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|f| f.0 == key).map(|f| &f.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::String(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Json::Number(n) => Some(n as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match *self {
+            Json::Array(ref a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// An object from field names and values.
+pub fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+impl<'a> From<&'a str> for Json {
+    fn from(s: &'a str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(n: i32) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(ref s) => write_string(f, s),
+            Json::Array(ref items) => {
+                try!(write!(f, "["));
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write!(f, "{}", item));
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref fields) => {
+                try!(write!(f, "{{"));
+                for (i, &(ref k, ref v)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, ","));
+                    }
+                    try!(write_string(f, k));
+                    try!(write!(f, ":{}", v));
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    try!(write!(f, "\""));
+    for c in s.chars() {
+        match c {
+            '"' => try!(write!(f, "\\\"")),
+            '\\' => try!(write!(f, "\\\\")),
+            '\n' => try!(write!(f, "\\n")),
+            '\r' => try!(write!(f, "\\r")),
+            '\t' => try!(write!(f, "\\t")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(write!(f, "{}", c)),
+        }
+    }
+    write!(f, "\"")
+}
+
+pub fn parse(s: &str) -> Result<Json, String> {
+    let mut p = Parser {
+        chars: s.chars().collect(),
+        pos: 0,
+    };
+    let value = try!(p.value());
+    p.skip_space();
+    if p.pos < p.chars.len() {
+        return Err(format!("trailing characters at {}", p.pos));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().map_or(false, |c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        for want in word.chars() {
+            if self.next() != Some(want) {
+                return Err(format!("expected {} at {}", word, self.pos - 1));
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_space();
+        match self.peek() {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if c == '-' || c.is_digit(10) => self.number(),
+            Some(c) => Err(format!("unexpected {} at {}", c, self.pos)),
+            None => Err("unexpected end".to_string()),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_digit(10) || "+-.eE".contains(c)) {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().cloned().collect();
+        text.parse().map(Json::Number).map_err(|_| format!("bad number {}", text))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        try!(self.expect("\""));
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => try!(self.unicode_escape()),
+                        Some(c) => c,
+                        None => return Err("unterminated string".to_string()),
+                    };
+                    s.push(c);
+                }
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+
+    /// The rest of a `\uXXXX`, and the second half of a surrogate pair.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = try!(self.hex4());
+        if high < 0xd800 || high > 0xdbff {
+            return Ok(::std::char::from_u32(high).unwrap_or('\u{fffd}'));
+        }
+        if self.peek() != Some('\\') {
+            return Ok('\u{fffd}');
+        }
+        try!(self.expect("\\u"));
+        let low = try!(self.hex4());
+        if low < 0xdc00 || low > 0xdfff {
+            return Err(format!("bad low surrogate at {}", self.pos - 4));
+        }
+        let c = 0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00);
+        Ok(::std::char::from_u32(c).unwrap_or('\u{fffd}'))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let d = try!(self.next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or(format!("bad \\u escape at {}", self.pos)));
+            n = n * 16 + d;
+        }
+        Ok(n)
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        try!(self.expect("["));
+        let mut items = Vec::new();
+        self.skip_space();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(try!(self.value()));
+            self.skip_space();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(format!("expected , or ] at {}", self.pos - 1)),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        try!(self.expect("{"));
+        let mut fields = Vec::new();
+        self.skip_space();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_space();
+            let key = try!(self.string());
+            self.skip_space();
+            try!(self.expect(":"));
+            fields.push((key, try!(self.value())));
+            self.skip_space();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(format!("expected , or }} at {}", self.pos - 1)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let value = object(vec![("id", Json::from(3)),
+                                ("ok", Json::from(true)),
+                                ("none", Json::Null),
+                                ("pi", Json::Number(3.25)),
+                                ("list", Json::Array(vec![Json::from(-1), Json::from("a")])),
+                                ("nested", object(vec![("empty", Json::Array(Vec::new()))]))]);
+        let text = value.to_string();
+        assert_eq!(text,
+                   "{\"id\":3,\"ok\":true,\"none\":null,\"pi\":3.25,\"list\":[-1,\"a\"],\
+                    \"nested\":{\"empty\":[]}}");
+        assert_eq!(parse(&text), Ok(value));
+        assert_eq!(parse(" { \"a\" : [ 1 , 2e3 ] } "),
+                   Ok(object(vec![("a", Json::Array(vec![Json::from(1), Json::from(2000)]))])));
+        assert!(parse("[1,]").is_err());
+        assert!(parse("{\"a\":1} x").is_err());
+    }
+
+    #[test]
+    fn escapes_strings() {
+        let s = "quote \" slash \\ tab \t line \n bell \u{7} é";
+        let text = Json::from(s).to_string();
+        assert_eq!(text, "\"quote \\\" slash \\\\ tab \\t line \\n bell \\u0007 é\"");
+        assert_eq!(parse(&text), Ok(Json::from(s)));
+        assert_eq!(parse("\"\\u00e9\\/\""), Ok(Json::from("é/")));
+    }
+
+    #[test]
+    fn reads_surrogate_pairs() {
+        assert_eq!(parse("\"\\ud83d\\ude00\""), Ok(Json::from("\u{1f600}")));
+        assert_eq!(parse("\"\\ud83dx\""), Ok(Json::from("\u{fffd}x")));
+        assert!(parse("\"\\ud83d\\u0041\"").is_err());
+        assert!(parse("\"\\ud83d\\ud83d\"").is_err());
+    }
+}
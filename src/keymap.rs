@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rustbox::Key;
+
+use mode::{Case, Command, Direction, Mode};
+use session;
+
+/// What a key sequence resolves to.
+#[derive(Debug, Clone)]
+pub enum Binding {
+    /// Run a command. Numeric arguments get multiplied by the count prefix.
+    Cmd(Command),
+    /// Behave like another key sequence in the same mode.
+    Keys(Vec<Key>),
+}
+
+pub type ModeMap = HashMap<Vec<Key>, Binding>;
+
+pub enum Lookup {
+    /// The keys are bound and no longer sequence starts with them.
+    Exact(Binding),
+    /// The keys start a longer sequence. If they are also bound themselves,
+    /// that binding is used when the timeout expires.
+    Partial(Option<Binding>),
+    None,
+}
+
+/// How deep `Binding::Keys` remaps can nest before we give up on them.
+const MAX_REMAP_DEPTH: usize = 16;
+
+pub struct Keymap {
+    normal: ModeMap,
+    insert: ModeMap,
+    command: ModeMap,
+    terminal: ModeMap,
+    replace: ModeMap,
+    /// What `<leader>` expands to when parsing key sequences.
+    pub leader: Key,
+    /// How long to wait for the next key of a partial sequence.
+    pub timeout: Duration,
+}
+
+impl Keymap {
+    pub fn new() -> Keymap {
+        let mut km = Keymap {
+            normal: HashMap::new(),
+            insert: HashMap::new(),
+            command: HashMap::new(),
+            terminal: HashMap::new(),
+            replace: HashMap::new(),
+            leader: Key::Char(','),
+            timeout: Duration::from_millis(1000),
+        };
+        km.defaults();
+        km
+    }
+
+    fn defaults(&mut self) {
+        let normal = vec![("<leader>q", Command::Quit),
+                          ("q", Command::Record(' ')),
+                          ("@", Command::Replay(' ', 1)),
+                          (".", Command::RepeatChange(1)),
+                          ("<leader>j", Command::AddCursor(1)),
+                          ("<leader>k", Command::AddCursor(-1)),
+                          ("<leader>n", Command::AddCursorNextMatch),
+                          ("<leader>a", Command::AddCursorAllMatches),
+                          ("<Esc>", Command::SingleCursor),
+                          ("m", Command::SetMark(' ')),
+                          ("`", Command::JumpMark(' ')),
+                          ("'", Command::JumpMarkLine(' ')),
+                          ("<C-o>", Command::JumpBack(1)),
+                          // Terminals send Ctrl-i as Tab.
+                          ("<Tab>", Command::JumpBack(-1)),
+                          ("<leader>ss", Command::SaveSession(session::DEFAULT.to_string())),
+                          ("<leader>sl", Command::LoadSession(session::DEFAULT.to_string())),
+                          ("<leader>b", Command::Build),
+                          ("<C-c>", Command::CancelJobs),
+                          (":", Command::ChangeMode(Mode::Command)),
+                          ("u", Command::Undo(1)),
+                          ("<leader>t", Command::OpenTerminal),
+                          ("%", Command::MatchBracket),
+                          ("gcc", Command::ToggleComment(1)),
+                          ("zo", Command::OpenFold),
+                          ("zc", Command::CloseFold),
+                          ("za", Command::ToggleFold),
+                          ("zF", Command::CreateFold(1)),
+                          ("K", Command::Hover),
+                          ("gd", Command::GoToDefinition),
+                          ("gr", Command::FindReferences),
+                          (">>", Command::Indent(1)),
+                          ("<lt><lt>", Command::Dedent(1)),
+                          ("==", Command::Reindent(1)),
+                          ("<C-r>", Command::Undo(-1)),
+                          ("<C-n>", Command::NextError(1)),
+                          ("<C-p>", Command::NextError(-1)),
+                          ("j", Command::MoveDown(1)),
+                          ("k", Command::MoveUp(1)),
+                          ("h", Command::MoveLeft(1)),
+                          ("l", Command::MoveRight(1)),
+                          ("x", Command::Delete(Direction::F)),
+                          ("<Down>", Command::MoveDown(1)),
+                          ("<Up>", Command::MoveUp(1)),
+                          ("<Left>", Command::MoveLeft(1)),
+                          ("<Right>", Command::MoveRight(1)),
+                          ("i", Command::ChangeMode(Mode::Insert)),
+                          ("b", Command::Scroll(1)),
+                          ("v", Command::Scroll(-1)),
+                          ("<leader>r", Command::RecompileSelf),
+                          ("a", Command::Append),
+                          ("A", Command::AppendLine),
+                          ("I", Command::InsertLineStart),
+                          ("o", Command::OpenLine(Direction::F)),
+                          ("O", Command::OpenLine(Direction::B)),
+                          ("R", Command::ChangeMode(Mode::Replace)),
+                          ("r", Command::ReplaceChar(1, ' ')),
+                          ("~", Command::ToggleCase(1)),
+                          ("gu", Command::CaseOperator(Case::Lower)),
+                          ("gU", Command::CaseOperator(Case::Upper)),
+                          ("g~", Command::CaseOperator(Case::Toggle)),
+                          ("J", Command::Join(1)),
+                          ("<C-a>", Command::Increment(1)),
+                          ("<C-x>", Command::Increment(-1)),
+                          ("<Space>", Command::Save),
+                          ("w", Command::FocusWindow(1)),
+                          ("<Home>", Command::LineStart),
+                          ("<End>", Command::LineEnd),
+                          ("$", Command::LineEnd),
+                          // Pages go by the window's height, which Scroll doesn't know.
+                          ("<PageUp>", Command::HalfPage(-2)),
+                          ("<PageDown>", Command::HalfPage(2)),
+                          ("<C-u>", Command::HalfPage(-1)),
+                          ("<C-d>", Command::HalfPage(1)),
+                          ("<Del>", Command::Delete(Direction::F))];
+        let insert = vec![("<Tab>", Command::TabComplete),
+                          ("<C-n>", Command::Complete(1)),
+                          ("<C-p>", Command::Complete(-1)),
+                          ("<Esc>", Command::ChangeMode(Mode::Normal)),
+                          ("<F1>", Command::ChangeMode(Mode::Normal)),
+                          ("<BS>", Command::Delete(Direction::B)),
+                          ("<Enter>", Command::NewLine),
+                          ("<Down>", Command::MoveDown(1)),
+                          ("<Up>", Command::MoveUp(1)),
+                          ("<Left>", Command::MoveLeft(1)),
+                          ("<Right>", Command::MoveRight(1)),
+                          ("<Home>", Command::LineStart),
+                          ("<End>", Command::LineEnd),
+                          ("<PageUp>", Command::HalfPage(-2)),
+                          ("<PageDown>", Command::HalfPage(2)),
+                          ("<C-u>", Command::HalfPage(-1)),
+                          ("<C-d>", Command::HalfPage(1)),
+                          ("<C-w>", Command::DeleteWord),
+                          ("<Del>", Command::Delete(Direction::F))];
+        let command = vec![("<Esc>", Command::ChangeMode(Mode::Normal)),
+                           ("<Enter>", Command::RunCmdline),
+                           ("<BS>", Command::CmdlineDelete)];
+        // Esc belongs to the programs in the terminal.
+        let terminal = vec![("<F1>", Command::ChangeMode(Mode::Normal))];
+        let replace = vec![("<Esc>", Command::ChangeMode(Mode::Normal)),
+                           ("<F1>", Command::ChangeMode(Mode::Normal)),
+                           ("<BS>", Command::MoveLeft(1)),
+                           ("<Enter>", Command::NewLine),
+                           ("<Down>", Command::MoveDown(1)),
+                           ("<Up>", Command::MoveUp(1)),
+                           ("<Left>", Command::MoveLeft(1)),
+                           ("<Right>", Command::MoveRight(1)),
+                           ("<Home>", Command::LineStart),
+                           ("<End>", Command::LineEnd)];
+        for (keys, cmd) in normal {
+            let keys = parse_keys(keys, self.leader).unwrap();
+            self.bind(Mode::Normal, keys, Binding::Cmd(cmd));
+        }
+        for (keys, cmd) in insert {
+            let keys = parse_keys(keys, self.leader).unwrap();
+            self.bind(Mode::Insert, keys, Binding::Cmd(cmd));
+        }
+        for (keys, cmd) in command {
+            let keys = parse_keys(keys, self.leader).unwrap();
+            self.bind(Mode::Command, keys, Binding::Cmd(cmd));
+        }
+        for (keys, cmd) in terminal {
+            let keys = parse_keys(keys, self.leader).unwrap();
+            self.bind(Mode::Terminal, keys, Binding::Cmd(cmd));
+        }
+        for (keys, cmd) in replace {
+            let keys = parse_keys(keys, self.leader).unwrap();
+            self.bind(Mode::Replace, keys, Binding::Cmd(cmd));
+        }
+    }
+
+    fn table(&self, mode: Mode) -> &ModeMap {
+        match mode {
+            Mode::Normal => &self.normal,
+            Mode::Insert => &self.insert,
+            Mode::Command => &self.command,
+            Mode::Terminal => &self.terminal,
+            Mode::Replace => &self.replace,
+        }
+    }
+
+    fn table_mut(&mut self, mode: Mode) -> &mut ModeMap {
+        match mode {
+            Mode::Normal => &mut self.normal,
+            Mode::Insert => &mut self.insert,
+            Mode::Command => &mut self.command,
+            Mode::Terminal => &mut self.terminal,
+            Mode::Replace => &mut self.replace,
+        }
+    }
+
+    pub fn bind(&mut self, mode: Mode, keys: Vec<Key>, b: Binding) {
+        self.table_mut(mode).insert(keys, b);
+    }
+
+    pub fn unbind(&mut self, mode: Mode, keys: &[Key]) {
+        self.table_mut(mode).remove(keys);
+    }
+
+    pub fn lookup(&self, mode: Mode, keys: &[Key]) -> Lookup {
+        let table = self.table(mode);
+        let exact = table.get(keys).cloned();
+        let longer = table.keys().any(|k| k.len() > keys.len() && k.starts_with(keys));
+        match (exact, longer) {
+            (e, true) => Lookup::Partial(e),
+            (Some(b), false) => Lookup::Exact(b),
+            (None, false) => Lookup::None,
+        }
+    }
+
+    /// Follow `Binding::Keys` remaps until reaching a command.
+    pub fn resolve(&self, mode: Mode, b: Binding) -> Option<Command> {
+        let mut b = b;
+        for _ in 0..MAX_REMAP_DEPTH {
+            b = match b {
+                Binding::Cmd(cmd) => return Some(cmd),
+                Binding::Keys(keys) => {
+                    match self.table(mode).get(&keys) {
+                        Some(next) => next.clone(),
+                        None => return None,
+                    }
+                }
+            };
+        }
+        None
+    }
+}
+
+/// Scale the numeric argument of a bound command by the count prefix.
+pub fn with_count(cmd: Command, count: u32) -> Command {
+    let n = count as i32;
+    match cmd {
+        Command::MoveLeft(x) => Command::MoveLeft(x * n),
+        Command::MoveRight(x) => Command::MoveRight(x * n),
+        Command::MoveUp(x) => Command::MoveUp(x * n),
+        Command::MoveDown(x) => Command::MoveDown(x * n),
+        Command::Scroll(x) => Command::Scroll(x * n),
+        Command::HalfPage(x) => Command::HalfPage(x * n),
+        Command::AddCursor(x) => Command::AddCursor(x * n),
+        Command::JumpBack(x) => Command::JumpBack(x * n),
+        Command::NextError(x) => Command::NextError(x * n),
+        Command::Undo(x) => Command::Undo(x * n),
+        Command::Complete(x) => Command::Complete(x * n),
+        Command::Indent(x) => Command::Indent(x * n),
+        Command::Dedent(x) => Command::Dedent(x * n),
+        Command::Reindent(x) => Command::Reindent(x * n),
+        Command::CreateFold(x) => Command::CreateFold(x * n),
+        Command::ToggleComment(x) => Command::ToggleComment(x * n),
+        Command::ToggleCase(x) => Command::ToggleCase(x * n),
+        Command::Join(x) => Command::Join(x * n),
+        Command::ReplaceChar(x, c) => Command::ReplaceChar(x * n, c),
+        Command::Increment(x) => Command::Increment(x * n),
+        Command::Replay(r, x) => Command::Replay(r, x * count),
+        Command::RepeatChange(x) => Command::RepeatChange(x * count),
+        Command::FocusWindow(_) => Command::FocusWindow(count),
+        c => c,
+    }
+}
+
+pub fn parse_mode(s: &str) -> Result<Mode, String> {
+    match s {
+        "normal" | "n" => Ok(Mode::Normal),
+        "insert" | "i" => Ok(Mode::Insert),
+        "command" | "c" => Ok(Mode::Command),
+        "terminal" | "t" => Ok(Mode::Terminal),
+        "replace" | "r" => Ok(Mode::Replace),
+        _ => Err(format!("unknown mode {}", s)),
+    }
+}
+
+/// Parse a key sequence like `<leader>w`, `<C-x>s` or `<Esc>`.
+/// `<lt>` is a literal `<`.
+pub fn parse_keys(s: &str, leader: Key) -> Result<Vec<Key>, String> {
+    let mut keys = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '<' {
+            keys.push(Key::Char(c));
+            continue;
+        }
+        let name: String = chars.by_ref().take_while(|&c| c != '>').collect();
+        keys.push(try!(parse_key_name(&name, leader)));
+    }
+    if keys.is_empty() {
+        return Err("empty key sequence".to_string());
+    }
+    Ok(keys)
+}
+
+fn parse_key_name(name: &str, leader: Key) -> Result<Key, String> {
+    let lower = name.to_lowercase();
+    let key = match lower.as_str() {
+        "leader" => leader,
+        "lt" => Key::Char('<'),
+        "space" => Key::Char(' '),
+        "esc" => Key::Esc,
+        "cr" | "enter" => Key::Enter,
+        "tab" => Key::Tab,
+        "bs" | "backspace" => Key::Backspace,
+        "del" | "delete" => Key::Delete,
+        "insert" => Key::Insert,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        _ => {
+            let cs: Vec<char> = lower.chars().collect();
+            if cs.len() == 3 && cs[0] == 'c' && cs[1] == '-' {
+                Key::Ctrl(cs[2])
+            } else if cs.len() > 1 && cs[0] == 'f' {
+                let n: String = cs[1..].iter().cloned().collect();
+                Key::F(try!(n.parse().map_err(|_| format!("unknown key <{}>", name))))
+            } else {
+                return Err(format!("unknown key <{}>", name));
+            }
+        }
+    };
+    Ok(key)
+}
+
+/// Parse a command name and its arguments as written in the config file.
+pub fn parse_command(name: &str, args: &[&str]) -> Result<Command, String> {
+    let num = |default: i32| -> Result<i32, String> {
+        match args.first() {
+            Some(a) => a.parse().map_err(|_| format!("bad number {}", a)),
+            None => Ok(default),
+        }
+    };
+    let cmd = match name {
+        "quit" => Command::Quit,
+        "move-left" => Command::MoveLeft(try!(num(1))),
+        "move-right" => Command::MoveRight(try!(num(1))),
+        "move-up" => Command::MoveUp(try!(num(1))),
+        "move-down" => Command::MoveDown(try!(num(1))),
+        "line-start" => Command::LineStart,
+        "line-end" => Command::LineEnd,
+        "scroll" => Command::Scroll(try!(num(1))),
+        "half-page" => Command::HalfPage(try!(num(1))),
+        "insert" => {
+            match args.first().and_then(|a| a.chars().next()) {
+                Some(c) => Command::Insert(c),
+                None => return Err("insert needs a character".to_string()),
+            }
+        }
+        "delete-forward" => Command::Delete(Direction::F),
+        "delete-backward" => Command::Delete(Direction::B),
+        "delete-word" => Command::DeleteWord,
+        "newline" => Command::NewLine,
+        "normal-mode" => Command::ChangeMode(Mode::Normal),
+        "insert-mode" => Command::ChangeMode(Mode::Insert),
+        "replace-mode" => Command::ChangeMode(Mode::Replace),
+        "append" => Command::Append,
+        "append-line" => Command::AppendLine,
+        "insert-line-start" => Command::InsertLineStart,
+        "open-line-below" => Command::OpenLine(Direction::F),
+        "open-line-above" => Command::OpenLine(Direction::B),
+        "replace-char" => Command::ReplaceChar(try!(num(1)), ' '),
+        "toggle-case" => Command::ToggleCase(try!(num(1))),
+        "lowercase" => Command::CaseOperator(Case::Lower),
+        "uppercase" => Command::CaseOperator(Case::Upper),
+        "switch-case" => Command::CaseOperator(Case::Toggle),
+        "join-lines" => Command::Join(try!(num(1))),
+        "increment" => Command::Increment(try!(num(1))),
+        "decrement" => Command::Increment(-try!(num(1))),
+        "command-mode" => Command::ChangeMode(Mode::Command),
+        "run-command-line" => Command::RunCmdline,
+        "terminal" => Command::OpenTerminal,
+        "undo" => Command::Undo(try!(num(1))),
+        "redo" => Command::Undo(-try!(num(1))),
+        "recompile" => Command::RecompileSelf,
+        "save" => Command::Save,
+        "focus-window" => Command::FocusWindow(1),
+        "record-macro" => Command::Record(' '),
+        "replay-macro" => Command::Replay(' ', 1),
+        "repeat-change" => Command::RepeatChange(1),
+        "add-cursor" => Command::AddCursor(try!(num(1))),
+        "add-cursor-next-match" => Command::AddCursorNextMatch,
+        "add-cursor-all-matches" => Command::AddCursorAllMatches,
+        "single-cursor" => Command::SingleCursor,
+        "set-mark" => Command::SetMark(' '),
+        "jump-mark" => Command::JumpMark(' '),
+        "jump-mark-line" => Command::JumpMarkLine(' '),
+        "jump-back" => Command::JumpBack(try!(num(1))),
+        "jump-forward" => Command::JumpBack(-try!(num(1))),
+        "save-session" => Command::SaveSession(session_name(args)),
+        "load-session" => Command::LoadSession(session_name(args)),
+        "build" => Command::Build,
+        "cancel-jobs" => Command::CancelJobs,
+        "next-error" => Command::NextError(try!(num(1))),
+        "previous-error" => Command::NextError(-try!(num(1))),
+        "match-bracket" => Command::MatchBracket,
+        "toggle-comment" => Command::ToggleComment(try!(num(1))),
+        "open-fold" => Command::OpenFold,
+        "close-fold" => Command::CloseFold,
+        "toggle-fold" => Command::ToggleFold,
+        "create-fold" => Command::CreateFold(try!(num(1))),
+        "hover" => Command::Hover,
+        "go-to-definition" => Command::GoToDefinition,
+        "find-references" => Command::FindReferences,
+        "complete" => Command::Complete(try!(num(1))),
+        "complete-previous" => Command::Complete(-try!(num(1))),
+        "tab-complete" => Command::TabComplete,
+        "indent" => Command::Indent(try!(num(1))),
+        "dedent" => Command::Dedent(try!(num(1))),
+        "reindent" => Command::Reindent(try!(num(1))),
+        "nop" => Command::Unknown,
+        _ => return Err(format!("unknown command {}", name)),
+    };
+    Ok(cmd)
+}
+
+fn session_name(args: &[&str]) -> String {
+    args.first().unwrap_or(&session::DEFAULT).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_keys() {
+        let leader = Key::Char(',');
+        assert_eq!(parse_keys("gU", leader).unwrap(), vec![Key::Char('g'), Key::Char('U')]);
+        assert_eq!(parse_keys("<leader>w", leader).unwrap(), vec![Key::Char(','), Key::Char('w')]);
+        assert_eq!(parse_keys("<C-x>s<Esc>", leader).unwrap(),
+                   vec![Key::Ctrl('x'), Key::Char('s'), Key::Esc]);
+        assert_eq!(parse_keys("<lt><SPACE><f12>", leader).unwrap(),
+                   vec![Key::Char('<'), Key::Char(' '), Key::F(12)]);
+        assert!(parse_keys("", leader).is_err());
+        assert!(parse_keys("<nope>", leader).is_err());
+        assert!(parse_keys("<C-xy>", leader).is_err());
+    }
+}
@@ -0,0 +1,713 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::process;
+use std::process::{Child, ChildStdin, Stdio};
+use std::str;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+use buffer::Edit;
+use errors::{CrbError, CrbResult};
+use json;
+use json::Json;
+use logging;
+
+/// What a request to a server was for, to know what to do with the answer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request {
+    Initialize,
+    Hover,
+    Definition,
+    References,
+    Completion,
+}
+
+/// A place in a file, as a server gives it: 0-based, with the column in
+/// UTF-16 code units.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub path: String,
+    pub line: i32,
+    pub col: i32,
+}
+
+/// A problem a server found, with columns in UTF-16 code units.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub start: (i32, i32),
+    pub end: (i32, i32),
+    /// 1 is an error, 2 a warning, 3 and 4 are information and hints.
+    pub severity: i32,
+    pub message: String,
+}
+
+/// Something a server said that the editor has to act on.
+#[derive(Debug)]
+pub enum Reply {
+    /// All the diagnostics of a file, replacing the ones before.
+    Diagnostics(String, Vec<Diagnostic>),
+    Hover(String),
+    Definition(Vec<Location>),
+    References(Vec<Location>),
+    /// Text that could go at the cursor.
+    Completion(Vec<String>),
+    Error(String),
+}
+
+/// Something held back until the server has answered `initialize`.
+enum Held {
+    Message(Json),
+    /// A change to a document, by URI and version, which can't be put into
+    /// words until the server says how it wants them.
+    Change(String, i64, Vec<Edit>, String),
+}
+
+/// A language server running as a child, talking JSON-RPC over its stdin
+/// and stdout. Its output is read on a thread of its own.
+struct Server {
+    child: Child,
+    stdin: ChildStdin,
+    rx: Receiver<Json>,
+    next_id: i64,
+    /// What each unanswered request was for.
+    pending: HashMap<i64, Request>,
+    /// What's held back until the server has answered `initialize`.
+    queued: Option<Vec<Held>>,
+    /// How the server wants changes: 0 not at all, 1 the whole text, 2
+    /// just the edits.
+    sync: i64,
+    /// Version of each open document, by URI.
+    versions: HashMap<String, i64>,
+}
+
+impl Server {
+    fn start(cmd: &str) -> CrbResult<Server> {
+        let mut child = try!(process::Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| CrbError::new(&format!("error running {}: {}", cmd, e))));
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut r = BufReader::new(stdout);
+            while let Some(body) = read_message(&mut r) {
+                match json::parse(&body) {
+                    Ok(msg) => {
+                        if tx.send(msg).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => logging::debug(&format!("bad message from server: {}", e)),
+                }
+            }
+        });
+        let mut server = Server {
+            child: child,
+            stdin: stdin,
+            rx: rx,
+            next_id: 1,
+            pending: HashMap::new(),
+            queued: None,
+            sync: 0,
+            versions: HashMap::new(),
+        };
+        let root = uri(&try!(env::current_dir()
+                .map_err(|e| CrbError::new(&format!("no current directory: {}", e))))
+            .to_string_lossy());
+        let hover = json::object(vec![("contentFormat", Json::Array(vec!["plaintext".into()]))]);
+        let sync = json::object(vec![("didSave", true.into())]);
+        let capabilities =
+            json::object(vec![("textDocument",
+                               json::object(vec![("hover", hover),
+                                                 ("publishDiagnostics", json::object(vec![])),
+                                                 ("synchronization", sync)]))]);
+        let params = json::object(vec![("processId", (process::id() as i64).into()),
+                                       ("rootUri", root.into()),
+                                       ("capabilities", capabilities)]);
+        try!(server.request("initialize", params, Request::Initialize));
+        server.queued = Some(Vec::new());
+        Ok(server)
+    }
+
+    fn request(&mut self, method: &str, params: Json, what: Request) -> CrbResult<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, what);
+        self.send(json::object(vec![("jsonrpc", "2.0".into()),
+                                    ("id", id.into()),
+                                    ("method", method.into()),
+                                    ("params", params)]))
+    }
+
+    fn notify(&mut self, method: &str, params: Json) -> CrbResult<()> {
+        self.send(json::object(vec![("jsonrpc", "2.0".into()),
+                                    ("method", method.into()),
+                                    ("params", params)]))
+    }
+
+    fn send(&mut self, msg: Json) -> CrbResult<()> {
+        if let Some(ref mut queued) = self.queued {
+            queued.push(Held::Message(msg));
+            return Ok(());
+        }
+        let body = msg.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .and_then(|_| self.stdin.flush())
+            .map_err(|e| CrbError::new(&format!("error writing to language server: {}", e)))
+    }
+
+    fn open(&mut self, path: &str, filetype: &str, text: &str) -> CrbResult<()> {
+        let uri = uri(&absolute(path));
+        // A file opened again, like after going to another one and back,
+        // starts over.
+        if self.versions.remove(&uri).is_some() {
+            try!(self.notify("textDocument/didClose",
+                             json::object(vec![("textDocument",
+                                                json::object(vec![("uri", uri.clone().into())]))])));
+        }
+        self.versions.insert(uri.clone(), 0);
+        let doc = json::object(vec![("uri", uri.into()),
+                                    ("languageId", filetype.into()),
+                                    ("version", 0.into()),
+                                    ("text", text.into())]);
+        self.notify("textDocument/didOpen", json::object(vec![("textDocument", doc)]))
+    }
+
+    fn change(&mut self, path: &str, edits: &[Edit], text: &str) -> CrbResult<()> {
+        let uri = uri(&absolute(path));
+        let version = match self.versions.get_mut(&uri) {
+            Some(v) => {
+                *v += 1;
+                *v
+            }
+            None => return Ok(()),
+        };
+        if let Some(ref mut queued) = self.queued {
+            queued.push(Held::Change(uri, version, edits.to_vec(), text.to_string()));
+            return Ok(());
+        }
+        self.send_change(uri, version, edits, text)
+    }
+
+    fn send_change(&mut self,
+                   uri: String,
+                   version: i64,
+                   edits: &[Edit],
+                   text: &str)
+                   -> CrbResult<()> {
+        let changes = match self.sync {
+            0 => return Ok(()),
+            1 => vec![json::object(vec![("text", text.into())])],
+            _ => {
+                edits.iter()
+                    .map(|e| {
+                        json::object(vec![("range", range(e.start, e.end)),
+                                          ("text", e.text.clone().into())])
+                    })
+                    .collect()
+            }
+        };
+        let doc = json::object(vec![("uri", uri.into()), ("version", version.into())]);
+        self.notify("textDocument/didChange",
+                    json::object(vec![("textDocument", doc),
+                                      ("contentChanges", Json::Array(changes))]))
+    }
+
+    /// Deal with a message from the server, adding what the editor needs
+    /// to know to `replies`.
+    fn handle(&mut self, msg: Json, replies: &mut Vec<Reply>) -> CrbResult<()> {
+        let id = msg.get("id").and_then(|i| i.as_i64());
+        match (msg.get("method").and_then(|m| m.as_str()), id) {
+            (Some(method), Some(id)) => self.answer(method, id, msg.get("params")),
+            (Some("textDocument/publishDiagnostics"), None) => {
+                let params = msg.get("params");
+                let path = params.and_then(|p| p.get("uri"))
+                    .and_then(|u| u.as_str())
+                    .and_then(path_of);
+                let diags = params.and_then(|p| p.get("diagnostics"))
+                    .and_then(|d| d.as_array())
+                    .map(|ds| ds.iter().filter_map(diagnostic).collect());
+                if let (Some(path), Some(diags)) = (path, diags) {
+                    replies.push(Reply::Diagnostics(path, diags));
+                }
+                Ok(())
+            }
+            (Some(_), None) => Ok(()),
+            (None, Some(id)) => {
+                let what = match self.pending.remove(&id) {
+                    Some(w) => w,
+                    None => return Ok(()),
+                };
+                if let Some(err) = msg.get("error") {
+                    let text = err.get("message").and_then(|m| m.as_str()).unwrap_or("error");
+                    replies.push(Reply::Error(format!("language server: {}", text)));
+                    return Ok(());
+                }
+                let result = msg.get("result").unwrap_or(&Json::Null);
+                match what {
+                    Request::Initialize => return self.initialized(result),
+                    Request::Hover => replies.push(Reply::Hover(hover_text(result))),
+                    Request::Definition => replies.push(Reply::Definition(locations(result))),
+                    Request::References => replies.push(Reply::References(locations(result))),
+                    Request::Completion => replies.push(Reply::Completion(completions(result))),
+                }
+                Ok(())
+            }
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Answer a request from the server. Nothing is configurable, so
+    /// everything gets a null.
+    fn answer(&mut self, method: &str, id: i64, params: Option<&Json>) -> CrbResult<()> {
+        let result = match method {
+            "workspace/configuration" => {
+                let n = params.and_then(|p| p.get("items"))
+                    .and_then(|i| i.as_array())
+                    .map_or(0, |i| i.len());
+                Json::Array(vec![Json::Null; n])
+            }
+            _ => Json::Null,
+        };
+        self.send(json::object(vec![("jsonrpc", "2.0".into()),
+                                    ("id", id.into()),
+                                    ("result", result)]))
+    }
+
+    /// The server is ready: send what was held back.
+    fn initialized(&mut self, result: &Json) -> CrbResult<()> {
+        let sync = result.get("capabilities").and_then(|c| c.get("textDocumentSync"));
+        let kind = match sync {
+            Some(&Json::Number(n)) => n as i64,
+            Some(s) => s.get("change").and_then(|c| c.as_i64()).unwrap_or(0),
+            None => 0,
+        };
+        self.sync = kind;
+        let queued = self.queued.take().unwrap_or(Vec::new());
+        try!(self.notify("initialized", json::object(vec![])));
+        for held in queued {
+            try!(match held {
+                Held::Message(msg) => self.send(msg),
+                Held::Change(uri, version, edits, text) => {
+                    self.send_change(uri, version, &edits, &text)
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The language servers, one per filetype, started when the first file of
+/// their filetype is opened. A server that exits isn't started again.
+pub struct Lsp {
+    servers: HashMap<String, Server>,
+    dead: HashSet<String>,
+}
+
+impl Lsp {
+    pub fn new() -> Lsp {
+        Lsp {
+            servers: HashMap::new(),
+            dead: HashSet::new(),
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        !self.servers.is_empty()
+    }
+
+    /// Tell the filetype's server about a file, starting it with `cmd` if
+    /// it isn't running yet.
+    pub fn open(&mut self, cmd: &str, filetype: &str, path: &str, text: &str) -> CrbResult<()> {
+        if self.dead.contains(filetype) {
+            return Ok(());
+        }
+        if !self.servers.contains_key(filetype) {
+            let server = try!(Server::start(cmd).map_err(|e| {
+                self.dead.insert(filetype.to_string());
+                e
+            }));
+            self.servers.insert(filetype.to_string(), server);
+        }
+        self.servers.get_mut(filetype).unwrap().open(path, filetype, text)
+    }
+
+    /// Tell a server about edits to an open file. `text` is what the file
+    /// looks like after them, for servers that want all of it.
+    pub fn change(&mut self, filetype: &str, path: &str, edits: &[Edit], text: &str) -> CrbResult<()> {
+        match self.servers.get_mut(filetype) {
+            Some(s) => s.change(path, edits, text),
+            None => Ok(()),
+        }
+    }
+
+    pub fn saved(&mut self, filetype: &str, path: &str) -> CrbResult<()> {
+        match self.servers.get_mut(filetype) {
+            Some(s) => {
+                let doc = json::object(vec![("uri", uri(&absolute(path)).into())]);
+                s.notify("textDocument/didSave", json::object(vec![("textDocument", doc)]))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Ask about a position in a file. The answer comes back from `poll`.
+    pub fn request(&mut self,
+                   what: Request,
+                   filetype: &str,
+                   path: &str,
+                   line: i32,
+                   col: i32)
+                   -> CrbResult<()> {
+        let server = try!(self.servers
+            .get_mut(filetype)
+            .ok_or(CrbError::new(&format!("no language server for {}", filetype))));
+        let method = match what {
+            Request::Hover => "textDocument/hover",
+            Request::Definition => "textDocument/definition",
+            Request::References => "textDocument/references",
+            Request::Completion => "textDocument/completion",
+            Request::Initialize => return Err(CrbError::new("already initialized")),
+        };
+        let mut params = vec![("textDocument", json::object(vec![("uri", uri(&absolute(path)).into())])),
+                              ("position", position((line, col)))];
+        if what == Request::References {
+            params.push(("context", json::object(vec![("includeDeclaration", true.into())])));
+        }
+        server.request(method, json::object(params), what)
+    }
+
+    /// Everything the servers said since the last poll. Doesn't block.
+    pub fn poll(&mut self) -> Vec<Reply> {
+        let mut replies = Vec::new();
+        let mut exited = Vec::new();
+        for (filetype, server) in self.servers.iter_mut() {
+            loop {
+                match server.rx.try_recv() {
+                    Ok(msg) => {
+                        if let Err(e) = server.handle(msg, &mut replies) {
+                            replies.push(Reply::Error(format!("{}", e)));
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        exited.push(filetype.clone());
+                        break;
+                    }
+                }
+            }
+        }
+        for filetype in exited {
+            self.servers.remove(&filetype);
+            replies.push(Reply::Error(format!("the {} language server exited", filetype)));
+            self.dead.insert(filetype);
+        }
+        replies
+    }
+}
+
+/// Read one `Content-Length` framed message. None once the server's gone.
+fn read_message<R: BufRead>(r: &mut R) -> Option<String> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        match r.read_line(&mut header) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        if parts.next().map(|n| n.trim().to_lowercase()) == Some("content-length".to_string()) {
+            len = parts.next().and_then(|n| n.trim().parse().ok());
+        }
+    }
+    let mut body = match len {
+        Some(n) => vec![0; n],
+        None => return None,
+    };
+    match r.read_exact(&mut body) {
+        Ok(_) => Some(String::from_utf8_lossy(&body).into_owned()),
+        Err(_) => None,
+    }
+}
+
+fn position(pos: (i32, i32)) -> Json {
+    json::object(vec![("line", pos.0.into()), ("character", pos.1.into())])
+}
+
+fn range(start: (i32, i32), end: (i32, i32)) -> Json {
+    json::object(vec![("start", position(start)), ("end", position(end))])
+}
+
+fn parse_position(p: &Json) -> Option<(i32, i32)> {
+    match (p.get("line").and_then(|l| l.as_i64()), p.get("character").and_then(|c| c.as_i64())) {
+        (Some(l), Some(c)) => Some((l as i32, c as i32)),
+        _ => None,
+    }
+}
+
+fn diagnostic(d: &Json) -> Option<Diagnostic> {
+    let range = d.get("range");
+    let start = range.and_then(|r| r.get("start")).and_then(parse_position);
+    let end = range.and_then(|r| r.get("end")).and_then(parse_position);
+    match (start, end) {
+        (Some(start), Some(end)) => {
+            Some(Diagnostic {
+                start: start,
+                end: end,
+                severity: d.get("severity").and_then(|s| s.as_i64()).unwrap_or(1) as i32,
+                message: d.get("message").and_then(|m| m.as_str()).unwrap_or("").to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Locations from a `Location`, a list of them, or a list of
+/// `LocationLink`s.
+fn locations(result: &Json) -> Vec<Location> {
+    match *result {
+        Json::Array(ref items) => items.iter().filter_map(location).collect(),
+        Json::Null => Vec::new(),
+        ref l => location(l).into_iter().collect(),
+    }
+}
+
+fn location(l: &Json) -> Option<Location> {
+    let path = l.get("uri").or(l.get("targetUri")).and_then(|u| u.as_str()).and_then(path_of);
+    let start = l.get("range")
+        .or(l.get("targetSelectionRange"))
+        .and_then(|r| r.get("start"))
+        .and_then(parse_position);
+    match (path, start) {
+        (Some(path), Some((line, col))) => {
+            Some(Location {
+                path: path,
+                line: line,
+                col: col,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// What completion items insert, from a list of them or a
+/// `CompletionList`.
+fn completions(result: &Json) -> Vec<String> {
+    let items = match result.get("items") {
+        Some(items) => items,
+        None => result,
+    };
+    let text = |item: &Json| {
+        item.get("textEdit")
+            .and_then(|e| e.get("newText"))
+            .or(item.get("insertText"))
+            .or(item.get("label"))
+            .and_then(|t| t.as_str())
+            .map(|t| t.to_string())
+    };
+    items.as_array().map_or(Vec::new(), |items| items.iter().filter_map(text).collect())
+}
+
+/// The text of a hover, which can be a string, markup, or a list of them.
+fn hover_text(result: &Json) -> String {
+    fn text(c: &Json) -> String {
+        match *c {
+            Json::String(ref s) => s.clone(),
+            Json::Array(ref items) => items.iter().map(text).collect::<Vec<_>>().join("\n"),
+            _ => c.get("value").and_then(|v| v.as_str()).unwrap_or("").to_string(),
+        }
+    }
+    result.get("contents").map(text).unwrap_or(String::new()).trim().to_string()
+}
+
+/// A path made absolute, the way servers give them back.
+pub fn absolute(path: &str) -> String {
+    match env::current_dir() {
+        Ok(dir) => dir.join(path).to_string_lossy().into_owned(),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// A path relative to the current directory, if it's under it.
+pub fn relative(path: &str) -> String {
+    let dir = match env::current_dir() {
+        Ok(dir) => dir.to_string_lossy().into_owned() + "/",
+        Err(_) => return path.to_string(),
+    };
+    match path.starts_with(&dir) {
+        true => path[dir.len()..].to_string(),
+        false => path.to_string(),
+    }
+}
+
+fn uri(path: &str) -> String {
+    let mut uri = "file://".to_string();
+    for b in path.bytes() {
+        match b {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(b as char)
+            }
+            b => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+fn path_of(uri: &str) -> Option<String> {
+    if !uri.starts_with("file://") {
+        return None;
+    }
+    let bytes = uri[7..].as_bytes();
+    let mut path = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok())
+            }
+            _ => None,
+        };
+        match hex {
+            Some(b) => {
+                path.push(b);
+                i += 3;
+            }
+            None => {
+                path.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(path).ok()
+}
+
+/// The UTF-16 column of a character offset in a line.
+pub fn utf16_col(line: &str, offset: i32) -> i32 {
+    line.chars().take(offset as usize).map(|c| c.len_utf16() as i32).sum()
+}
+
+/// The character offset of a UTF-16 column in a line.
+pub fn char_col(line: &str, col: i32) -> i32 {
+    let mut units = 0;
+    for (i, c) in line.chars().enumerate() {
+        if units >= col {
+            return i as i32;
+        }
+        units += c.len_utf16() as i32;
+    }
+    line.chars().count() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// A stand-in server that says its piece, whatever it's sent.
+    fn scripted(messages: &[String]) -> String {
+        let mut script = String::new();
+        for m in messages {
+            script.push_str(&format!("printf 'Content-Length: {}\\r\\n\\r\\n%s' '{}'; ", m.len(), m));
+        }
+        script + "cat > /dev/null"
+    }
+
+    fn poll_until<F: Fn(&[Reply]) -> bool>(lsp: &mut Lsp, done: F) -> Vec<Reply> {
+        let start = Instant::now();
+        let mut replies = Vec::new();
+        while !done(&replies) && start.elapsed() < Duration::from_secs(5) {
+            replies.extend(lsp.poll());
+            thread::sleep(Duration::from_millis(10));
+        }
+        replies
+    }
+
+    #[test]
+    fn talks_to_a_server() {
+        let path = absolute("src/main.rs");
+        let messages = vec![r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":{"textDocumentSync":2}}}"#
+                                .to_string(),
+                            format!(r#"{{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics","params":{{"uri":"{}","diagnostics":[{{"range":{{"start":{{"line":2,"character":4}},"end":{{"line":2,"character":6}}}},"severity":2,"message":"unused"}}]}}}}"#,
+                                    uri(&path)),
+                            r#"{"jsonrpc":"2.0","id":2,"result":{"contents":{"kind":"plaintext","value":"fn main()"}}}"#
+                                .to_string()];
+        let mut lsp = Lsp::new();
+        lsp.open(&scripted(&messages), "rust", "src/main.rs", "fn main() {}").unwrap();
+        lsp.request(Request::Hover, "rust", "src/main.rs", 0, 3).unwrap();
+        let replies = poll_until(&mut lsp, |r| r.len() >= 2);
+        match replies[0] {
+            Reply::Diagnostics(ref p, ref d) => {
+                assert_eq!(*p, path);
+                assert_eq!(d[0].start, (2, 4));
+                assert_eq!(d[0].severity, 2);
+                assert_eq!(d[0].message, "unused");
+            }
+            ref r => panic!("got {:?}", r),
+        }
+        match replies[1] {
+            Reply::Hover(ref text) => assert_eq!(text, "fn main()"),
+            ref r => panic!("got {:?}", r),
+        }
+    }
+
+    /// What a server that got an edit before answering `initialize` is sent
+    /// for it, once it says how it wants changes.
+    fn early_change(sync: &str) -> String {
+        let out = env::temp_dir().join(format!("crb-lsp-{}", process::id()));
+        let mut server = Server::start(&format!("cat > '{}'", out.display())).unwrap();
+        server.open("a.rs", "rust", "fn main() {}").unwrap();
+        let edit = Edit {
+            start: (0, 11),
+            end: (0, 11),
+            text: " ".to_string(),
+        };
+        server.change("a.rs", &[edit], "fn main() { }").unwrap();
+        server.initialized(&json::parse(sync).unwrap()).unwrap();
+        server.notify("done", json::object(vec![])).unwrap();
+        let start = Instant::now();
+        let mut sent = String::new();
+        while !sent.contains("done") && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+            sent = ::std::fs::read_to_string(&out).unwrap_or(String::new());
+        }
+        let _ = ::std::fs::remove_file(&out);
+        sent
+    }
+
+    #[test]
+    fn holds_changes_until_sync_is_known() {
+        let full = early_change(r#"{"capabilities":{"textDocumentSync":1}}"#);
+        assert!(full.contains("didChange"));
+        assert!(!full.contains("range"));
+        let ranged = early_change(r#"{"capabilities":{"textDocumentSync":{"change":2}}}"#);
+        assert!(ranged.contains("range"));
+        let none = early_change(r#"{"capabilities":{}}"#);
+        assert!(none.contains("done"));
+        assert!(!none.contains("didChange"));
+    }
+
+    #[test]
+    fn columns_count_utf16() {
+        assert_eq!(utf16_col("a😀b", 2), 3);
+        assert_eq!(char_col("a😀b", 3), 2);
+        assert_eq!(char_col("ab", 10), 2);
+        assert_eq!(path_of(&uri("/tmp/a b%.rs")), Some("/tmp/a b%.rs".to_string()));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+
+use mode::Command;
+
+/// Register name that means "the last macro played".
+pub const LAST: char = '@';
+
+/// Recorded command sequences, by register.
+pub struct Macros {
+    registers: HashMap<char, Vec<Command>>,
+    /// Register being recorded into, and what's been recorded so far.
+    recording: Option<(char, Vec<Command>)>,
+    last: Option<char>,
+}
+
+impl Macros {
+    pub fn new() -> Macros {
+        Macros {
+            registers: HashMap::new(),
+            recording: None,
+            last: None,
+        }
+    }
+
+    pub fn recording(&self) -> Option<char> {
+        self.recording.as_ref().map(|&(reg, _)| reg)
+    }
+
+    pub fn start(&mut self, reg: char) {
+        self.recording = Some((reg, Vec::new()));
+    }
+
+    pub fn stop(&mut self) {
+        if let Some((reg, cmds)) = self.recording.take() {
+            self.registers.insert(reg, cmds);
+        }
+    }
+
+    /// Add a command to the macro being recorded, if any.
+    pub fn record(&mut self, cmd: &Command) {
+        if let Some((_, ref mut cmds)) = self.recording {
+            if recordable(cmd) {
+                cmds.push(cmd.clone());
+            }
+        }
+    }
+
+    /// Commands in a register, resolving `@` to the last macro played.
+    /// Remembers the register as the last one played.
+    pub fn play(&mut self, reg: char) -> Option<Vec<Command>> {
+        let reg = match reg {
+            LAST => {
+                match self.last {
+                    Some(r) => r,
+                    None => return None,
+                }
+            }
+            r => r,
+        };
+        self.last = Some(reg);
+        self.registers.get(&reg).cloned()
+    }
+}
+
+/// Counts are already folded into the commands that use them, and
+/// recording controls shouldn't end up in the macro itself.
+fn recordable(cmd: &Command) -> bool {
+    match *cmd {
+        Command::Digit(_) |
+        Command::Pending |
+        Command::Unknown |
+        Command::Record(_) |
+        Command::StopRecord => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(cmds: Option<Vec<Command>>) -> Option<String> {
+        cmds.map(|c| format!("{:?}", c))
+    }
+
+    #[test]
+    fn records_only_what_replays() {
+        let mut macros = Macros::new();
+        macros.record(&Command::Indent(1));
+        macros.start('q');
+        assert_eq!(macros.recording(), Some('q'));
+        for cmd in &[Command::Digit(3), Command::Pending, Command::Unknown, Command::Indent(3)] {
+            macros.record(cmd);
+        }
+        macros.record(&Command::StopRecord);
+        macros.stop();
+        assert_eq!(macros.recording(), None);
+        assert_eq!(names(macros.play('q')), Some("[Indent(3)]".to_string()));
+    }
+
+    #[test]
+    fn plays_the_last_register() {
+        let mut macros = Macros::new();
+        assert!(macros.play(LAST).is_none());
+        macros.start('a');
+        macros.record(&Command::Indent(1));
+        macros.stop();
+        assert!(macros.play('b').is_none());
+        // Playing an empty register still counts as the last one played.
+        assert!(macros.play(LAST).is_none());
+        macros.play('a');
+        assert_eq!(names(macros.play(LAST)), Some("[Indent(1)]".to_string()));
+        assert_eq!(names(macros.play(LAST)), Some("[Indent(1)]".to_string()));
+    }
+}
@@ -1,3 +1,4 @@
+extern crate libc;
 extern crate rustbox;
 extern crate unicode_width;
 
@@ -11,30 +12,58 @@ mod mode;
 mod hacks;
 mod settings;
 mod state;
+mod keymap;
+mod config;
+mod paste;
+mod macros;
+mod repeat;
+mod marks;
+mod session;
+mod build;
+mod jobs;
+mod events;
+mod ex;
+mod filetype;
+mod term;
+mod json;
+mod lsp;
+mod complete;
+mod indent;
+mod brackets;
 
 use std::default::Default;
+use std::cmp;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::error::Error;
+use std::io::Read;
 use std::sync::{Arc, Mutex};
 use std::str;
-use std::process;
 
 use rustbox::RustBox;
 
 use window::Window;
 use geometry::{Point, Size};
-use errors::CrbResult;
-use mode::Command;
+use errors::{CrbError, CrbResult};
+use mode::{Command, Direction, Mode};
 use state::State;
+use events::{Event, EventLoop, Timer};
+use jobs::JobEvent;
+use ex::Ex;
+use lsp::Reply;
+use complete::Completion;
 
 fn main() {
     logging::debug("started");
 
     // Restart loop.
     loop {
-        match startup() {
+        let res = startup();
+        hacks::bracketed_paste(false);
+        match res {
             Ok(true) => {
-                let res = hacks::restart();
+                let res = hacks::restart(&["--restore", &session::restart_path()]);
                 if let Err(e) = res {
                     println!("Fatal error restarting: {}", e);
                 }
@@ -51,145 +80,897 @@ fn startup() -> Result<bool, Box<Error>> {
         Result::Ok(v) => v,
         Result::Err(e) => return Err(Box::new(e)),
     };
+    hacks::bracketed_paste(true);
 
     let state = Arc::new(Mutex::new(State::new()));
+    if let Err(e) = state::do_safe(&*state, |s| config::load(s)) {
+        logging::debug(&format!("config error: {}", e));
+    }
+
+    let width = rustbox.width() as i32;
+    let height = rustbox.height() as i32;
 
-    let buf1 = match env::args().nth(1) {
-        Some(path) => {
-            match buffer::Buffer::load_from_file(&path, state.clone()) {
-                Ok(buffer) => buffer,
-                _ => buffer::Buffer::new_file(&path, state.clone()),
+    // `crb --session <name>` restores a session, `crb <file>` opens a file
+    // and a bare `crb` picks up where the last one quit. `--restore` is how
+    // a recompiled crb gets back everything its predecessor had open.
+    let args: Vec<String> = env::args().skip(1).collect();
+    let restored = match (args.get(0).map(|a| &a[..]), args.get(1)) {
+        (Some("--session"), Some(name)) => Some(session::load(name)),
+        (Some("--restore"), Some(path)) => {
+            let sess = session::read(path);
+            if sess.is_ok() {
+                let _ = fs::remove_file(path);
+            }
+            Some(sess)
+        }
+        (None, _) if session::exists(session::DEFAULT) => Some(session::load(session::DEFAULT)),
+        _ => None,
+    };
+    let restored = restored.map(|r| r.and_then(|sess| {
+        session::restore(&sess, &state).map(|ws| (ws, sess.focused))
+    }));
+    let (mut windows, mut fwi) = match restored {
+        Some(Ok(r)) => r,
+        other => {
+            if let Some(Err(e)) = other {
+                logging::debug(&format!("session error: {}", e));
             }
+            let buf = match args.get(0) {
+                Some(path) if !path.starts_with("--") => buffer::Buffer::open(path, state.clone()),
+                _ => buffer::Buffer::empty(state.clone()),
+            };
+            (default_layout(buf, width, height, &state), 0)
         }
-        None => buffer::Buffer::empty(state.clone()),
     };
 
-    let buf1 = Mutex::new(buf1);
+    let mut events = EventLoop::new();
+    // Shown over the other windows until the next key, like hover text.
+    let mut popup: Option<Window> = None;
 
-    let buf2 = buffer::Buffer::empty(state.clone());
-    let buf2 = Mutex::new(buf2);
+    loop {
+        if let Err(e) = update_terminals(&mut windows, &mut fwi) {
+            logging::debug(&format!("terminal error: {}", e));
+        }
+        for w in windows.iter() {
+            graphics::render(&rustbox, w);
+        }
+        if let Some(ref p) = popup {
+            graphics::render(&rustbox, p);
+        }
 
-    let buf3 = buffer::Buffer::empty(state.clone());
-    let buf3 = Mutex::new(buf3);
+        let (cmdline, message) = state::do_safe(&*state, |s| (s.cmdline.clone(), s.message.clone()));
+        match (windows[fwi].mode, message) {
+            (Mode::Command, _) => graphics::render_cmdline(&rustbox, &cmdline),
+            (_, Some(msg)) => graphics::render_message(&rustbox, &msg),
+            _ => {
+                if let Some(msg) = windows[fwi].diagnostic_at_cursor() {
+                    graphics::render_message(&rustbox, &msg);
+                }
+            }
+        }
 
-    let width = rustbox.width() as i32;
-    let height = rustbox.height() as i32;
+        rustbox.present();
+
+        let terminals = windows.iter().any(|w| w.is_terminal());
+        let event = match events.next(&rustbox, &state, terminals) {
+            Ok(e) => e,
+            // TODO don't panic...
+            Err(e) => panic!("{}", e),
+        };
+        let cmd = match event {
+            Event::Typeahead(key) => {
+                popup = None;
+                state::do_safe(&*state, |s| mode::map(windows[fwi].mode, key, s))
+            }
+            Event::Key(key) => {
+                popup = None;
+                state::do_safe(&*state, |s| s.message = None);
+                state::do_safe(&*state, |s| mode::input(windows[fwi].mode, key, s))
+            }
+            Event::Timer(Timer::Keys) => {
+                state::do_safe(&*state, |s| mode::flush(windows[fwi].mode, s))
+            }
+            Event::Tick => continue,
+            Event::Jobs(ref done) => {
+                match jobs_output(done, &mut windows, fwi, &state) {
+                    Ok(Flow::Restart) => return Ok(true),
+                    Ok(_) => {}
+                    Err(e) => {
+                        logging::debug(&format!("job error: {}", e));
+                        state::do_safe(&*state, |s| s.message = Some(format!("{}", e)));
+                    }
+                }
+                continue;
+            }
+            Event::Lsp(ref replies) => {
+                let screen = Size::new(width, height);
+                match lsp_replies(replies, &mut windows, &mut fwi, screen, &state) {
+                    Ok(Some(p)) => popup = Some(p),
+                    Ok(None) => {}
+                    Err(e) => state::do_safe(&*state, |s| s.message = Some(format!("{}", e))),
+                }
+                if let Err(e) = lsp_sync(&windows, &state) {
+                    state::do_safe(&*state, |s| s.message = Some(format!("{}", e)));
+                }
+                if let Some(p) = completion_popup(&windows[fwi], screen, &state) {
+                    popup = Some(p);
+                }
+                continue;
+            }
+        };
+        match execute(&cmd, &mut windows, &mut fwi, &state, 0) {
+            // Commands that failed aren't worth playing again.
+            Ok(Flow::Continue) => state::do_safe(&*state, |s| s.macros.record(&cmd)),
+            Ok(Flow::Quit) => break,
+            Ok(Flow::Restart) => return Ok(true),
+            Err(e) => {
+                logging::debug(&format!("cmd error: {}", e));
+                state::do_safe(&*state, |s| s.message = Some(format!("{}", e)));
+            }
+        }
+        if let Err(e) = lsp_sync(&windows, &state) {
+            state::do_safe(&*state, |s| s.message = Some(format!("{}", e)));
+        }
+        if let Some(p) = completion_popup(&windows[fwi], Size::new(width, height), &state) {
+            popup = Some(p);
+        }
+        match state::do_safe(&*state, |s| s.input_timeout()) {
+            Some(t) => events.set_timer(Timer::Keys, t),
+            None => events.cancel_timer(Timer::Keys),
+        }
+
+        // Remove num prefix if you didn't type a number
+        match cmd {
+            Command::Digit(_) | Command::Pending => {}
+            _ => state::do_safe(&*state, |s| s.end_num_prefix()),
+        }
+        // TODO handle errors
+        let _ = windows[2].clear();
+        let _ = windows[2].insert_s(&format!("{:?}", event));
+        let _ = windows[2].insert('\n');
+        let _ = windows[2].insert_s(&format!("{:?}", cmd));
+        let _ = windows[2].insert('\n');
+        let _ = windows[2]
+            .insert_s(&format!("state.num_prefix = {:?}", state.lock().unwrap().num_prefix));
+        if let Some(reg) = state.lock().unwrap().macros.recording() {
+            let _ = windows[2].insert_s(&format!("\nrecording @{}", reg));
+        }
+    }
+
+    Ok(false)
+}
+
+/// The main window with the file, compiler output below it and the debug
+/// overlay in the top right.
+fn default_layout(buf: buffer::Buffer,
+                  width: i32,
+                  height: i32,
+                  state: &Arc<Mutex<State>>)
+                  -> Vec<Window> {
+    let buf2 = buffer::Buffer::empty(state.clone());
+    let buf3 = buffer::Buffer::empty(state.clone());
 
-    let window1 = Window::new(buf1,
+    let window1 = Window::new(Mutex::new(buf),
                               Point::new(0, 0),
                               Size::new(width, height - 10),
                               state.clone());
-    let window2 = Window::new(buf2,
+    let window2 = Window::new(Mutex::new(buf2),
                               Point::new(0, height - 10),
                               Size::new(width, 10),
                               state.clone());
-    let window3 = Window::new(buf3,
+    let window3 = Window::new(Mutex::new(buf3),
                               Point::new(width / 2, 2),
                               Size::new(width / 2 - 1, 4),
                               state.clone());
-    let mut windows = vec![window1, window2, window3];
-    let mut fwi = 0;
+    vec![window1, window2, window3]
+}
 
+fn save_session(name: &str,
+                windows: &[Window],
+                fwi: usize,
+                state: &Arc<Mutex<State>>)
+                -> CrbResult<()> {
+    let sess = try!(session::capture(windows, fwi, state, false));
+    session::save(name, &sess)
+}
 
-    loop {
-        graphics::render(&rustbox, &windows[0]);
-        graphics::render(&rustbox, &windows[1]);
-        graphics::render(&rustbox, &windows[2]);
+/// Show what the build and `:!` commands print, and restart crb once a
+/// rebuild of it succeeds.
+fn jobs_output(events: &[JobEvent],
+               windows: &mut Vec<Window>,
+               fwi: usize,
+               state: &Arc<Mutex<State>>)
+               -> CrbResult<Flow> {
+    for e in events {
+        let shell = |id| state::do_safe(&**state, |s| s.shell_job == Some(id));
+        match *e {
+            JobEvent::Output(id, ref line) if shell(id) => {
+                try!(windows[1].append(line));
+                try!(windows[1].append("\n"));
+            }
+            JobEvent::Done(id, success) if shell(id) => {
+                state::do_safe(&**state, |s| s.shell_job = None);
+                if !success {
+                    try!(windows[1].append("[failed]\n"));
+                }
+            }
+            JobEvent::Output(id, ref line) => {
+                if state::do_safe(&**state, |s| s.build.owns(id)) {
+                    state::do_safe(&**state, |s| s.build.output(line));
+                    try!(windows[1].append(line));
+                    try!(windows[1].append("\n"));
+                }
+            }
+            JobEvent::Done(id, success) => {
+                if !state::do_safe(&**state, |s| s.build.owns(id)) {
+                    continue;
+                }
+                let restart = state::do_safe(&**state, |s| {
+                    s.build.finish();
+                    s.build.restart
+                });
+                if !success {
+                    try!(windows[1].append("Compilation failed\n"));
+                    continue;
+                }
+                try!(windows[1].append("Compilation successful\n"));
+                if restart {
+                    let sess = try!(session::capture(windows, fwi, state, true));
+                    try!(session::write(&session::restart_path(), &sess));
+                    return Ok(Flow::Restart);
+                }
+            }
+        }
+    }
+    Ok(Flow::Continue)
+}
 
-        rustbox.present();
+/// Split the bottom half off a window for a terminal.
+fn open_terminal(w: &mut Window, state: &Arc<Mutex<State>>) -> CrbResult<Window> {
+    let height = w.size.height / 2;
+    let topleft = Point::new(w.topleft.x, w.topleft.y + w.size.height - height);
+    let term = try!(Window::new_terminal(topleft, Size::new(w.size.width, height), state.clone()));
+    w.size.height -= height;
+    Ok(term)
+}
 
-        let event = rustbox.poll_event(false);
-        match event {
-            Ok(rustbox::Event::KeyEvent(key)) => {
-                let cmd = state::do_safe(&*state, |s| mode::map(windows[fwi].mode.clone(), key, s));
-                let res = match cmd {
-                    Command::Quit => break,
-                    Command::MoveUp(_) => windows[fwi].move_cursors(&cmd),
-                    Command::MoveDown(_) => windows[fwi].move_cursors(&cmd),
-                    Command::MoveLeft(_) => windows[fwi].move_cursors(&cmd),
-                    Command::MoveRight(_) => windows[fwi].move_cursors(&cmd),
-                    Command::Insert(c) => windows[fwi].insert(c),
-                    Command::Delete(d) => windows[fwi].delete(d),
-                    Command::NewLine => windows[fwi].insert('\n'),
-                    Command::Scroll(_) => windows[fwi].scroll(&cmd),
-                    Command::ChangeMode(m) => {
-                        windows[fwi].mode = m;
-                        Ok(())
-                    }
-                    Command::RecompileSelf => {
-                        // TODO handle error
-                        let _ = windows[2].clear();
-                        let res = hacks::recompile();
-                        let restart =
-                            res.and_then(|output| fill_compilation_buffer(&mut windows[1], output));
-                        if let Ok(true) = restart {
-                            return Ok(true);
-                        }
-                        restart.and(Ok(()))
-                    }
-                    Command::Save => windows[0].save(),
-                    Command::Digit(d) => {
-                        state::do_safe(&*state, |s| s.type_num_prefix(d));
-                        Ok(())
-                    }
-                    Command::FocusWindow(n) => {
-                        fwi = (n as usize) - 1;
-                        Ok(())
-                    }
-                    _ => Ok(()), //TODO show this somewhere
-                };
-                if let Err(e) = res {
-                    logging::debug(&format!("cmd error: {}", e));
+/// Show what the shells in terminal windows drew. Windows of shells that
+/// exited close, giving their space back to the main window.
+fn update_terminals(windows: &mut Vec<Window>, fwi: &mut usize) -> CrbResult<()> {
+    let mut i = 0;
+    while i < windows.len() {
+        if try!(windows[i].update_terminal()) {
+            i += 1;
+            continue;
+        }
+        let closed = windows.remove(i);
+        windows[0].size.height += closed.size.height;
+        if *fwi == i {
+            *fwi = 0;
+            windows[0].mode = Mode::Normal;
+        } else if *fwi > i {
+            *fwi -= 1;
+        }
+    }
+    Ok(())
+}
+
+/// Run a command typed after `:`. Output of `:!` goes to the window below,
+/// like the build's.
+fn run_ex(ex: &Ex, windows: &mut Vec<Window>, fwi: usize, state: &Arc<Mutex<State>>) -> CrbResult<()> {
+    match *ex {
+        Ex::Shell(ref cmd) => {
+            try!(windows[1].clear());
+            try!(windows[1].append(&format!("$ {}\n", cmd)));
+            state::do_safe(&**state, |s| {
+                if let Some(id) = s.shell_job.take() {
+                    s.jobs.cancel(id);
                 }
+                s.shell_job = Some(try!(s.jobs.spawn(cmd)));
+                Ok(())
+            })
+        }
+        Ex::ReadShell(ref cmd) => {
+            let output = try!(hacks::pipe(cmd, ""));
+            windows[fwi].checkpoint();
+            windows[fwi].insert_s(&output)
+        }
+        Ex::Filter(ref range, ref cmd) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            let input = windows[fwi].lines_text(first, last);
+            let output = try!(hacks::pipe(cmd, &input));
+            windows[fwi].checkpoint();
+            windows[fwi].replace_lines(first, last, &output)
+        }
+        Ex::Shift(ref range, n) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].shift_lines(first, last, n)
+        }
+        Ex::Reindent(ref range) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].reindent_lines(first, last)
+        }
+        Ex::Sort(ref range, ref how) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].sort_lines(first, last, how)
+        }
+        Ex::Uniq(ref range) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].uniq_lines(first, last)
+        }
+        Ex::Reverse(ref range) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].reverse_lines(first, last)
+        }
+        Ex::Align(ref range, ref delimiter) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].align_lines(first, last, delimiter)
+        }
+        Ex::Trim(ref range) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].trim_lines(first, last)
+        }
+        Ex::Retab(ref range, spaces) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            let spaces = spaces.unwrap_or_else(|| state::do_safe(&**state, |s| s.settings.insertSpaces));
+            windows[fwi].checkpoint();
+            windows[fwi].retab_lines(first, last, spaces)
+        }
+        Ex::Comment(ref range) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].checkpoint();
+            windows[fwi].toggle_comment_lines(first, last)
+        }
+        Ex::Fold(ref range) => {
+            let (first, last) = try!(windows[fwi].resolve_range(range));
+            windows[fwi].create_fold(first, last)
+        }
+    }
+}
+
+/// The window showing a file, opening it in the main window if none is.
+fn open_in_window(path: &str, windows: &mut Vec<Window>, state: &Arc<Mutex<State>>) -> CrbResult<usize> {
+    if let Ok(want) = fs::canonicalize(path) {
+        for (i, w) in windows.iter().enumerate() {
+            let shown = w.buf.lock().unwrap().file_path.clone();
+            if shown.and_then(|p| fs::canonicalize(p).ok()) == Some(want.clone()) {
+                return Ok(i);
             }
-            // TODO don't panic...
-            Err(e) => panic!("{}", e),
-            _ => {}
         }
-        // TODO handle errors
-        if let Ok(rustbox::Event::KeyEvent(key)) = event {
-            let cmd = state::do_safe(&*state, |s| mode::map(windows[fwi].mode.clone(), key, s));
-            // Remove num prefix if you didn't type a number
-            match cmd {
-                Command::Digit(_) => {}
-                _ => state::do_safe(&*state, |s| s.end_num_prefix()),
+    }
+    if windows[0].buf.lock().unwrap().unsaved {
+        return Err(CrbError::new("unsaved changes in the main window"));
+    }
+    try!(windows[0].save_marks());
+    windows[0].set_buffer(buffer::Buffer::open(path, state.clone()));
+    Ok(0)
+}
+
+/// Tell language servers about files as they're opened and changed.
+fn lsp_sync(windows: &[Window], state: &Arc<Mutex<State>>) -> CrbResult<()> {
+    for w in windows {
+        let mut buf = w.buf.lock().unwrap();
+        let (path, ft) = match (buf.file_path.clone(), buf.filetype()) {
+            (Some(p), Some(ft)) => (p, ft),
+            _ => continue,
+        };
+        let cmd = state::do_safe(&**state, |s| s.settings.languageServers.get(ft).cloned());
+        let cmd = match cmd {
+            Some(c) => c,
+            None => continue,
+        };
+        if !buf.tracking_edits() {
+            buf.track_edits();
+            let text = buf.text();
+            try!(state::do_safe(&**state, |s| s.lsp.open(&cmd, ft, &path, &text)));
+            continue;
+        }
+        let edits = buf.take_edits();
+        if !edits.is_empty() {
+            let text = buf.text();
+            try!(state::do_safe(&**state, |s| s.lsp.change(ft, &path, &edits, &text)));
+        }
+    }
+    Ok(())
+}
+
+/// Ask the language server of the focused window's file about what's
+/// under the primary cursor. The answer comes later, as an `Event::Lsp`.
+fn lsp_request(what: lsp::Request, w: &Window, state: &Arc<Mutex<State>>) -> CrbResult<()> {
+    let (line, offset) = *try!(w.cursor_positions()).last().unwrap();
+    let buf = w.buf.lock().unwrap();
+    let path = try!(buf.file_path.clone().ok_or(CrbError::new("no file")));
+    let ft = try!(buf.filetype().ok_or(CrbError::new("unknown filetype")));
+    let col = lsp::utf16_col(buf.line(line).unwrap_or(""), offset);
+    state::do_safe(&**state, |s| s.lsp.request(what, ft, &path, line, col))
+}
+
+/// Act on what the language servers said. Hover text comes back as a
+/// popup window.
+fn lsp_replies(replies: &[Reply],
+               windows: &mut Vec<Window>,
+               fwi: &mut usize,
+               screen: Size,
+               state: &Arc<Mutex<State>>)
+               -> CrbResult<Option<Window>> {
+    let mut popup = None;
+    for r in replies {
+        match *r {
+            Reply::Diagnostics(ref path, ref diags) => {
+                for w in windows.iter() {
+                    let mut buf = w.buf.lock().unwrap();
+                    if buf.file_path.as_ref().map(|p| lsp::absolute(p)) == Some(path.clone()) {
+                        buf.set_diagnostics(diags);
+                    }
+                }
+            }
+            Reply::Hover(ref text) if text.is_empty() => {
+                return Err(CrbError::new("nothing to show here"));
+            }
+            Reply::Hover(ref text) => {
+                popup = Some(try!(popup_window(text, "hover", &windows[*fwi], screen, state)));
             }
-            let _ = windows[2].clear();
-            let _ = windows[2].insert_s(&format!("{:?}", event));
-            let _ = windows[2].insert('\n');
-            let _ = windows[2].insert_s(&format!("{:?}", cmd));
-            let _ = windows[2].insert('\n');
-            let _ = windows[2]
-                .insert_s(&format!("state.num_prefix = {:?}", state.lock().unwrap().num_prefix));
+            Reply::Completion(ref items) => {
+                if let Some(ref mut c) = state.lock().unwrap().completion {
+                    c.add(items.clone());
+                }
+            }
+            Reply::Definition(ref locations) => {
+                let loc = try!(locations.first().ok_or(CrbError::new("no definition found")));
+                *fwi = try!(open_in_window(&lsp::relative(&loc.path), windows, state));
+                let col = {
+                    let buf = windows[*fwi].buf.lock().unwrap();
+                    lsp::char_col(buf.line(loc.line).unwrap_or(""), loc.col)
+                };
+                try!(windows[*fwi].jump_to(loc.line, col));
+            }
+            Reply::References(ref locations) => try!(show_references(locations, windows, state)),
+            Reply::Error(ref e) => return Err(CrbError::new(e)),
         }
     }
+    Ok(popup)
+}
 
-    Ok(false)
+/// A window over the others, under the cursor or above it if there's no
+/// room.
+fn popup_window(text: &str,
+                name: &str,
+                w: &Window,
+                screen: Size,
+                state: &Arc<Mutex<State>>)
+                -> CrbResult<Window> {
+    let cursor = try!(w.cursor_screen_pos());
+    let lines = text.lines().count() as i32;
+    let gutter = lines.to_string().len() as i32 + 1;
+    let longest = text.lines().map(|l| l.chars().count()).max().unwrap_or(0) as i32;
+    let width = cmp::min(longest + gutter + 1, screen.width);
+    let height = cmp::min(lines + 1, screen.height / 2);
+    let y = match cursor.y + 1 + height <= screen.height {
+        true => cursor.y + 1,
+        false => cmp::max(0, cursor.y - height),
+    };
+    let x = cmp::max(0, cmp::min(cursor.x, screen.width - width));
+    let buf = buffer::Buffer::from_text(text, state.clone());
+    let mut popup = Window::new(Mutex::new(buf), Point::new(x, y), Size::new(width, height), state.clone());
+    popup.name = Some(name.to_string());
+    Ok(popup)
 }
 
-fn fill_compilation_buffer(w: &mut Window, output: process::Output) -> CrbResult<bool> {
-    try!(w.clear());
-    if output.status.success() {
-        try!(w.insert_s("Compilation successful\n"));
-        Ok(true)
-    } else {
-        try!(w.insert_s("Compilation failed\n"));
-        match str::from_utf8(&output.stderr) {
-            Ok(s) => try!(w.insert_s(s)),
-            Err(e) => {
-                try!(w.insert_s("<stderr-utf8-error>\n"));
-                try!(w.insert_s(&format!("{}", e)));
+/// Complete the text before the primary cursor from the words in every
+/// file that's open, or from file paths. The language server's candidates
+/// come later. None if there's nothing to complete with.
+fn start_completion(windows: &[Window],
+                    fwi: usize,
+                    state: &Arc<Mutex<State>>)
+                    -> CrbResult<Option<Completion>> {
+    let (line, offset) = *try!(windows[fwi].cursor_positions()).last().unwrap();
+    let text = windows[fwi].buf.lock().unwrap().line(line).unwrap_or("").to_string();
+    let (prefix, path) = complete::prefix_at(&text, offset);
+    let candidates = match path {
+        true => complete::paths(&prefix),
+        false => {
+            let texts: Vec<String> = windows.iter()
+                .map(|w| w.buf.lock().unwrap())
+                .filter(|buf| buf.file_path.is_some())
+                .map(|buf| buf.text())
+                .collect();
+            complete::words(&texts, &prefix)
+        }
+    };
+    let asked = !path && lsp_request(lsp::Request::Completion, &windows[fwi], state).is_ok();
+    if candidates.is_empty() && !asked {
+        return Ok(None);
+    }
+    Ok(Some(Completion::new(&prefix, candidates)))
+}
+
+/// Put the next candidate (previous if n is negative) in place of the
+/// text being completed, starting a completion if there isn't one.
+fn complete(n: i32, windows: &mut Vec<Window>, fwi: usize, state: &Arc<Mutex<State>>) -> CrbResult<()> {
+    if state::do_safe(&**state, |s| s.completion.is_none()) {
+        let c = try!(try!(start_completion(windows, fwi, state))
+            .ok_or(CrbError::new("no completions")));
+        state.lock().unwrap().completion = Some(c);
+    }
+    let (old, new) = {
+        let mut s = state.lock().unwrap();
+        let c = s.completion.as_mut().unwrap();
+        let old = c.current().to_string();
+        c.select(n);
+        (old, c.current().to_string())
+    };
+    if old == new {
+        return Ok(());
+    }
+    for _ in 0..old.chars().count() {
+        try!(windows[fwi].delete(Direction::B));
+    }
+    windows[fwi].insert_s(&new)
+}
+
+/// Whether there's a word or path right before the primary cursor.
+fn after_word(w: &Window) -> CrbResult<bool> {
+    let (line, offset) = *try!(w.cursor_positions()).last().unwrap();
+    let buf = w.buf.lock().unwrap();
+    Ok(!complete::prefix_at(buf.line(line).unwrap_or(""), offset).0.is_empty())
+}
+
+/// The candidates of the completion going on, with the selected one under
+/// the popup's cursor.
+fn completion_popup(w: &Window, screen: Size, state: &Arc<Mutex<State>>) -> Option<Window> {
+    let (text, selected) = match state.lock().unwrap().completion {
+        Some(ref c) if !c.candidates.is_empty() => (c.candidates.join("\n"), c.selected),
+        _ => return None,
+    };
+    let mut popup = match popup_window(&text, "complete", w, screen, state) {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+    if let Some(i) = selected {
+        let _ = popup.set_cursor_positions(&[(i as i32, 0)]);
+        let _ = popup.move_cursors(&Command::MoveRight(0));
+    }
+    Some(popup)
+}
+
+/// Put references in the window below as a list to go through with
+/// `NextError`, each with the text of its line.
+fn show_references(locations: &[lsp::Location],
+                   windows: &mut Vec<Window>,
+                   state: &Arc<Mutex<State>>)
+                   -> CrbResult<()> {
+    if locations.is_empty() {
+        return Err(CrbError::new("no references found"));
+    }
+    // The build's errors go in the same list.
+    if state::do_safe(&**state, |s| s.build.running()) {
+        return Err(CrbError::new("a build is running; try again when it's done"));
+    }
+    let mut files: HashMap<String, Vec<String>> = HashMap::new();
+    let mut list = Vec::new();
+    for loc in locations {
+        // Open files can have changes that aren't saved yet.
+        let open = windows.iter().find(|w| {
+            w.buf.lock().unwrap().file_path.as_ref().map(|p| lsp::absolute(p)) == Some(loc.path.clone())
+        });
+        let text = match open {
+            Some(w) => w.buf.lock().unwrap().line(loc.line).unwrap_or("").to_string(),
+            None => {
+                let lines = files.entry(loc.path.clone()).or_insert_with(|| {
+                    let mut contents = String::new();
+                    if let Ok(mut f) = fs::File::open(&loc.path) {
+                        let _ = f.read_to_string(&mut contents);
+                    }
+                    contents.lines().map(|l| l.to_string()).collect()
+                });
+                lines.get(loc.line as usize).cloned().unwrap_or(String::new())
             }
         };
-        try!(w.insert('\n'));
-        match str::from_utf8(&output.stdout) {
-            Ok(s) => try!(w.insert_s(s)),
-            Err(e) => {
-                try!(w.insert_s("<stdout-utf8-error>\n"));
-                try!(w.insert_s(&format!("{}", e)));
+        list.push(format!("{}:{}:{}: {}",
+                          lsp::relative(&loc.path),
+                          loc.line + 1,
+                          lsp::char_col(&text, loc.col) + 1,
+                          text.trim()));
+    }
+    try!(windows[1].clear());
+    state::do_safe(&**state, |s| s.build.list());
+    for line in list {
+        state::do_safe(&**state, |s| s.build.output(&line));
+        try!(windows[1].append(&line));
+        try!(windows[1].append("\n"));
+    }
+    Ok(())
+}
+
+/// What the main loop should do after a command.
+enum Flow {
+    Continue,
+    Quit,
+    /// Exec the freshly compiled editor.
+    Restart,
+}
+
+/// Macros can play other macros, but not forever.
+const MAX_REPLAY_DEPTH: u32 = 100;
+
+/// Run each command count times, stopping early to quit or restart.
+fn replay(cmds: &[Command],
+          count: u32,
+          windows: &mut Vec<Window>,
+          fwi: &mut usize,
+          state: &Arc<Mutex<State>>,
+          depth: u32)
+          -> CrbResult<Flow> {
+    if depth >= MAX_REPLAY_DEPTH {
+        return Err(CrbError::new("macros nested too deeply"));
+    }
+    for _ in 0..count {
+        for c in cmds {
+            match try!(execute(c, windows, fwi, state, depth + 1)) {
+                Flow::Continue => {}
+                flow => return Ok(flow),
             }
-        };
-        Ok(false)
+        }
     }
+    Ok(Flow::Continue)
 }
 
+/// Switch a window's mode, marking where Insert mode ended and letting
+/// undo take back a whole Insert mode session at once.
+fn change_mode(m: Mode, w: &mut Window, state: &Arc<Mutex<State>>) -> CrbResult<()> {
+    // Typing in a terminal window goes to its shell.
+    let m = match (m, w.is_terminal()) {
+        (Mode::Insert, true) | (Mode::Replace, true) => Mode::Terminal,
+        (m, _) => m,
+    };
+    match (w.mode, m) {
+        (Mode::Insert, Mode::Normal) | (Mode::Replace, Mode::Normal) => try!(w.set_mark('^')),
+        (Mode::Normal, Mode::Insert) | (Mode::Normal, Mode::Replace) => w.checkpoint(),
+        _ => {}
+    }
+    state::do_safe(&**state, |s| s.cmdline.clear());
+    w.mode = m;
+    Ok(())
+}
+
+/// Run a command against the focused window. Doesn't redraw, so macros
+/// can run many of these in a row.
+fn execute(cmd: &Command,
+           windows: &mut Vec<Window>,
+           fwi: &mut usize,
+           state: &Arc<Mutex<State>>,
+           depth: u32)
+           -> CrbResult<Flow> {
+    if let Mode::Normal = windows[*fwi].mode {
+        if repeat::changes_buffer(cmd) {
+            windows[*fwi].checkpoint();
+        }
+    }
+    match *cmd {
+        Command::Complete(_) | Command::TabComplete | Command::Pending => {}
+        // Anything else takes the completion as it is.
+        _ => state::do_safe(&**state, |s| s.completion = None),
+    }
+    let res = match *cmd {
+        Command::Quit => {
+            for w in windows.iter() {
+                if let Err(e) = w.save_marks() {
+                    logging::debug(&format!("{}", e));
+                }
+            }
+            if let Err(e) = save_session(session::DEFAULT, windows, *fwi, state) {
+                logging::debug(&format!("{}", e));
+            }
+            return Ok(Flow::Quit);
+        }
+        Command::MoveUp(_) => windows[*fwi].move_cursors(cmd),
+        Command::MoveDown(_) => windows[*fwi].move_cursors(cmd),
+        Command::MoveLeft(_) => windows[*fwi].move_cursors(cmd),
+        Command::MoveRight(_) => windows[*fwi].move_cursors(cmd),
+        Command::LineStart => windows[*fwi].move_cursors(cmd),
+        Command::LineEnd => windows[*fwi].move_cursors(cmd),
+        Command::Insert(c) => windows[*fwi].insert(c),
+        Command::Delete(Direction::B) => windows[*fwi].backspace(),
+        Command::Delete(ref d) => windows[*fwi].delete(d.clone()),
+        Command::DeleteWord => windows[*fwi].delete_word(),
+        Command::NewLine => windows[*fwi].new_line(),
+        Command::Paste(ref text) => windows[*fwi].insert_s(text),
+        Command::Scroll(_) => windows[*fwi].scroll(cmd),
+        Command::HalfPage(n) => windows[*fwi].scroll_half_pages(n),
+        Command::ChangeMode(m) => change_mode(m, &mut windows[*fwi], state),
+        Command::Append | Command::AppendLine | Command::InsertLineStart | Command::OpenLine(_) => {
+            try!(change_mode(Mode::Insert, &mut windows[*fwi], state));
+            if windows[*fwi].is_terminal() {
+                return Ok(Flow::Continue);
+            }
+            match *cmd {
+                Command::Append => windows[*fwi].move_cursors(&Command::MoveRight(1)),
+                Command::AppendLine => windows[*fwi].move_cursors(&Command::LineEnd),
+                Command::OpenLine(ref d) => windows[*fwi].open_line(d.clone()),
+                _ => windows[*fwi].to_first_non_blank(),
+            }
+        }
+        Command::CmdlineInsert(ref text) => {
+            state::do_safe(&**state, |s| s.cmdline.push_str(text));
+            Ok(())
+        }
+        Command::CmdlineDelete => {
+            state::do_safe(&**state, |s| s.cmdline.pop());
+            Ok(())
+        }
+        Command::RunCmdline => {
+            let line = state::do_safe(&**state, |s| s.cmdline.split_off(0));
+            windows[*fwi].mode = Mode::Normal;
+            let ex = try!(ex::parse(&line).map_err(|e| CrbError::new(&e)));
+            run_ex(&ex, windows, *fwi, state)
+        }
+        Command::Undo(n) => windows[*fwi].undo(n),
+        Command::OpenTerminal => {
+            *fwi = match windows.iter().position(|w| w.is_terminal()) {
+                Some(i) => i,
+                None => {
+                    let w = try!(open_terminal(&mut windows[0], state));
+                    windows.push(w);
+                    windows.len() - 1
+                }
+            };
+            windows[*fwi].mode = Mode::Terminal;
+            Ok(())
+        }
+        Command::TermSend(ref text) => windows[*fwi].send_terminal(text),
+        Command::RecompileSelf => {
+            try!(windows[1].clear());
+            state::do_safe(&**state, |s| s.build.start(&mut s.jobs, "make", true))
+        }
+        Command::Build => {
+            try!(windows[1].clear());
+            state::do_safe(&**state, |s| {
+                let cmd = s.settings.buildCommand.clone();
+                s.build.start(&mut s.jobs, &cmd, false)
+            })
+        }
+        Command::CancelJobs => {
+            let (cancelled, build) = state::do_safe(&**state, |s| {
+                let ids = s.jobs.cancel_all();
+                s.shell_job = None;
+                let build = ids.iter().any(|&id| s.build.owns(id));
+                if build {
+                    s.build.finish();
+                }
+                (ids.len(), build)
+            });
+            if build {
+                try!(windows[1].append("Cancelled\n"));
+            }
+            match cancelled {
+                0 => Err(CrbError::new("no jobs running")),
+                _ => Ok(()),
+            }
+        }
+        Command::NextError(n) => {
+            let diag = state::do_safe(&**state, |s| s.build.next_error(n).cloned());
+            let diag = try!(diag.ok_or(CrbError::new("no errors")));
+            logging::debug(&format!("{}:{}: {}", diag.file, diag.line, diag.message));
+            try!(windows[1].set_cursor_positions(&[(diag.output_line, 0)]));
+            try!(windows[1].move_cursors(&Command::MoveRight(0)));
+            *fwi = try!(open_in_window(&diag.file, windows, state));
+            windows[*fwi].jump_to(diag.line - 1, diag.col - 1)
+        }
+        Command::Save => {
+            try!(windows[0].save());
+            // The server hears about formatting before the save.
+            try!(lsp_sync(windows, state));
+            let buf = windows[0].buf.lock().unwrap();
+            match (buf.file_path.as_ref(), buf.filetype()) {
+                (Some(path), Some(ft)) => state::do_safe(&**state, |s| s.lsp.saved(ft, path)),
+                _ => Ok(()),
+            }
+        }
+        Command::Complete(n) => complete(n, windows, *fwi, state),
+        Command::TabComplete => {
+            let mut going = state::do_safe(&**state, |s| s.completion.is_some());
+            // After a word with nothing to complete it with, it's a tab.
+            if !going && try!(after_word(&windows[*fwi])) {
+                if let Some(c) = try!(start_completion(windows, *fwi, state)) {
+                    state.lock().unwrap().completion = Some(c);
+                    going = true;
+                }
+            }
+            match going {
+                true => complete(1, windows, *fwi, state),
+                false => windows[*fwi].insert('\t'),
+            }
+        }
+        Command::Indent(n) => {
+            let (first, last) = try!(windows[*fwi].cursor_lines(n));
+            windows[*fwi].shift_lines(first, last, 1)
+        }
+        Command::Dedent(n) => {
+            let (first, last) = try!(windows[*fwi].cursor_lines(n));
+            windows[*fwi].shift_lines(first, last, -1)
+        }
+        Command::Reindent(n) => {
+            let (first, last) = try!(windows[*fwi].cursor_lines(n));
+            windows[*fwi].reindent_lines(first, last)
+        }
+        Command::Hover => lsp_request(lsp::Request::Hover, &windows[*fwi], state),
+        Command::MatchBracket => windows[*fwi].match_bracket(),
+        Command::ToggleComment(n) => windows[*fwi].toggle_comment(n),
+        Command::ToggleCase(n) => windows[*fwi].toggle_case(n),
+        Command::ChangeCase(case, ref motion) => windows[*fwi].change_case(case, motion),
+        Command::Join(n) => windows[*fwi].join_lines(n),
+        Command::ReplaceChar(n, c) => windows[*fwi].replace_chars(n, c),
+        Command::Overwrite(c) => windows[*fwi].overwrite(c),
+        Command::Increment(n) => windows[*fwi].increment(n),
+        Command::OpenFold => windows[*fwi].open_fold(),
+        Command::CloseFold => windows[*fwi].close_fold(),
+        Command::ToggleFold => windows[*fwi].toggle_fold(),
+        Command::CreateFold(n) => {
+            // One line is no fold; `zF` folds the line and the next.
+            let (first, last) = try!(windows[*fwi].cursor_lines(cmp::max(n, 2)));
+            windows[*fwi].create_fold(first, last)
+        }
+        Command::GoToDefinition => lsp_request(lsp::Request::Definition, &windows[*fwi], state),
+        Command::FindReferences => lsp_request(lsp::Request::References, &windows[*fwi], state),
+        Command::Digit(d) => {
+            state::do_safe(&**state, |s| s.type_num_prefix(d));
+            Ok(())
+        }
+        Command::FocusWindow(n) => {
+            if n == 0 || n as usize > windows.len() {
+                return Err(CrbError::new(&format!("no window {}", n)));
+            }
+            *fwi = (n as usize) - 1;
+            Ok(())
+        }
+        Command::SaveSession(ref name) => save_session(name, windows, *fwi, state),
+        Command::LoadSession(ref name) => {
+            if windows.iter().any(|w| w.buf.lock().unwrap().unsaved) {
+                return Err(CrbError::new("unsaved changes; save them before loading a session"));
+            }
+            if windows.iter().any(|w| w.is_terminal()) {
+                return Err(CrbError::new("a terminal is open; exit it before loading a session"));
+            }
+            let sess = try!(session::load(name));
+            *windows = try!(session::restore(&sess, state));
+            *fwi = sess.focused;
+            Ok(())
+        }
+        Command::Record(reg) => {
+            state::do_safe(&**state, |s| s.macros.start(reg));
+            Ok(())
+        }
+        Command::StopRecord => {
+            state::do_safe(&**state, |s| s.macros.stop());
+            Ok(())
+        }
+        Command::Replay(reg, count) => {
+            let cmds = state::do_safe(&**state, |s| s.macros.play(reg));
+            let cmds = try!(cmds.ok_or(CrbError::new(&format!("register {} is empty", reg))));
+            return replay(&cmds, count, windows, fwi, state, depth);
+        }
+        Command::RepeatChange(count) => {
+            let cmds = state::do_safe(&**state, |s| s.repeat.last());
+            return replay(&cmds, count, windows, fwi, state, depth);
+        }
+        Command::AddCursor(n) => windows[*fwi].add_cursor_lines(n),
+        Command::AddCursorNextMatch => windows[*fwi].add_cursor_next_match(),
+        Command::AddCursorAllMatches => windows[*fwi].add_cursor_all_matches(),
+        Command::SingleCursor => windows[*fwi].single_cursor(),
+        Command::SetMark(name) => windows[*fwi].set_mark(name),
+        Command::JumpMark(name) => windows[*fwi].jump_to_mark(name, true),
+        Command::JumpMarkLine(name) => windows[*fwi].jump_to_mark(name, false),
+        Command::JumpBack(n) => windows[*fwi].jump_back(n),
+        _ => Ok(()), //TODO show this somewhere
+    };
+    try!(res);
+    state::do_safe(&**state, |s| s.repeat.observe(cmd));
+    Ok(Flow::Continue)
+}
@@ -0,0 +1,72 @@
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+
+use errors::{CrbError, CrbResult};
+
+/// A mark as stored on disk: name, line, offset.
+pub type Saved = (char, i32, i32);
+
+/// Marks of every file live in one file: $HOME/.crb_marks.
+/// Each line is `<name> <line> <offset> <absolute path>`.
+fn marks_file() -> Option<String> {
+    env::var("HOME").ok().map(|home| home + "/.crb_marks")
+}
+
+/// Files are keyed by absolute path so marks survive a different cwd.
+fn key(path: &str) -> String {
+    fs::canonicalize(path)
+        .ok()
+        .and_then(|p| p.to_str().map(|s| s.to_string()))
+        .unwrap_or(path.to_string())
+}
+
+fn read_all() -> Vec<(String, Saved)> {
+    let mut contents = String::new();
+    let read = marks_file()
+        .and_then(|f| fs::File::open(f).ok())
+        .map(|mut f| f.read_to_string(&mut contents));
+    if read.is_none() {
+        return Vec::new();
+    }
+    contents.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<(String, Saved)> {
+    let mut parts = line.splitn(4, ' ');
+    let name = parts.next().and_then(|s| s.chars().next());
+    let l = parts.next().and_then(|s| s.parse().ok());
+    let o = parts.next().and_then(|s| s.parse().ok());
+    let path = parts.next();
+    match (name, l, o, path) {
+        (Some(n), Some(l), Some(o), Some(p)) => Some((p.to_string(), (n, l, o))),
+        _ => None,
+    }
+}
+
+/// The saved marks of a file.
+pub fn load(path: &str) -> Vec<Saved> {
+    let key = key(path);
+    read_all().into_iter().filter(|&(ref p, _)| *p == key).map(|(_, m)| m).collect()
+}
+
+/// Replace the saved marks of a file.
+pub fn store(path: &str, marks: &[Saved]) -> CrbResult<()> {
+    let file = match marks_file() {
+        Some(f) => f,
+        None => return Ok(()),
+    };
+    let key = key(path);
+    let mut all: Vec<(String, Saved)> =
+        read_all().into_iter().filter(|&(ref p, _)| *p != key).collect();
+    for m in marks {
+        all.push((key.clone(), *m));
+    }
+    let mut f = try!(fs::File::create(&file)
+        .map_err(|e| CrbError::new(&format!("error while saving marks {}", e))));
+    for (p, (n, l, o)) in all {
+        try!(writeln!(f, "{} {} {} {}", n, l, o, p)
+            .map_err(|e| CrbError::new(&format!("error while saving marks {}", e))));
+    }
+    Ok(())
+}
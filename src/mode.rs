@@ -1,11 +1,27 @@
 use rustbox::Key;
-use std::collections::HashMap;
+use keymap;
+use keymap::{Binding, Lookup};
+use paste::Feed;
 use state;
+use term;
 
 #[derive(Debug, Copy, Clone)]
 pub enum Mode {
     Normal,
     Insert,
+    /// Typing a command after `:`.
+    Command,
+    /// Typing into a terminal window's shell.
+    Terminal,
+    /// Typing over the text.
+    Replace,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Case {
+    Lower,
+    Upper,
+    Toggle,
 }
 
 #[derive(Debug, Clone)]
@@ -14,17 +30,119 @@ pub enum Command {
     MoveRight(i32),
     MoveUp(i32),
     MoveDown(i32),
+    LineStart,
+    LineEnd,
     Scroll(i32),
+    /// Scroll by half the window height, moving the cursors along.
+    HalfPage(i32),
     Quit,
     Insert(char),
     Delete(Direction),
+    /// Delete the word before the cursor.
+    DeleteWord,
     NewLine,
+    /// Text from a bracketed paste, inserted as is.
+    Paste(String),
     Unknown,
+    /// Waiting for more keys of a sequence.
+    Pending,
     ChangeMode(Mode),
     RecompileSelf,
     Save,
     Digit(u32),
     FocusWindow(u32),
+    /// Start recording a macro into a register.
+    Record(char),
+    StopRecord,
+    /// Play the macro in a register, count times.
+    Replay(char, u32),
+    /// Do the last change again, count times.
+    RepeatChange(u32),
+    /// Add a cursor this many lines below the primary one.
+    AddCursor(i32),
+    /// Add a cursor at the next occurrence of the word under the cursor.
+    AddCursorNextMatch,
+    /// Add a cursor at every match of the last search.
+    AddCursorAllMatches,
+    SingleCursor,
+    SetMark(char),
+    /// Jump to a mark's exact position.
+    JumpMark(char),
+    /// Jump to the first non-blank character of a mark's line.
+    JumpMarkLine(char),
+    /// Go back in the jump list (forward if negative).
+    JumpBack(i32),
+    /// Save the open windows, cursors and search under a name.
+    SaveSession(String),
+    LoadSession(String),
+    /// Run the build command in the background.
+    Build,
+    /// Kill every background job.
+    CancelJobs,
+    /// Go to the next error of the last build (previous if negative).
+    NextError(i32),
+    /// Type text on the command line.
+    CmdlineInsert(String),
+    CmdlineDelete,
+    /// Run what's on the command line.
+    RunCmdline,
+    /// Undo n changes (redo if negative).
+    Undo(i32),
+    /// Open a window running a shell, or go to the one that's open.
+    OpenTerminal,
+    /// Type text into a terminal window.
+    TermSend(String),
+    /// Jump to the bracket matching the one under the cursor, or the next
+    /// one on the line.
+    MatchBracket,
+    /// Toggle the case of n characters from the cursor on.
+    ToggleCase(i32),
+    /// Waits for a motion to change the case over, like `gu`.
+    CaseOperator(Case),
+    /// Change the case from the cursor to where a motion goes, or of whole
+    /// lines for up and down.
+    ChangeCase(Case, Box<Command>),
+    /// Join the next line onto the cursor's, n times.
+    Join(i32),
+    /// Replace n characters with a character.
+    ReplaceChar(i32, char),
+    /// Type a character over the one at the cursor.
+    Overwrite(char),
+    /// Add n to the number under or after the cursor.
+    Increment(i32),
+    /// Go into Insert mode after the cursor.
+    Append,
+    /// Go into Insert mode at the end of the line.
+    AppendLine,
+    /// Go into Insert mode at the first non-blank character of the line.
+    InsertLineStart,
+    /// Go into Insert mode on a new line below (F) or above (B).
+    OpenLine(Direction),
+    /// Comment out n lines from the cursor's, or back in.
+    ToggleComment(i32),
+    /// Open the fold at the cursor.
+    OpenFold,
+    /// Close the fold at the cursor, or fold the block it's in.
+    CloseFold,
+    ToggleFold,
+    /// Fold n lines from the cursor's.
+    CreateFold(i32),
+    /// Ask the language server what's under the cursor.
+    Hover,
+    GoToDefinition,
+    /// List the references to what's under the cursor, to go through like
+    /// build errors.
+    FindReferences,
+    /// Complete the word before the cursor, or go n candidates further
+    /// (back if negative) when already completing.
+    Complete(i32),
+    /// Complete after a word and insert a tab anywhere else.
+    TabComplete,
+    /// Indent n lines from the cursor's one level more.
+    Indent(i32),
+    Dedent(i32),
+    /// Indent n lines from the cursor's by their brackets.
+    Reindent(i32),
 }
 
 #[derive(Debug, Clone)]
@@ -33,46 +151,228 @@ pub enum Direction {
     B,
 }
 
-type ModeMap = HashMap<Key, Command>;
+/// Handle a key from the terminal. Pasted text is collected here before
+/// it can reach the keymap.
+pub fn input(mode: Mode, key: Key, state: &mut state::State) -> Command {
+    match state.paste.feed(key) {
+        Feed::Held => Command::Pending,
+        Feed::Done(text) => {
+            match mode {
+                Mode::Command => Command::CmdlineInsert(text),
+                Mode::Terminal => Command::TermSend(text),
+                _ => Command::Paste(text),
+            }
+        }
+        Feed::Keys(keys) => map_first(mode, keys, state),
+    }
+}
+
+/// Map the first key and put the rest back in line ahead of the terminal.
+fn map_first(mode: Mode, keys: Vec<Key>, state: &mut state::State) -> Command {
+    let mut keys = keys.into_iter();
+    let first = match keys.next() {
+        Some(k) => k,
+        None => return Command::Unknown,
+    };
+    for (i, k) in keys.enumerate() {
+        state.typeahead.insert(i, k);
+    }
+    map(mode, first, state)
+}
 
+/// Resolve a key press through the keymap of the given mode.
+/// Returns `Command::Pending` while in the middle of a key sequence.
 pub fn map(mode: Mode, key: Key, state: &mut state::State) -> Command {
-    match mode {
-        Mode::Insert => {
-            match key {
-                Key::Char(c) => Command::Insert(c),
-                Key::Tab => Command::Insert('\t'),
-                Key::Esc => Command::ChangeMode(Mode::Normal),
-                Key::F(1) => Command::ChangeMode(Mode::Normal),
-                Key::Backspace => Command::Delete(Direction::B),
-                Key::Enter => Command::NewLine,
-                Key::Down => Command::MoveDown(1),
-                Key::Up => Command::MoveUp(1),
-                Key::Left => Command::MoveLeft(1),
-                Key::Right => Command::MoveRight(1),
-                _ => Command::Unknown,
-            }
+    if let Some(cmd) = state.pending_arg.take() {
+        return match key {
+            Key::Char(c) => with_char(cmd, c),
+            _ => Command::Unknown,
+        };
+    }
+    if let (true, Some(op)) = (state.pending_keys.is_empty(), state.pending_op.take()) {
+        // An operator's own last key, like `guu`, works on whole lines.
+        if line_key(&op) == Some(key) {
+            return with_motion(op, Command::MoveDown(state.num_prefix as i32 - 1));
+        }
+        state.pending_op = Some(op);
+    }
+    state.pending_keys.push(key);
+    let keys = state.pending_keys.clone();
+    match state.keymap.lookup(mode, &keys) {
+        Lookup::Exact(b) => {
+            state.pending_keys.clear();
+            resolve(mode, b, state)
         }
-        Mode::Normal => {
-            match key {
-                Key::Char('q') => Command::Quit,
-                Key::Char('j') => Command::MoveDown(state.num_prefix as i32),
-                Key::Char('k') => Command::MoveUp(state.num_prefix as i32),
-                Key::Char('h') => Command::MoveLeft(state.num_prefix as i32),
-                Key::Char('l') => Command::MoveRight(state.num_prefix as i32),
-                Key::Char('x') => Command::Delete(Direction::F),
-                Key::Down => Command::MoveDown(1),
-                Key::Up => Command::MoveUp(1),
-                Key::Left => Command::MoveLeft(1),
-                Key::Right => Command::MoveRight(1),
-                Key::Char('i') => Command::ChangeMode(Mode::Insert),
-                Key::Char('b') => Command::Scroll(state.num_prefix as i32),
-                Key::Char('v') => Command::Scroll(-(state.num_prefix as i32)),
-                Key::Char('r') => Command::RecompileSelf,
-                Key::Char(' ') => Command::Save,
-                Key::Char(d) if d.is_digit(10) => Command::Digit(d.to_digit(10).unwrap()),
-                Key::Char('w') => Command::FocusWindow(state.num_prefix),
-                _ => Command::Unknown,
+        Lookup::Partial(_) => Command::Pending,
+        Lookup::None => {
+            state.pending_keys.clear();
+            first_alone(mode, keys, state)
+        }
+    }
+}
+
+/// Called when the timeout expires in the middle of a key sequence.
+pub fn flush(mode: Mode, state: &mut state::State) -> Command {
+    if state.paste.holding() {
+        let keys = state.paste.flush();
+        return map_first(mode, keys, state);
+    }
+    let keys: Vec<Key> = state.pending_keys.drain(..).collect();
+    if keys.is_empty() {
+        return Command::Unknown;
+    }
+    match state.keymap.lookup(mode, &keys) {
+        Lookup::Exact(b) |
+        Lookup::Partial(Some(b)) => resolve(mode, b, state),
+        _ => first_alone(mode, keys, state),
+    }
+}
+
+/// Resolve the first of some keys that don't make a binding together, and
+/// put the rest back in line to be read again, so that with `jk` mapped in
+/// Insert mode typing `ja` still types both.
+fn first_alone(mode: Mode, keys: Vec<Key>, state: &mut state::State) -> Command {
+    for (i, &k) in keys[1..].iter().enumerate() {
+        state.typeahead.insert(i, k);
+    }
+    match state.keymap.lookup(mode, &keys[..1]) {
+        Lookup::Exact(b) |
+        Lookup::Partial(Some(b)) => resolve(mode, b, state),
+        _ => {
+            let cmd = unbound(mode, &keys[..1]);
+            // A key that isn't a motion or a count ends an operator.
+            match cmd {
+                Command::Digit(_) => {}
+                _ => state.pending_op = None,
             }
+            cmd
+        }
+    }
+}
+
+fn resolve(mode: Mode, b: Binding, state: &mut state::State) -> Command {
+    let cmd = match state.keymap.resolve(mode, b) {
+        Some(cmd) => keymap::with_count(cmd, state.num_prefix),
+        None => return Command::Unknown,
+    };
+    if let Some(op) = state.pending_op.take() {
+        return match is_motion(&cmd) {
+            true => with_motion(op, cmd),
+            false => Command::Unknown,
+        };
+    }
+    match cmd {
+        // q stops recording without waiting for a register.
+        Command::Record(_) if state.macros.recording().is_some() => Command::StopRecord,
+        cmd if takes_char(&cmd) => {
+            state.pending_arg = Some(cmd);
+            Command::Pending
         }
+        Command::CaseOperator(case) => {
+            state.pending_op = Some(Command::CaseOperator(case));
+            Command::Pending
+        }
+        cmd => cmd,
+    }
+}
+
+/// Commands that operators can work over.
+fn is_motion(cmd: &Command) -> bool {
+    match *cmd {
+        Command::MoveLeft(_) |
+        Command::MoveRight(_) |
+        Command::MoveUp(_) |
+        Command::MoveDown(_) |
+        Command::LineStart |
+        Command::LineEnd => true,
+        _ => false,
+    }
+}
+
+/// The key that repeats an operator to work on lines.
+fn line_key(op: &Command) -> Option<Key> {
+    match *op {
+        Command::CaseOperator(Case::Lower) => Some(Key::Char('u')),
+        Command::CaseOperator(Case::Upper) => Some(Key::Char('U')),
+        Command::CaseOperator(Case::Toggle) => Some(Key::Char('~')),
+        _ => None,
+    }
+}
+
+fn with_motion(op: Command, motion: Command) -> Command {
+    match op {
+        Command::CaseOperator(case) => Command::ChangeCase(case, Box::new(motion)),
+        _ => Command::Unknown,
+    }
+}
+
+/// Commands that take the next typed character as an argument.
+fn takes_char(cmd: &Command) -> bool {
+    match *cmd {
+        Command::Record(_) |
+        Command::Replay(_, _) |
+        Command::SetMark(_) |
+        Command::JumpMark(_) |
+        Command::JumpMarkLine(_) |
+        Command::ReplaceChar(_, _) => true,
+        _ => false,
+    }
+}
+
+fn with_char(cmd: Command, c: char) -> Command {
+    match cmd {
+        Command::Record(_) => Command::Record(c),
+        Command::Replay(_, n) => Command::Replay(c, n),
+        Command::SetMark(_) => Command::SetMark(c),
+        Command::JumpMark(_) => Command::JumpMark(c),
+        Command::JumpMarkLine(_) => Command::JumpMarkLine(c),
+        Command::ReplaceChar(n, _) => Command::ReplaceChar(n, c),
+        cmd => cmd,
+    }
+}
+
+/// Keys that aren't in the tables: typed text and count digits.
+fn unbound(mode: Mode, keys: &[Key]) -> Command {
+    if let Mode::Terminal = mode {
+        // Everything else goes to the shell.
+        let text: String = keys.iter().filter_map(|&k| term::key_text(k)).collect();
+        return Command::TermSend(text);
+    }
+    if keys.len() != 1 {
+        return Command::Unknown;
+    }
+    match (mode, keys[0]) {
+        (Mode::Insert, Key::Char(c)) => Command::Insert(c),
+        (Mode::Replace, Key::Char(c)) => Command::Overwrite(c),
+        (Mode::Command, Key::Char(c)) => Command::CmdlineInsert(c.to_string()),
+        (Mode::Normal, Key::Char(d)) if d.is_digit(10) => Command::Digit(d.to_digit(10).unwrap()),
+        _ => Command::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keymap::Binding;
+
+    #[test]
+    fn keeps_keys_that_dont_match() {
+        let mut s = state::State::new();
+        let jk = vec![Key::Char('j'), Key::Char('k')];
+        s.keymap.bind(Mode::Insert, jk, Binding::Cmd(Command::ChangeMode(Mode::Normal)));
+        assert!(match map(Mode::Insert, Key::Char('j'), &mut s) {
+            Command::Pending => true,
+            _ => false,
+        });
+        assert!(match map(Mode::Insert, Key::Char('a'), &mut s) {
+            Command::Insert('j') => true,
+            _ => false,
+        });
+        assert_eq!(s.typeahead.pop_front(), Some(Key::Char('a')));
+        map(Mode::Insert, Key::Char('j'), &mut s);
+        assert!(match flush(Mode::Insert, &mut s) {
+            Command::Insert('j') => true,
+            _ => false,
+        });
     }
 }
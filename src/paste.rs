@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use rustbox::Key;
+
+/// Sent by the terminal around pasted text when bracketed paste is on.
+const PASTE_START: &'static str = "\x1b[200~";
+const PASTE_END: &'static str = "\x1b[201~";
+
+/// Escape sequences arrive all at once, so a held Esc that isn't followed
+/// by the rest of a marker this quickly was typed by a person.
+pub const TIMEOUT_MS: u64 = 25;
+
+pub fn timeout() -> Duration {
+    Duration::from_millis(TIMEOUT_MS)
+}
+
+/// Watches the key stream for bracketed paste markers.
+pub struct Paste {
+    /// Whether we're between the start and end markers.
+    pub pasting: bool,
+    /// Keys that so far match the start of a marker.
+    held: Vec<Key>,
+    /// Text pasted so far.
+    text: String,
+}
+
+pub enum Feed {
+    /// The key is part of a paste or a marker. Nothing to do yet.
+    Held,
+    /// The end marker arrived. Here's everything in between.
+    Done(String),
+    /// Not a paste after all. Handle these keys normally.
+    Keys(Vec<Key>),
+}
+
+impl Paste {
+    pub fn new() -> Paste {
+        Paste {
+            pasting: false,
+            held: Vec::new(),
+            text: String::new(),
+        }
+    }
+
+    /// Whether some keys are held waiting for the rest of a marker.
+    pub fn holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    pub fn feed(&mut self, key: Key) -> Feed {
+        let marker = match self.pasting {
+            true => PASTE_END,
+            false => PASTE_START,
+        };
+        self.held.push(key);
+        if is_prefix(&self.held, marker) {
+            if self.held.len() == marker.chars().count() {
+                self.held.clear();
+                self.pasting = !self.pasting;
+                if !self.pasting {
+                    return Feed::Done(self.text.drain(..).collect());
+                }
+            }
+            return Feed::Held;
+        }
+        let mut keys: Vec<Key> = self.held.drain(..).collect();
+        // The key that broke the match could still start a marker.
+        if keys.len() > 1 && is_prefix(&[key], marker) {
+            self.held.push(key);
+            keys.pop();
+        }
+        if !self.pasting {
+            return Feed::Keys(keys);
+        }
+        for k in keys {
+            match k {
+                Key::Char(c) => self.text.push(c),
+                Key::Enter => self.text.push('\n'),
+                Key::Tab => self.text.push('\t'),
+                Key::Esc => self.text.push('\x1b'),
+                _ => {}
+            }
+        }
+        Feed::Held
+    }
+
+    /// Give up waiting for the rest of a marker.
+    pub fn flush(&mut self) -> Vec<Key> {
+        self.held.drain(..).collect()
+    }
+}
+
+fn is_prefix(keys: &[Key], marker: &str) -> bool {
+    keys.len() <= marker.chars().count() &&
+    keys.iter().zip(marker.chars()).all(|(k, m)| {
+        match *k {
+            Key::Esc => m == '\x1b',
+            Key::Char(c) => m == c,
+            _ => false,
+        }
+    })
+}
@@ -0,0 +1,141 @@
+use mode::{Command, Mode};
+
+/// Remembers the last change to a buffer so `.` can do it again.
+pub struct Repeat {
+    last: Vec<Command>,
+    /// Commands of the Insert mode session in progress, starting with the
+    /// command that entered Insert mode.
+    insert: Option<Vec<Command>>,
+}
+
+impl Repeat {
+    pub fn new() -> Repeat {
+        Repeat {
+            last: Vec::new(),
+            insert: None,
+        }
+    }
+
+    /// Look at every command run. A change made in Normal mode replaces the
+    /// last change right away. An Insert mode session replaces it when it
+    /// ends, if anything in it changed the buffer.
+    pub fn observe(&mut self, cmd: &Command) {
+        match *cmd {
+            Command::ChangeMode(Mode::Insert) |
+            Command::ChangeMode(Mode::Replace) |
+            Command::Append |
+            Command::AppendLine |
+            Command::InsertLineStart |
+            Command::OpenLine(_) => {
+                self.insert = Some(vec![cmd.clone()]);
+            }
+            Command::ChangeMode(Mode::Normal) => {
+                if let Some(mut cmds) = self.insert.take() {
+                    if cmds.iter().any(changes_buffer) {
+                        cmds.push(cmd.clone());
+                        self.last = cmds;
+                    }
+                }
+            }
+            _ => {
+                match self.insert {
+                    Some(ref mut cmds) => {
+                        if changes_buffer(cmd) || moves_cursor(cmd) {
+                            cmds.push(cmd.clone());
+                        }
+                    }
+                    None => {
+                        if changes_buffer(cmd) {
+                            self.last = vec![cmd.clone()];
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn last(&self) -> Vec<Command> {
+        self.last.clone()
+    }
+}
+
+pub fn changes_buffer(cmd: &Command) -> bool {
+    match *cmd {
+        Command::Insert(_) |
+        Command::Delete(_) |
+        Command::DeleteWord |
+        Command::NewLine |
+        Command::Paste(_) |
+        Command::Complete(_) |
+        Command::TabComplete |
+        Command::Indent(_) |
+        Command::Dedent(_) |
+        Command::Reindent(_) |
+        Command::ToggleComment(_) |
+        Command::ToggleCase(_) |
+        Command::ChangeCase(_, _) |
+        Command::Join(_) |
+        Command::ReplaceChar(_, _) |
+        Command::Overwrite(_) |
+        Command::Increment(_) |
+        Command::OpenLine(_) => true,
+        _ => false,
+    }
+}
+
+fn moves_cursor(cmd: &Command) -> bool {
+    match *cmd {
+        Command::MoveLeft(_) |
+        Command::MoveRight(_) |
+        Command::MoveUp(_) |
+        Command::MoveDown(_) |
+        Command::LineStart |
+        Command::LineEnd => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observe_all(repeat: &mut Repeat, cmds: Vec<Command>) {
+        for cmd in &cmds {
+            repeat.observe(cmd);
+        }
+    }
+
+    #[test]
+    fn repeats_normal_mode_changes() {
+        let mut repeat = Repeat::new();
+        observe_all(&mut repeat, vec![Command::MoveDown(1), Command::Indent(2), Command::MoveUp(1)]);
+        assert_eq!(format!("{:?}", repeat.last()), "[Indent(2)]");
+    }
+
+    #[test]
+    fn repeats_whole_insert_sessions() {
+        let mut repeat = Repeat::new();
+        observe_all(&mut repeat,
+                    vec![Command::ChangeMode(Mode::Insert),
+                         Command::Insert('a'),
+                         Command::MoveLeft(1),
+                         Command::Insert('b'),
+                         Command::ChangeMode(Mode::Normal)]);
+        assert_eq!(format!("{:?}", repeat.last()),
+                   "[ChangeMode(Insert), Insert('a'), MoveLeft(1), Insert('b'), ChangeMode(Normal)]");
+        // Normal mode closed the session, so this is a change of its own.
+        repeat.observe(&Command::Indent(1));
+        assert_eq!(format!("{:?}", repeat.last()), "[Indent(1)]");
+    }
+
+    #[test]
+    fn keeps_the_last_change_after_an_empty_insert() {
+        let mut repeat = Repeat::new();
+        observe_all(&mut repeat,
+                    vec![Command::Indent(1),
+                         Command::Append,
+                         Command::MoveRight(1),
+                         Command::ChangeMode(Mode::Normal)]);
+        assert_eq!(format!("{:?}", repeat.last()), "[Indent(1)]");
+    }
+}
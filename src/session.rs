@@ -0,0 +1,381 @@
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use buffer::Buffer;
+use errors::{CrbError, CrbResult};
+use geometry::{Point, Size};
+use marks;
+use mode::{Command, Mode};
+use state;
+use state::State;
+use window::Window;
+
+/// The session saved on quit and restored when crb starts without a file.
+pub const DEFAULT: &'static str = "default";
+
+/// The main, output and debug windows, which are always there.
+const FIXED_WINDOWS: usize = 3;
+
+/// Everything needed to put a window back the way it was.
+pub struct WindowState {
+    pub topleft: Point,
+    pub size: Size,
+    pub scroll: i32,
+    pub mode: Mode,
+    pub path: Option<String>,
+    pub cursors: Vec<(i32, i32)>,
+    /// The buffer as it was, unsaved edits and all, instead of reading the file.
+    pub snapshot: Option<Snapshot>,
+}
+
+pub struct Snapshot {
+    pub text: String,
+    pub unsaved: bool,
+    pub newfile: bool,
+    pub marks: Vec<marks::Saved>,
+}
+
+impl Snapshot {
+    fn empty() -> Snapshot {
+        Snapshot {
+            text: String::new(),
+            unsaved: false,
+            newfile: false,
+            marks: Vec::new(),
+        }
+    }
+}
+
+pub struct Session {
+    pub windows: Vec<WindowState>,
+    pub focused: usize,
+    pub search: Option<String>,
+}
+
+/// Sessions live in $HOME/.crb_sessions/<name>.
+fn session_path(name: &str) -> CrbResult<String> {
+    let home = try!(env::var("HOME").map_err(|_| CrbError::new("no $HOME for sessions")));
+    let dir = home + "/.crb_sessions";
+    try!(fs::create_dir_all(&dir)
+        .map_err(|e| CrbError::new(&format!("error creating {}: {}", dir, e))));
+    Ok(dir + "/" + name)
+}
+
+/// Where a crb about to restart leaves everything for the new process.
+pub fn restart_path() -> String {
+    env::temp_dir().join(format!("crb-restart-{}", process::id())).to_string_lossy().into_owned()
+}
+
+pub fn exists(name: &str) -> bool {
+    session_path(name).map(|p| fs::metadata(p).is_ok()).unwrap_or(false)
+}
+
+/// Take down the layout, and with `snapshot` the text of every buffer too.
+pub fn capture(windows: &[Window],
+               focused: usize,
+               state: &Arc<Mutex<State>>,
+               snapshot: bool)
+               -> CrbResult<Session> {
+    let mut ws = Vec::new();
+    // Terminals don't outlive crb. Their space goes back to the main
+    // window they were split from.
+    let extra: i32 = windows.iter().filter(|w| w.is_terminal()).map(|w| w.size.height).sum();
+    let focused = match windows[focused].is_terminal() {
+        true => 0,
+        false => windows[..focused].iter().filter(|w| !w.is_terminal()).count(),
+    };
+    for (i, w) in windows.iter().enumerate().filter(|&(_, w)| !w.is_terminal()) {
+        let (path, snap) = {
+            let buf = w.buf.lock().unwrap();
+            let snap = if snapshot {
+                Some(Snapshot {
+                    text: buf.text(),
+                    unsaved: buf.unsaved,
+                    newfile: buf.newfile,
+                    marks: try!(buf.marks()),
+                })
+            } else {
+                None
+            };
+            (buf.file_path.clone(), snap)
+        };
+        ws.push(WindowState {
+            topleft: w.topleft,
+            size: match i {
+                0 => Size::new(w.size.width, w.size.height + extra),
+                _ => w.size,
+            },
+            scroll: w.scroll,
+            mode: w.mode,
+            path: path,
+            cursors: try!(w.cursor_positions()),
+            snapshot: snap,
+        });
+    }
+    Ok(Session {
+        windows: ws,
+        focused: focused,
+        search: state::do_safe(&**state, |s| s.search.clone()),
+    })
+}
+
+/// Open the session's files in new windows and put the state back.
+pub fn restore(session: &Session, state: &Arc<Mutex<State>>) -> CrbResult<Vec<Window>> {
+    state::do_safe(&**state, |s| {
+        s.next_window_index = 1;
+        s.search = session.search.clone();
+    });
+    let mut windows = Vec::new();
+    for ws in session.windows.iter() {
+        let buf = match (&ws.snapshot, &ws.path) {
+            (&Some(ref snap), path) => {
+                let mut buf = Buffer::from_text(&snap.text, state.clone());
+                buf.file_path = path.clone();
+                buf.unsaved = snap.unsaved;
+                buf.newfile = snap.newfile;
+                for &(name, line, offset) in snap.marks.iter() {
+                    buf.set_mark(name, line, offset);
+                }
+                buf
+            }
+            (&None, &Some(ref path)) => Buffer::open(path, state.clone()),
+            (&None, &None) => Buffer::empty(state.clone()),
+        };
+        let mut w = Window::new(Mutex::new(buf), ws.topleft, ws.size, state.clone());
+        w.mode = ws.mode;
+        try!(w.set_cursor_positions(&ws.cursors));
+        try!(w.scroll(&Command::Scroll(ws.scroll)));
+        windows.push(w);
+    }
+    if windows.len() < FIXED_WINDOWS {
+        return Err(CrbError::new(&format!("session needs {} windows", FIXED_WINDOWS)));
+    }
+    Ok(windows)
+}
+
+pub fn save(name: &str, session: &Session) -> CrbResult<()> {
+    write(&try!(session_path(name)), session)
+}
+
+pub fn load(name: &str) -> CrbResult<Session> {
+    read(&try!(session_path(name)))
+}
+
+pub fn write(path: &str, session: &Session) -> CrbResult<()> {
+    let out = to_text(session);
+    let mut f = try!(fs::File::create(path)
+        .map_err(|e| CrbError::new(&format!("error saving session {}: {}", path, e))));
+    f.write_all(out.as_bytes())
+        .map_err(|e| CrbError::new(&format!("error saving session {}: {}", path, e)))
+}
+
+fn to_text(session: &Session) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("focus {}\n", session.focused));
+    if let Some(ref search) = session.search {
+        out.push_str(&format!("search {}\n", search));
+    }
+    for w in session.windows.iter() {
+        let mode = match w.mode {
+            Mode::Insert => "insert",
+            _ => "normal",
+        };
+        out.push_str(&format!("window {} {} {} {} {} {}\n",
+                              w.topleft.x,
+                              w.topleft.y,
+                              w.size.width,
+                              w.size.height,
+                              w.scroll,
+                              mode));
+        if let Some(ref p) = w.path {
+            out.push_str(&format!("path {}\n", p));
+        }
+        for &(line, offset) in w.cursors.iter() {
+            out.push_str(&format!("cursor {} {}\n", line, offset));
+        }
+        if let Some(ref snap) = w.snapshot {
+            for &(name, line, offset) in snap.marks.iter() {
+                out.push_str(&format!("mark {} {} {}\n", name, line, offset));
+            }
+            // The text follows its line count verbatim.
+            out.push_str(&format!("text {} {} {}\n",
+                                  snap.unsaved as i32,
+                                  snap.newfile as i32,
+                                  snap.text.split('\n').count()));
+            out.push_str(&snap.text);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+pub fn read(path: &str) -> CrbResult<Session> {
+    let mut contents = String::new();
+    try!(fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| CrbError::new(&format!("error loading session {}: {}", path, e))));
+    parse(&contents).map_err(|e| CrbError::new(&format!("bad session {}: {}", path, e)))
+}
+
+fn parse(contents: &str) -> Result<Session, String> {
+    let mut session = Session {
+        windows: Vec::new(),
+        focused: 0,
+        search: None,
+    };
+    // Whether each window's snapshot has had its text line, since marks can come first.
+    let mut has_text = Vec::new();
+    let mut lines = contents.split('\n');
+    while let Some(line) = lines.next() {
+        let (word, rest) = match line.find(' ') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        let nums: Vec<i32> = rest.split_whitespace().filter_map(|n| n.parse().ok()).collect();
+        match word {
+            "focus" if nums.len() == 1 => session.focused = nums[0] as usize,
+            "search" => session.search = Some(rest.to_string()),
+            "window" if nums.len() == 5 => {
+                let mode = match rest.split_whitespace().last() {
+                    Some("insert") => Mode::Insert,
+                    _ => Mode::Normal,
+                };
+                session.windows.push(WindowState {
+                    topleft: Point::new(nums[0], nums[1]),
+                    size: Size::new(nums[2], nums[3]),
+                    scroll: nums[4],
+                    mode: mode,
+                    path: None,
+                    cursors: Vec::new(),
+                    snapshot: None,
+                });
+                has_text.push(false);
+            }
+            "path" => {
+                try!(session.windows.last_mut().ok_or("path before window")).path =
+                    Some(rest.to_string())
+            }
+            "cursor" if nums.len() == 2 => {
+                try!(session.windows.last_mut().ok_or("cursor before window"))
+                    .cursors
+                    .push((nums[0], nums[1]))
+            }
+            "mark" => {
+                // The name may itself be a digit, so it can't go through nums.
+                let mut parts = rest.split(' ');
+                let name = parts.next().and_then(|n| n.chars().next());
+                let pos: Vec<i32> = parts.filter_map(|n| n.parse().ok()).collect();
+                let mark = match (name, pos.len()) {
+                    (Some(name), 2) => (name, pos[0], pos[1]),
+                    _ => return Err(format!("bad line: {}", line)),
+                };
+                let w = try!(session.windows.last_mut().ok_or("mark before window"));
+                let mut snap = w.snapshot.take().unwrap_or(Snapshot::empty());
+                snap.marks.push(mark);
+                w.snapshot = Some(snap);
+            }
+            "text" if nums.len() == 3 => {
+                let w = try!(session.windows.last_mut().ok_or("text before window"));
+                let text: Vec<&str> = lines.by_ref().take(nums[2] as usize).collect();
+                if text.len() != nums[2] as usize {
+                    return Err("text cut short".to_string());
+                }
+                let mut snap = w.snapshot.take().unwrap_or(Snapshot::empty());
+                snap.text = text.join("\n");
+                snap.unsaved = nums[0] != 0;
+                snap.newfile = nums[1] != 0;
+                w.snapshot = Some(snap);
+                has_text[session.windows.len() - 1] = true;
+            }
+            "" => {}
+            _ => return Err(format!("bad line: {}", line)),
+        }
+    }
+    if session.windows.iter().zip(&has_text).any(|(w, &text)| w.snapshot.is_some() && !text) {
+        return Err("marks without text".to_string());
+    }
+    if session.windows.len() < FIXED_WINDOWS {
+        return Err(format!("{} windows, not at least {}", session.windows.len(), FIXED_WINDOWS));
+    }
+    if session.focused >= session.windows.len() {
+        session.focused = 0;
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let session = Session {
+            windows: vec![WindowState {
+                              topleft: Point::new(0, 0),
+                              size: Size::new(80, 20),
+                              scroll: 3,
+                              mode: Mode::Insert,
+                              path: Some("src/my file.rs".to_string()),
+                              cursors: vec![(4, 2), (7, 0)],
+                              snapshot: Some(Snapshot {
+                                  text: "window 1 2 3 4 5 normal\n\nend".to_string(),
+                                  unsaved: true,
+                                  newfile: false,
+                                  marks: vec![('a', 1, 2), ('3', 0, 0)],
+                              }),
+                          },
+                          WindowState {
+                              topleft: Point::new(0, 20),
+                              size: Size::new(80, 4),
+                              scroll: 0,
+                              mode: Mode::Normal,
+                              path: None,
+                              cursors: vec![(0, 0)],
+                              snapshot: None,
+                          },
+                          WindowState {
+                              topleft: Point::new(40, 2),
+                              size: Size::new(39, 4),
+                              scroll: 0,
+                              mode: Mode::Normal,
+                              path: None,
+                              cursors: vec![(0, 0)],
+                              snapshot: None,
+                          }],
+            focused: 1,
+            search: Some("two words".to_string()),
+        };
+        let back = parse(&to_text(&session)).unwrap();
+        assert_eq!(back.focused, 1);
+        assert_eq!(back.search, Some("two words".to_string()));
+        assert_eq!(back.windows.len(), 3);
+        let w = &back.windows[0];
+        assert_eq!((w.topleft.y, w.size.width, w.size.height, w.scroll), (0, 80, 20, 3));
+        assert!(match w.mode {
+            Mode::Insert => true,
+            _ => false,
+        });
+        assert_eq!(w.path, Some("src/my file.rs".to_string()));
+        assert_eq!(w.cursors, vec![(4, 2), (7, 0)]);
+        let snap = w.snapshot.as_ref().unwrap();
+        assert_eq!(snap.text, "window 1 2 3 4 5 normal\n\nend");
+        assert_eq!((snap.unsaved, snap.newfile), (true, false));
+        assert_eq!(snap.marks, vec![('a', 1, 2), ('3', 0, 0)]);
+        assert_eq!(back.windows[1].topleft.y, 20);
+        assert!(back.windows[1].snapshot.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_sessions() {
+        assert!(parse("path x").is_err());
+        assert!(parse("window 0 0 80 20 0 normal\ntext 0 0 3\nonly one").is_err());
+        assert!(parse("nonsense").is_err());
+        let window = "window 0 0 80 20 0 normal\n";
+        assert!(parse(&window.repeat(2)).is_err());
+        assert_eq!(parse(&format!("focus 5\n{}", window.repeat(3))).unwrap().focused, 0);
+        assert!(parse(&format!("{}mark a 1 2\n{}", window, window.repeat(2))).is_err());
+    }
+}
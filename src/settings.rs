@@ -15,16 +15,79 @@ pub struct Settings {
     pub insertSpaces: bool, // False => tab
     pub numSpacesPerTab: usize,
     pub tabWidth: usize,
+    /// Colors of text that language servers found errors and warnings in.
+    pub errorColor: Color,
+    pub warningColor: Color,
+    /// Color of the bracket matching the one under the cursor.
+    pub matchColor: Color,
+    /// Color of the line a closed fold shows instead of its text.
+    pub foldColor: Color,
+    /// Draw tabs and non-breaking spaces as glyphs.
+    pub showWhitespace: bool,
+    /// Draw whitespace at the end of lines as glyphs, in `trailingColor`.
+    pub highlightTrailing: bool,
+    pub tabGlyph: char,
+    pub trailGlyph: char,
+    pub nbspGlyph: char,
+    pub whitespaceColor: Color,
+    pub trailingColor: Color,
+    /// Strip whitespace from the end of lines on save.
+    pub trimOnSave: bool,
+    /// Redo indentation in tabs or spaces, whichever `insertSpaces` says,
+    /// on save.
+    pub retabOnSave: bool,
+    /// Directories whose .crbrc may set commands to run.
+    pub trustedDirs: Vec<String>,
+    /// Shell command run by the build command.
+    pub buildCommand: String,
+    /// Commands that format a filetype's text from stdin to stdout, run on
+    /// every save.
+    pub formatters: HashMap<String, String>,
+    /// Language server commands by filetype, talked to over stdio.
+    pub languageServers: HashMap<String, String>,
+    /// Brackets and quotes that Insert mode closes by itself, by filetype,
+    /// as openers each followed by their closer: `()[]{}`. `*` is for
+    /// filetypes that aren't listed.
+    pub autoPairs: HashMap<String, String>,
+    /// How to find a fold to close where there isn't one, by filetype:
+    /// `manual` (don't), `indent` or `syntax` (by brackets). `*` is for
+    /// filetypes that aren't listed.
+    pub foldMethods: HashMap<String, String>,
 }
 
 impl Settings {
     pub fn new() -> Settings {
-        return Settings {
+        let mut s = Settings {
             lineNumColor: Color::Yellow,
             insertSpaces: false,
             numSpacesPerTab: 4,
             tabWidth: 4,
+            errorColor: Color::Red,
+            warningColor: Color::Yellow,
+            matchColor: Color::Cyan,
+            foldColor: Color::Magenta,
+            showWhitespace: false,
+            highlightTrailing: false,
+            tabGlyph: '→',
+            trailGlyph: '·',
+            nbspGlyph: '␣',
+            whitespaceColor: Color::Blue,
+            trailingColor: Color::Red,
+            trimOnSave: false,
+            retabOnSave: false,
+            trustedDirs: Vec::new(),
+            buildCommand: "make".to_string(),
+            formatters: HashMap::new(),
+            languageServers: HashMap::new(),
+            autoPairs: HashMap::new(),
+            foldMethods: HashMap::new(),
         };
+        s.autoPairs.insert("*".to_string(), "()[]{}\"\"''".to_string());
+        s.autoPairs.insert("rust".to_string(), "()[]{}\"\"".to_string());
+        s.foldMethods.insert("*".to_string(), "syntax".to_string());
+        s.foldMethods.insert("python".to_string(), "indent".to_string());
+        s.foldMethods.insert("markdown".to_string(), "manual".to_string());
+        s
     }
 
     // pub fn get(&self, setting: &str) -> Option<&Value> {
@@ -1,11 +1,49 @@
+use rustbox::Key;
 use settings::Settings;
+use build::Build;
+use jobs::{JobId, Jobs};
+use keymap::Keymap;
+use lsp::Lsp;
+use complete::Completion;
+use macros::Macros;
+use mode::Command;
+use repeat::Repeat;
+use paste;
+use paste::Paste;
+use std::collections::VecDeque;
 use std::sync::Mutex;
+use std::time::Duration;
 
 pub struct State {
     pub settings: Settings,
     pub next_window_index: i32,
     pub num_prefix: u32,
     pub typing_num_prefix: bool,
+    pub keymap: Keymap,
+    /// Keys typed so far of an unfinished sequence.
+    pub pending_keys: Vec<Key>,
+    pub paste: Paste,
+    /// Keys to handle before reading more from the terminal.
+    pub typeahead: VecDeque<Key>,
+    /// A command waiting for a character argument, like the register for q.
+    pub pending_arg: Option<Command>,
+    /// An operator waiting for a motion, like `gu`.
+    pub pending_op: Option<Command>,
+    pub macros: Macros,
+    pub repeat: Repeat,
+    /// The last thing searched for.
+    pub search: Option<String>,
+    pub jobs: Jobs,
+    /// The job started with `:!`, if it's still running.
+    pub shell_job: Option<JobId>,
+    /// What's been typed after `:`.
+    pub cmdline: String,
+    /// Shown on the bottom row until the next key, e.g. errors.
+    pub message: Option<String>,
+    pub build: Build,
+    pub lsp: Lsp,
+    /// The completion going on in Insert mode, if any.
+    pub completion: Option<Completion>,
 }
 
 impl State {
@@ -15,6 +53,22 @@ impl State {
             next_window_index: 1,
             num_prefix: 1,
             typing_num_prefix: false,
+            keymap: Keymap::new(),
+            pending_keys: Vec::new(),
+            paste: Paste::new(),
+            typeahead: VecDeque::new(),
+            pending_arg: None,
+            pending_op: None,
+            macros: Macros::new(),
+            repeat: Repeat::new(),
+            search: None,
+            jobs: Jobs::new(),
+            shell_job: None,
+            cmdline: String::new(),
+            message: None,
+            build: Build::new(),
+            lsp: Lsp::new(),
+            completion: None,
         };
     }
 
@@ -31,6 +85,18 @@ impl State {
         self.typing_num_prefix = false;
         self.num_prefix = 1;
     }
+
+    /// How long to wait for the next key before giving up on what's been
+    /// typed so far. None means wait forever.
+    pub fn input_timeout(&self) -> Option<Duration> {
+        if self.paste.holding() {
+            Some(paste::timeout())
+        } else if self.paste.pasting || self.pending_keys.is_empty() {
+            None
+        } else {
+            Some(self.keymap.timeout)
+        }
+    }
 }
 
 pub fn do_safe<F, T>(statelock: &Mutex<State>, func: F) -> T
@@ -0,0 +1,496 @@
+use std::cmp;
+use std::env;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::process::{Child, Stdio};
+use std::str;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+use libc;
+use rustbox::Key;
+
+use errors::{CrbError, CrbResult};
+
+/// A shell running in a pseudo-terminal, and what it has drawn so far.
+pub struct Terminal {
+    master: File,
+    child: Child,
+    output: Receiver<Vec<u8>>,
+    /// Bytes of a character split between two reads.
+    partial: Vec<u8>,
+    pub screen: Screen,
+    pub exited: bool,
+}
+
+impl Terminal {
+    /// Start $SHELL in a terminal of the given size.
+    pub fn spawn(rows: usize, cols: usize) -> CrbResult<Terminal> {
+        let (master, slave) = try!(open_pty(rows, cols));
+        let shell = env::var("SHELL").unwrap_or("/bin/sh".to_string());
+        let err = |e| CrbError::new(&format!("error starting terminal: {}", e));
+        let child = try!(unsafe {
+                process::Command::new(&shell)
+                    .stdin(Stdio::from_raw_fd(try!(slave.try_clone().map_err(&err)).into_raw_fd()))
+                    .stdout(Stdio::from_raw_fd(try!(slave.try_clone().map_err(&err)).into_raw_fd()))
+                    .stderr(Stdio::from_raw_fd(slave.into_raw_fd()))
+                    .env("TERM", "vt100")
+                    .pre_exec(|| {
+                        // Give the shell a session of its own with the pty
+                        // as its controlling terminal, so job control works.
+                        libc::setsid();
+                        libc::ioctl(0, libc::TIOCSCTTY, 0);
+                        Ok(())
+                    })
+                    .spawn()
+            }
+            .map_err(&err));
+        let mut reader = try!(master.try_clone().map_err(&err));
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(Terminal {
+            master: master,
+            child: child,
+            output: rx,
+            partial: Vec::new(),
+            screen: Screen::new(rows, cols),
+            exited: false,
+        })
+    }
+
+    /// Draw what the shell printed since last time. Returns whether
+    /// anything changed.
+    pub fn update(&mut self) -> bool {
+        let mut changed = false;
+        loop {
+            match self.output.try_recv() {
+                Ok(bytes) => {
+                    self.partial.extend(bytes);
+                    let text = take_utf8(&mut self.partial);
+                    self.screen.feed(&text);
+                    changed = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if !self.exited {
+                        self.exited = true;
+                        let _ = self.child.wait();
+                        changed = true;
+                    }
+                    break;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Type into the shell.
+    pub fn send(&mut self, text: &str) -> CrbResult<()> {
+        self.master
+            .write_all(text.as_bytes())
+            .map_err(|e| CrbError::new(&format!("error writing to terminal: {}", e)))
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if !self.exited {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+fn open_pty(rows: usize, cols: usize) -> CrbResult<(File, File)> {
+    let err = |what: &str| CrbError::new(&format!("error opening pty: {} failed", what));
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(err("posix_openpt"));
+        }
+        let master = File::from_raw_fd(fd);
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(err("grantpt"));
+        }
+        let mut name = [0 as libc::c_char; 128];
+        if libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+            return Err(err("ptsname"));
+        }
+        let path = try!(CStr::from_ptr(name.as_ptr()).to_str().map_err(|_| err("ptsname")));
+        let slave = try!(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)
+            .map_err(|_| err("opening the slave")));
+        let size = libc::winsize {
+            ws_row: rows as u16,
+            ws_col: cols as u16,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        if libc::ioctl(fd, libc::TIOCSWINSZ, &size) != 0 {
+            return Err(err("setting the size"));
+        }
+        Ok((master, slave))
+    }
+}
+
+/// The complete characters at the start of some bytes, leaving the start of
+/// a split character behind. Bytes that aren't UTF-8 become U+FFFD.
+fn take_utf8(bytes: &mut Vec<u8>) -> String {
+    let valid = match str::from_utf8(bytes) {
+        Ok(_) => bytes.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    let split = bytes.len() - valid < 4 && valid < bytes.len() &&
+                str::from_utf8(&bytes[valid..]).is_err();
+    let end = if split { valid } else { bytes.len() };
+    let text = String::from_utf8_lossy(&bytes[..end]).into_owned();
+    bytes.drain(..end);
+    text
+}
+
+/// What a key sends to a program running in a terminal.
+pub fn key_text(key: Key) -> Option<String> {
+    let s = match key {
+        Key::Char(c) => return Some(c.to_string()),
+        Key::Ctrl(c) => {
+            let code = (c.to_ascii_lowercase() as u8) & 0x1f;
+            return Some((code as char).to_string());
+        }
+        Key::Enter => "\r",
+        Key::Tab => "\t",
+        Key::Backspace => "\x7f",
+        Key::Esc => "\x1b",
+        Key::Up => "\x1b[A",
+        Key::Down => "\x1b[B",
+        Key::Right => "\x1b[C",
+        Key::Left => "\x1b[D",
+        Key::Home => "\x1b[H",
+        Key::End => "\x1b[F",
+        Key::Delete => "\x1b[3~",
+        Key::Insert => "\x1b[2~",
+        Key::PageUp => "\x1b[5~",
+        Key::PageDown => "\x1b[6~",
+        _ => return None,
+    };
+    Some(s.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Parse {
+    Text,
+    /// After ESC.
+    Escape,
+    /// In `ESC [ params final`.
+    Csi,
+    /// In `ESC ] ... BEL`, like a window title. Skipped.
+    Osc,
+    /// After `ESC (` and the like, which pick a character set. Skipped.
+    Charset,
+}
+
+/// The characters on a terminal's screen. Understands enough of what a
+/// vt100 does for shells and compilers: moving the cursor, erasing and
+/// scrolling. Colors and the like are ignored.
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    cells: Vec<Vec<char>>,
+    pub row: usize,
+    pub col: usize,
+    parse: Parse,
+    params: String,
+}
+
+impl Screen {
+    pub fn new(rows: usize, cols: usize) -> Screen {
+        Screen {
+            rows: rows,
+            cols: cols,
+            cells: vec![vec![' '; cols]; rows],
+            row: 0,
+            col: 0,
+            parse: Parse::Text,
+            params: String::new(),
+        }
+    }
+
+    /// The screen as lines, without trailing blanks except up to the
+    /// cursor, so it can be shown where it is.
+    pub fn text(&self) -> String {
+        let lines: Vec<String> = self.cells
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let line: String = row.iter().cloned().collect();
+                let trimmed = line.trim_right();
+                let keep = match i == self.row {
+                    true => cmp::max(trimmed.chars().count(), self.col),
+                    false => trimmed.chars().count(),
+                };
+                row.iter().take(keep).cloned().collect()
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    pub fn feed(&mut self, text: &str) {
+        for c in text.chars() {
+            match self.parse {
+                Parse::Text => self.text_char(c),
+                Parse::Escape => {
+                    self.parse = Parse::Text;
+                    match c {
+                        '[' => {
+                            self.params.clear();
+                            self.parse = Parse::Csi;
+                        }
+                        ']' => self.parse = Parse::Osc,
+                        '(' | ')' => self.parse = Parse::Charset,
+                        'M' => self.reverse_index(),
+                        'D' => self.line_feed(),
+                        'E' => {
+                            self.col = 0;
+                            self.line_feed();
+                        }
+                        'c' => *self = Screen::new(self.rows, self.cols),
+                        _ => {}
+                    }
+                }
+                Parse::Csi => {
+                    if c.is_digit(10) || c == ';' || c == '?' || c == '>' {
+                        self.params.push(c);
+                    } else {
+                        self.parse = Parse::Text;
+                        self.csi(c);
+                    }
+                }
+                Parse::Osc => {
+                    match c {
+                        '\x07' => self.parse = Parse::Text,
+                        // ESC \ ends it too; the backslash is dropped then.
+                        '\x1b' => self.parse = Parse::Escape,
+                        _ => {}
+                    }
+                }
+                Parse::Charset => self.parse = Parse::Text,
+            }
+        }
+    }
+
+    fn text_char(&mut self, c: char) {
+        match c {
+            '\x1b' => self.parse = Parse::Escape,
+            '\r' => self.col = 0,
+            '\n' | '\x0b' | '\x0c' => self.line_feed(),
+            '\x08' => self.col = self.col.saturating_sub(1),
+            '\t' => self.col = cmp::min(self.cols - 1, (self.col / 8 + 1) * 8),
+            c if (c as u32) < 0x20 || c == '\x7f' => {}
+            c => {
+                if self.col >= self.cols {
+                    self.col = 0;
+                    self.line_feed();
+                }
+                self.cells[self.row][self.col] = c;
+                self.col += 1;
+            }
+        }
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll_up(1);
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.row > 0 {
+            self.row -= 1;
+        } else {
+            self.scroll_down(1);
+        }
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        for _ in 0..cmp::min(n, self.rows) {
+            self.cells.remove(0);
+            self.cells.push(vec![' '; self.cols]);
+        }
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        for _ in 0..cmp::min(n, self.rows) {
+            self.cells.pop();
+            self.cells.insert(0, vec![' '; self.cols]);
+        }
+    }
+
+    fn blank(&mut self, row: usize, from: usize, to: usize) {
+        for c in from..cmp::min(to, self.cols) {
+            self.cells[row][c] = ' ';
+        }
+    }
+
+    fn csi(&mut self, action: char) {
+        if self.params.starts_with('?') || self.params.starts_with('>') {
+            // Private modes, like hiding the cursor. Nothing to do.
+            return;
+        }
+        let params: Vec<usize> = self.params.split(';').map(|p| p.parse().unwrap_or(0)).collect();
+        // Most parameters count something and mean 1 when left out.
+        let n = cmp::max(1, params[0]);
+        let (last_row, last_col) = (self.rows - 1, self.cols - 1);
+        match action {
+            'A' => self.row = self.row.saturating_sub(n),
+            'B' => self.row = cmp::min(last_row, self.row + n),
+            'C' => self.col = cmp::min(last_col, self.col + n),
+            'D' => self.col = self.col.saturating_sub(n),
+            'E' => {
+                self.row = cmp::min(last_row, self.row + n);
+                self.col = 0;
+            }
+            'F' => {
+                self.row = self.row.saturating_sub(n);
+                self.col = 0;
+            }
+            'G' | '`' => self.col = cmp::min(last_col, n - 1),
+            'd' => self.row = cmp::min(last_row, n - 1),
+            'H' | 'f' => {
+                self.row = cmp::min(last_row, n - 1);
+                self.col = cmp::min(last_col, cmp::max(1, *params.get(1).unwrap_or(&1)) - 1);
+            }
+            'J' => {
+                let (row, col) = (self.row, self.col);
+                match params[0] {
+                    0 => {
+                        self.blank(row, col, self.cols);
+                        for r in row + 1..self.rows {
+                            self.blank(r, 0, self.cols);
+                        }
+                    }
+                    1 => {
+                        for r in 0..row {
+                            self.blank(r, 0, self.cols);
+                        }
+                        self.blank(row, 0, col + 1);
+                    }
+                    _ => {
+                        for r in 0..self.rows {
+                            self.blank(r, 0, self.cols);
+                        }
+                    }
+                }
+            }
+            'K' => {
+                let (row, col) = (self.row, self.col);
+                match params[0] {
+                    0 => self.blank(row, col, self.cols),
+                    1 => self.blank(row, 0, col + 1),
+                    _ => self.blank(row, 0, self.cols),
+                }
+            }
+            'X' => {
+                let (row, col) = (self.row, self.col);
+                self.blank(row, col, col + n);
+            }
+            'P' => {
+                let row = &mut self.cells[self.row];
+                for _ in 0..cmp::min(n, self.cols - cmp::min(self.col, self.cols)) {
+                    row.remove(self.col);
+                    row.push(' ');
+                }
+            }
+            '@' => {
+                let row = &mut self.cells[self.row];
+                for _ in 0..cmp::min(n, self.cols - cmp::min(self.col, self.cols)) {
+                    row.pop();
+                    row.insert(self.col, ' ');
+                }
+            }
+            'L' => {
+                for _ in 0..cmp::min(n, self.rows - self.row) {
+                    self.cells.pop();
+                    self.cells.insert(self.row, vec![' '; self.cols]);
+                }
+            }
+            'M' => {
+                for _ in 0..cmp::min(n, self.rows - self.row) {
+                    self.cells.remove(self.row);
+                    self.cells.push(vec![' '; self.cols]);
+                }
+            }
+            'S' => self.scroll_up(n),
+            'T' => self.scroll_down(n),
+            // Colors, modes and scroll regions aren't supported.
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_the_cursor() {
+        let mut s = Screen::new(4, 10);
+        s.feed("ab\r\ncd");
+        assert_eq!((s.row, s.col), (1, 2));
+        s.feed("\x1b[3;5Hx");
+        assert_eq!((s.row, s.col), (2, 5));
+        s.feed("\x1b[2A\x1b[10C\x1b[D");
+        assert_eq!((s.row, s.col), (0, 8));
+        s.feed("\x1b[H\x1b[99B");
+        assert_eq!((s.row, s.col), (3, 0));
+        assert_eq!(s.text(), "ab\ncd\n    x\n");
+    }
+
+    #[test]
+    fn erases() {
+        let mut s = Screen::new(3, 6);
+        s.feed("abcdef\r\nghijkl\r\nmnopqr");
+        s.feed("\x1b[2;3H\x1b[K");
+        assert_eq!(s.text(), "abcdef\ngh\nmnopqr");
+        s.feed("\x1b[1K");
+        assert_eq!(s.text(), "abcdef\n  \nmnopqr");
+        s.feed("\x1b[1;5H\x1b[2X\x1b[J");
+        assert_eq!(s.text(), "abcd\n\n");
+        s.feed("\x1b[2J");
+        assert_eq!(s.text(), "    \n\n");
+    }
+
+    #[test]
+    fn scrolls() {
+        let mut s = Screen::new(3, 5);
+        s.feed("1\r\n2\r\n3\r\n4");
+        assert_eq!(s.text(), "2\n3\n4");
+        assert_eq!((s.row, s.col), (2, 1));
+        s.feed("\x1b[H\x1bM0");
+        assert_eq!(s.text(), "0\n2\n3");
+        s.feed("\x1b[2S");
+        assert_eq!(s.text(), "3\n\n");
+        s.feed("\x1b[T");
+        assert_eq!(s.text(), " \n3\n");
+    }
+}
@@ -1,14 +1,17 @@
 use std::sync::{Arc, Mutex};
 use std::cmp;
 
-use buffer::{Buffer, Anchor};
+use buffer::{Buffer, Anchor, AnchorHandle, AnchorKind};
 use geometry::{Point, Size};
-use mode::{Command, Direction, Mode};
+use mode::{Case, Command, Direction, Mode};
 use buffer::{Display, Wrap};
 use errors::{CrbResult, CrbError};
+use ex::{Address, Range, Sort};
+use indent;
 use state;
 use state::State;
 use logging;
+use term::Terminal;
 
 pub struct Window {
     pub buf: Mutex<Buffer>,
@@ -21,17 +24,31 @@ pub struct Window {
     pub scroll: i32,
 
     pub mode: Mode,
-    cursors: Vec<Anchor>,
+    cursors: Vec<AnchorHandle>,
     wrap: Wrap,
     index: i32,
+
+    /// Where the cursor was before each jump, oldest first.
+    jumps: Vec<AnchorHandle>,
+    /// Position in `jumps` while going back and forth with Ctrl-o/Ctrl-i.
+    /// Equal to `jumps.len()` when not going through the list.
+    jump_index: usize,
+
+    /// The shell this window shows, if it's a terminal.
+    term: Option<Terminal>,
+    /// The title of a window without a file, like a popup.
+    pub name: Option<String>,
 }
 
+/// How many jumps each window remembers.
+const MAX_JUMPS: usize = 100;
+
 impl Window {
     pub fn new(buf: Mutex<Buffer>, topleft: Point, size: Size, state: Arc<Mutex<State>>) -> Window {
         let mut cursors = Vec::new();
         {
             let mut buf = buf.lock().unwrap();
-            let a1 = buf.new_anchor();
+            let a1 = buf.new_anchor(AnchorKind::Cursor);
             cursors.push(a1);
         }
         Window {
@@ -48,7 +65,64 @@ impl Window {
             cursors: cursors,
             mode: Mode::Normal,
             wrap: Wrap::default(size.width),
+            jumps: Vec::new(),
+            jump_index: 0,
+            term: None,
+            name: None,
+        }
+    }
+
+    /// A window running a shell.
+    pub fn new_terminal(topleft: Point, size: Size, state: Arc<Mutex<State>>) -> CrbResult<Window> {
+        let mut w = Window::new(Mutex::new(Buffer::empty(state.clone())), topleft, size, state);
+        // One row goes to the title bar, and the columns to line numbers.
+        let rows = cmp::max(1, size.height - 1);
+        let cols = cmp::max(1, size.width - rows.to_string().len() as i32 - 2);
+        w.term = Some(try!(Terminal::spawn(rows as usize, cols as usize)));
+        Ok(w)
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        self.term.is_some()
+    }
+
+    /// Show what the shell drew since last time. Returns false once it has
+    /// exited. The cursor follows the shell's only while typing into it, so
+    /// it stays put in Normal mode.
+    pub fn update_terminal(&mut self) -> CrbResult<bool> {
+        let (changed, alive, row, col) = match self.term {
+            Some(ref mut t) => (t.update(), !t.exited, t.screen.row, t.screen.col),
+            None => return Ok(true),
+        };
+        let mut buf = self.buf.lock().unwrap();
+        if changed {
+            let text = self.term.as_ref().unwrap().screen.text();
+            try!(buf.replace_text(&text));
+            buf.unsaved = false;
+        }
+        if let Mode::Terminal = self.mode {
+            if try!(buf.anchor_pos(self.primary())) != (row as i32, col as i32) {
+                try!(buf.set_anchor_pos(self.cursors.last().unwrap(), row as i32, col as i32));
+            }
         }
+        Ok(alive)
+    }
+
+    /// Type into the shell.
+    pub fn send_terminal(&mut self, text: &str) -> CrbResult<()> {
+        match self.term {
+            Some(ref mut t) => t.send(text),
+            None => Err(CrbError::new("not a terminal")),
+        }
+    }
+
+    /// Show another buffer, starting over at its top.
+    pub fn set_buffer(&mut self, mut buf: Buffer) {
+        self.jumps.clear();
+        self.jump_index = 0;
+        self.scroll = 0;
+        self.cursors = vec![buf.new_anchor(AnchorKind::Cursor)];
+        self.buf = Mutex::new(buf);
     }
 
     pub fn save(&mut self) -> CrbResult<()> {
@@ -76,6 +150,8 @@ impl Window {
                     false => thing.clone(),
                 }
             }
+            None if self.term.is_some() => "terminal".to_string(),
+            None if self.name.is_some() => self.name.clone().unwrap(),
             None => "empty buffer".to_string(),
         };
         unsaved_prefix + &index + &rest
@@ -92,15 +168,544 @@ impl Window {
             }
             let (dataline, wrapline) =
                 try!(buf.get_anchor_line(self.cursors.last().unwrap(), &self.wrap));
-            let bottom = self.scroll + self.size.height;
             // TODO: handle wrap
             logging::debug(&format!("{} {}", dataline, wrapline).to_owned());
-            let up = cmp::min(0, dataline - self.scroll);
-            let down = cmp::max(0, dataline - bottom + 2);
+            let rows = buf.visible_rows(self.scroll, dataline);
+            let up = cmp::min(0, rows);
+            let down = cmp::max(0, rows - self.size.height + 2);
             let delta = up + down;
             delta
         };
         try!(self.scroll(&Command::Scroll(delta)));
+        self.merge_cursors()
+    }
+
+    /// The cursor that the view follows. Always exists.
+    fn primary(&self) -> &Anchor {
+        self.cursors.last().unwrap()
+    }
+
+    pub fn is_cursor(&self, a: &Anchor) -> bool {
+        self.cursors.iter().any(|c| **c == *a)
+    }
+
+    pub fn is_primary_cursor(&self, a: &Anchor) -> bool {
+        self.primary() == a
+    }
+
+    /// Drop cursors that ended up in the same place as another one.
+    fn merge_cursors(&mut self) -> CrbResult<()> {
+        let buf = self.buf.lock().unwrap();
+        let mut seen = Vec::new();
+        let mut keep = Vec::new();
+        // Walk from the primary so it's the one that survives.
+        while let Some(anchor) = self.cursors.pop() {
+            let pos = try!(buf.anchor_pos(&anchor));
+            if !seen.contains(&pos) {
+                seen.push(pos);
+                keep.push(anchor);
+            }
+        }
+        keep.reverse();
+        self.cursors = keep;
+        Ok(())
+    }
+
+    /// Add a cursor and make it the primary one, unless there's already a
+    /// cursor there.
+    fn add_cursor_at(&mut self, line: i32, offset: i32) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                if try!(buf.anchor_pos(anchor)) == (line, offset) {
+                    return Ok(());
+                }
+            }
+            let a = buf.new_anchor_at(AnchorKind::Cursor, line, offset);
+            self.cursors.push(a);
+        }
+        self.move_cursors(&Command::MoveRight(0))
+    }
+
+    /// Add a cursor n lines below the primary one (above if negative).
+    pub fn add_cursor_lines(&mut self, n: i32) -> CrbResult<()> {
+        let (line, offset) = {
+            let buf = self.buf.lock().unwrap();
+            try!(buf.anchor_pos(self.primary()))
+        };
+        self.add_cursor_at(line + n, offset)
+    }
+
+    /// Add a cursor at the next occurrence of the word under the primary
+    /// cursor, wrapping around the end of the buffer. The word becomes the
+    /// search pattern.
+    pub fn add_cursor_next_match(&mut self) -> CrbResult<()> {
+        let (word, pos, found) = {
+            let buf = self.buf.lock().unwrap();
+            let (word, start) = try!(buf.word_at(self.primary())
+                .ok_or(CrbError::new("no word under the cursor")));
+            let (line, _) = try!(buf.anchor_pos(self.primary()));
+            let found = buf.find(&word);
+            (word, (line, start), found)
+        };
+        state::do_safe(&*self.state, |s| s.search = Some(word.clone()));
+        let next = found.iter().find(|&&p| p > pos).or(found.first());
+        match next {
+            Some(&(line, offset)) => self.add_cursor_at(line, offset),
+            None => Ok(()),
+        }
+    }
+
+    /// Add a cursor at every match of the search pattern, or of the word
+    /// under the primary cursor when nothing's been searched for.
+    pub fn add_cursor_all_matches(&mut self) -> CrbResult<()> {
+        let search = state::do_safe(&*self.state, |s| s.search.clone());
+        let found = {
+            let buf = self.buf.lock().unwrap();
+            let pattern = match search {
+                Some(p) => p,
+                None => {
+                    try!(buf.word_at(self.primary())
+                            .ok_or(CrbError::new("no word under the cursor")))
+                        .0
+                }
+            };
+            buf.find(&pattern)
+        };
+        let primary = *self.primary();
+        for (line, offset) in found {
+            try!(self.add_cursor_at(line, offset));
+        }
+        // Keep following the cursor we started from.
+        if let Some(i) = self.cursors.iter().position(|a| **a == primary) {
+            let a = self.cursors.remove(i);
+            self.cursors.push(a);
+        }
+        self.move_cursors(&Command::MoveRight(0))
+    }
+
+    /// Where the cursors are, primary last.
+    pub fn cursor_positions(&self) -> CrbResult<Vec<(i32, i32)>> {
+        let buf = self.buf.lock().unwrap();
+        let mut positions = Vec::new();
+        for anchor in self.cursors.iter() {
+            positions.push(try!(buf.anchor_pos(anchor)));
+        }
+        Ok(positions)
+    }
+
+    /// Replace the cursors, primary last. Does nothing if there are none.
+    pub fn set_cursor_positions(&mut self, positions: &[(i32, i32)]) -> CrbResult<()> {
+        if positions.is_empty() {
+            return Ok(());
+        }
+        {
+            let mut buf = self.buf.lock().unwrap();
+            self.cursors = positions.iter()
+                .map(|&(line, offset)| buf.new_anchor_at(AnchorKind::Cursor, line, offset))
+                .collect();
+        }
+        self.merge_cursors()
+    }
+
+    /// Where the primary cursor is drawn on the screen.
+    pub fn cursor_screen_pos(&self) -> CrbResult<Point> {
+        let buf = self.buf.lock().unwrap();
+        let (line, offset) = try!(buf.anchor_pos(self.primary()));
+        let gutter = buf.count_lines().to_string().len() as i32 + 1;
+        let offset = match buf.closed_fold_at(line) {
+            Some(_) => 0,
+            None => offset,
+        };
+        // The title bar is the first row.
+        Ok(Point::new(self.topleft.x + gutter + offset,
+                      self.topleft.y + 1 + buf.visible_rows(self.scroll, line)))
+    }
+
+    /// What a language server found wrong on the primary cursor's line.
+    pub fn diagnostic_at_cursor(&self) -> Option<String> {
+        let buf = self.buf.lock().unwrap();
+        let line = match buf.anchor_pos(self.primary()) {
+            Ok((line, _)) => line,
+            Err(_) => return None,
+        };
+        buf.diagnostic_on(line).map(|m| m.to_string())
+    }
+
+    /// Set a mark where the primary cursor is.
+    pub fn set_mark(&mut self, name: char) -> CrbResult<()> {
+        let mut buf = self.buf.lock().unwrap();
+        let (line, offset) = try!(buf.anchor_pos(self.primary()));
+        buf.set_mark(name, line, offset);
+        Ok(())
+    }
+
+    pub fn save_marks(&self) -> CrbResult<()> {
+        let buf = self.buf.lock().unwrap();
+        buf.save_marks()
+    }
+
+    /// Jump to a mark. Unless exact, go to the start of its line instead.
+    pub fn jump_to_mark(&mut self, name: char, exact: bool) -> CrbResult<()> {
+        let (line, offset) = {
+            let buf = self.buf.lock().unwrap();
+            let (line, offset) = try!(buf.mark_pos(name)
+                .ok_or(CrbError::new(&format!("mark {} not set", name))));
+            match exact {
+                true => (line, offset),
+                false => (line, buf.first_non_blank(line)),
+            }
+        };
+        self.jump_to(line, offset)
+    }
+
+    /// Move to a far away position, remembering where we came from.
+    pub fn jump_to(&mut self, line: i32, offset: i32) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            let (l, o) = try!(buf.anchor_pos(self.primary()));
+            self.jumps.truncate(self.jump_index);
+            self.jumps.push(buf.new_anchor_at(AnchorKind::Mark, l, o));
+            if self.jumps.len() > MAX_JUMPS {
+                self.jumps.remove(0);
+            }
+            self.jump_index = self.jumps.len();
+        }
+        self.go_to(line, offset)
+    }
+
+    /// Jump to the bracket matching the one under the cursor, or the next
+    /// one on its line.
+    pub fn match_bracket(&mut self) -> CrbResult<()> {
+        let (line, offset) = {
+            let buf = self.buf.lock().unwrap();
+            let (line, offset) = try!(buf.anchor_pos(self.primary()));
+            try!(buf.matching_bracket(line, offset, true)
+                .ok_or(CrbError::new("no matching bracket")))
+        };
+        self.jump_to(line, offset)
+    }
+
+    /// Open the fold at the primary cursor.
+    pub fn open_fold(&mut self) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            let (line, _) = try!(buf.anchor_pos(self.primary()));
+            try!(buf.open_fold(line));
+        }
+        self.move_cursors(&Command::MoveRight(0))
+    }
+
+    /// Close a fold at the primary cursor, making one if there isn't one.
+    pub fn close_fold(&mut self) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            let (line, _) = try!(buf.anchor_pos(self.primary()));
+            try!(buf.close_fold(line));
+        }
+        self.move_cursors(&Command::MoveRight(0))
+    }
+
+    pub fn toggle_fold(&mut self) -> CrbResult<()> {
+        let closed = {
+            let buf = self.buf.lock().unwrap();
+            let (line, _) = try!(buf.anchor_pos(self.primary()));
+            buf.closed_fold_at(line).is_some()
+        };
+        match closed {
+            true => self.open_fold(),
+            false => self.close_fold(),
+        }
+    }
+
+    /// Fold lines away.
+    pub fn create_fold(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().create_fold(first, last));
+        self.move_cursors(&Command::MoveRight(0))
+    }
+
+    /// Go n entries back in the jump list (forward if negative).
+    pub fn jump_back(&mut self, n: i32) -> CrbResult<()> {
+        if self.jump_index == self.jumps.len() && n > 0 {
+            // Remember where we are so Ctrl-i can come back here.
+            let mut buf = self.buf.lock().unwrap();
+            let (l, o) = try!(buf.anchor_pos(self.primary()));
+            self.jumps.push(buf.new_anchor_at(AnchorKind::Mark, l, o));
+        }
+        let target = self.jump_index as i32 - n;
+        if target < 0 || target >= self.jumps.len() as i32 {
+            return Err(CrbError::new("no more jumps that way"));
+        }
+        self.jump_index = target as usize;
+        let (line, offset) = {
+            let buf = self.buf.lock().unwrap();
+            try!(buf.anchor_pos(&self.jumps[self.jump_index]))
+        };
+        self.go_to(line, offset)
+    }
+
+    /// Change the case from each cursor to where a motion takes it, or of
+    /// the lines between for up and down. The cursors go to the start.
+    pub fn change_case(&mut self, case: Case, motion: &Command) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                let from = try!(buf.anchor_pos(anchor));
+                let to = try!(buf.moved_pos(anchor, motion));
+                let (start, end) = match *motion {
+                    Command::MoveUp(_) | Command::MoveDown(_) => {
+                        let last = cmp::max(from.0, to.0);
+                        let len = buf.line(last).unwrap_or("").chars().count() as i32;
+                        ((cmp::min(from.0, to.0), 0), (last, len))
+                    }
+                    _ => (cmp::min(from, to), cmp::max(from, to)),
+                };
+                try!(buf.change_case(start, end, case));
+                try!(buf.set_anchor_pos(anchor, start.0, start.1));
+            }
+        }
+        self.mark_edit()
+    }
+
+    /// Toggle the case of n characters from each cursor, moving past them.
+    pub fn toggle_case(&mut self, n: i32) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                let (line, offset) = try!(buf.anchor_pos(anchor));
+                let len = buf.line(line).unwrap_or("").chars().count() as i32;
+                let end = cmp::min(offset + n, len);
+                try!(buf.change_case((line, offset), (line, end), Case::Toggle));
+                try!(buf.set_anchor_pos(anchor, line, end));
+            }
+        }
+        self.mark_edit()
+    }
+
+    /// Join lines onto each cursor's: n of them, counting its own, and at
+    /// least two.
+    pub fn join_lines(&mut self, n: i32) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                let (line, _) = try!(buf.anchor_pos(anchor));
+                let at = try!(buf.join_lines(line, cmp::max(n - 1, 1)));
+                try!(buf.set_anchor_pos(anchor, line, at));
+            }
+        }
+        self.mark_edit()
+    }
+
+    pub fn replace_chars(&mut self, n: i32, c: char) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                try!(buf.replace_chars(anchor, n, c));
+            }
+        }
+        self.mark_edit()
+    }
+
+    pub fn overwrite(&mut self, c: char) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                try!(buf.overwrite(anchor, c));
+            }
+        }
+        self.mark_edit()
+    }
+
+    /// Add n to the number at or after each cursor.
+    pub fn increment(&mut self, n: i32) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                try!(buf.increment(anchor, n));
+            }
+        }
+        self.mark_edit()
+    }
+
+    /// Move each cursor to the first non-blank character of its line.
+    pub fn to_first_non_blank(&mut self) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                let (line, _) = try!(buf.anchor_pos(anchor));
+                let offset = buf.first_non_blank(line);
+                try!(buf.set_anchor_pos(anchor, line, offset));
+            }
+        }
+        self.move_cursors(&Command::MoveRight(0))
+    }
+
+    /// Start a new line below (F) or above (B) each cursor's, indented.
+    pub fn open_line(&mut self, d: Direction) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                match d {
+                    Direction::F => {
+                        try!(buf.move_anchor(anchor, &Command::LineEnd));
+                        try!(buf.new_line(anchor));
+                    }
+                    Direction::B => try!(buf.open_line_above(anchor)),
+                }
+            }
+        }
+        self.mark_edit()
+    }
+
+    /// Toggle comments on n lines from each cursor's.
+    pub fn toggle_comment(&mut self, n: i32) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            let mut ranges = Vec::new();
+            for anchor in self.cursors.iter() {
+                let (line, _) = try!(buf.anchor_pos(anchor));
+                ranges.push((line, cmp::min(line + cmp::max(n, 1) - 1, buf.count_lines() - 1)));
+            }
+            // Lines that more than one cursor reaches are toggled once.
+            ranges.sort();
+            let mut merged: Vec<(i32, i32)> = Vec::new();
+            for (first, last) in ranges {
+                match merged.last_mut() {
+                    Some(m) if first <= m.1 => m.1 = cmp::max(m.1, last),
+                    _ => merged.push((first, last)),
+                }
+            }
+            for &(first, last) in merged.iter() {
+                try!(buf.toggle_comment(first, last));
+            }
+        }
+        self.mark_edit()
+    }
+
+    pub fn toggle_comment_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().toggle_comment(first, last));
+        self.mark_edit()
+    }
+
+    /// Put the primary cursor somewhere, dropping the others.
+    fn go_to(&mut self, line: i32, offset: i32) -> CrbResult<()> {
+        try!(self.single_cursor());
+        {
+            let mut buf = self.buf.lock().unwrap();
+            buf.reveal(line);
+            try!(buf.set_anchor_pos(self.primary(), line, offset));
+        }
+        self.move_cursors(&Command::MoveRight(0))
+    }
+
+    /// Remember where the last change happened, as mark `.`.
+    fn mark_edit(&mut self) -> CrbResult<()> {
+        try!(self.merge_cursors());
+        self.set_mark('.')
+    }
+
+    /// Remember the text as it is, to come back to with undo.
+    pub fn checkpoint(&mut self) {
+        self.buf.lock().unwrap().checkpoint();
+    }
+
+    /// Undo n changes (redo if negative) and go to where they were.
+    pub fn undo(&mut self, n: i32) -> CrbResult<()> {
+        let line = {
+            let mut buf = self.buf.lock().unwrap();
+            let line = try!(buf.undo(n));
+            (line, buf.first_non_blank(line))
+        };
+        self.go_to(line.0, line.1)
+    }
+
+    /// The first and last line of a range, 0-based.
+    pub fn resolve_range(&self, range: &Range) -> CrbResult<(i32, i32)> {
+        let start = try!(self.resolve_address(&range.start));
+        let end = try!(self.resolve_address(&range.end));
+        Ok((cmp::min(start, end), cmp::max(start, end)))
+    }
+
+    fn resolve_address(&self, addr: &(Address, i32)) -> CrbResult<i32> {
+        let buf = self.buf.lock().unwrap();
+        let line = match addr.0 {
+            Address::Current => try!(buf.anchor_pos(self.primary())).0,
+            Address::Last => buf.count_lines() - 1,
+            Address::Line(n) => n - 1,
+            Address::Mark(name) => {
+                try!(buf.mark_pos(name).ok_or(CrbError::new(&format!("mark {} not set", name)))).0
+            }
+        };
+        let line = line + addr.1;
+        if line < 0 || line >= buf.count_lines() {
+            return Err(CrbError::new(&format!("no line {}", line + 1)));
+        }
+        Ok(line)
+    }
+
+    /// The text of some lines, each ending in a newline.
+    pub fn lines_text(&self, first: i32, last: i32) -> String {
+        let buf = self.buf.lock().unwrap();
+        let mut text = String::new();
+        for l in first..last + 1 {
+            text.push_str(buf.line(l).unwrap_or(""));
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn sort_lines(&mut self, first: i32, last: i32, how: &Sort) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().sort_lines(first, last, how));
+        self.mark_edit()
+    }
+
+    pub fn uniq_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().uniq_lines(first, last));
+        self.mark_edit()
+    }
+
+    pub fn reverse_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().reverse_lines(first, last));
+        self.mark_edit()
+    }
+
+    pub fn align_lines(&mut self, first: i32, last: i32, delimiter: &str) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().align_lines(first, last, delimiter));
+        self.mark_edit()
+    }
+
+    pub fn trim_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().trim_lines(first, last));
+        self.mark_edit()
+    }
+
+    pub fn retab_lines(&mut self, first: i32, last: i32, spaces: bool) -> CrbResult<()> {
+        try!(self.buf.lock().unwrap().retab_lines(first, last, spaces));
+        self.mark_edit()
+    }
+
+    /// Put text in place of some lines, as printed by a filter.
+    pub fn replace_lines(&mut self, first: i32, last: i32, text: &str) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            let text = match text.ends_with('\n') {
+                true => &text[..text.len() - 1],
+                false => text,
+            };
+            let lines: Vec<&str> = match text.is_empty() {
+                true => Vec::new(),
+                false => text.split('\n').collect(),
+            };
+            try!(buf.replace_lines(first, last - first + 1, &lines));
+        }
+        try!(self.go_to(first, 0));
+        self.mark_edit()
+    }
+
+    /// Go back to just the primary cursor.
+    pub fn single_cursor(&mut self) -> CrbResult<()> {
+        let primary = self.cursors.pop().unwrap();
+        self.cursors = vec![primary];
         Ok(())
     }
 
@@ -108,25 +713,85 @@ impl Window {
         where F: FnMut(&Display)
     {
         let buf = self.buf.lock().unwrap();
-        buf.display(self.scroll as usize, self.size, &self.wrap, f);
+        let matched = buf.anchor_pos(self.primary())
+            .ok()
+            .and_then(|(line, offset)| buf.matching_bracket(line, offset, false));
+        buf.display(self.scroll as usize, self.size, &self.wrap, matched, f);
     }
 
     pub fn insert(&mut self, c: char) -> CrbResult<()> {
-        let mut buf = self.buf.lock().unwrap();
-        for anchor in self.cursors.iter() {
-            try!(buf.insert_text_before(anchor, c));
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                try!(buf.type_char(anchor, c));
+                if indent::is_closer(c) {
+                    try!(buf.align_closer(anchor));
+                }
+            }
         }
-        Ok(())
+        self.mark_edit()
     }
 
-    pub fn insert_s(&mut self, s: &str) -> CrbResult<()> {
-        let mut buf = self.buf.lock().unwrap();
-        for c in s.chars() {
+    /// Break the line at each cursor, keeping the indentation.
+    pub fn new_line(&mut self) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
             for anchor in self.cursors.iter() {
-                try!(buf.insert_text_before(anchor, c));
+                try!(buf.new_line(anchor));
             }
         }
-        Ok(())
+        self.mark_edit()
+    }
+
+    /// The primary cursor's line and the ones below it, n in all.
+    pub fn cursor_lines(&self, n: i32) -> CrbResult<(i32, i32)> {
+        let buf = self.buf.lock().unwrap();
+        let (line, _) = try!(buf.anchor_pos(self.primary()));
+        Ok((line, cmp::min(line + cmp::max(n, 1) - 1, buf.count_lines() - 1)))
+    }
+
+    /// Indent lines n levels more (less if negative).
+    pub fn shift_lines(&mut self, first: i32, last: i32, n: i32) -> CrbResult<()> {
+        let offset = {
+            let mut buf = self.buf.lock().unwrap();
+            try!(buf.shift_lines(first, last, n));
+            buf.first_non_blank(first)
+        };
+        try!(self.go_to(first, offset));
+        self.mark_edit()
+    }
+
+    /// Indent lines by their brackets.
+    pub fn reindent_lines(&mut self, first: i32, last: i32) -> CrbResult<()> {
+        let offset = {
+            let mut buf = self.buf.lock().unwrap();
+            try!(buf.reindent_lines(first, last));
+            buf.first_non_blank(first)
+        };
+        try!(self.go_to(first, offset));
+        self.mark_edit()
+    }
+
+    pub fn insert_s(&mut self, s: &str) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for c in s.chars() {
+                for anchor in self.cursors.iter() {
+                    try!(buf.insert_text_before(anchor, c));
+                }
+            }
+        }
+        self.mark_edit()
+    }
+
+    /// Add text at the end, wherever the cursors are.
+    pub fn append(&mut self, s: &str) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            try!(buf.append(s));
+        }
+        // Cursors at the end get pushed along; keep the primary in view.
+        self.move_cursors(&Command::MoveRight(0))
     }
 
     pub fn clear(&mut self) -> CrbResult<()> {
@@ -135,19 +800,49 @@ impl Window {
     }
 
     pub fn delete(&mut self, d: Direction) -> CrbResult<()> {
-        let mut buf = self.buf.lock().unwrap();
-        for anchor in self.cursors.iter() {
-            try!(buf.delete_at(anchor, &d));
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                try!(buf.delete_at(anchor, &d));
+            }
         }
-        Ok(())
+        self.mark_edit()
+    }
+
+    /// Delete before each cursor, and both halves of empty pairs.
+    pub fn backspace(&mut self) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                try!(buf.backspace(anchor));
+            }
+        }
+        self.mark_edit()
+    }
+
+    pub fn delete_word(&mut self) -> CrbResult<()> {
+        {
+            let mut buf = self.buf.lock().unwrap();
+            for anchor in self.cursors.iter() {
+                try!(buf.delete_word_before(anchor));
+            }
+        }
+        self.mark_edit()
+    }
+
+    /// Scroll n half-heights of the window and move the cursors the same
+    /// distance, so they stay put on the screen.
+    pub fn scroll_half_pages(&mut self, n: i32) -> CrbResult<()> {
+        let lines = n * cmp::max(1, self.size.height / 2);
+        try!(self.scroll(&Command::Scroll(lines)));
+        self.move_cursors(&Command::MoveDown(lines))
     }
 
     pub fn scroll(&mut self, c: &Command) -> CrbResult<()> {
         let buf = self.buf.lock().unwrap();
         if let Command::Scroll(n) = *c {
-            self.scroll += n;
             // TODO this needs to happen whenever the buffer changes size.
-            self.scroll = cmp::max(0, cmp::min(self.scroll, buf.count_lines()));
+            self.scroll = buf.step_visible(cmp::min(self.scroll, buf.count_lines() - 1), n);
             Ok(())
         } else {
             Err(CrbError::new(&format!("invalid scroll command {:?}", c)))